@@ -115,6 +115,19 @@ pub enum DebugPortError {
     /// An error occurred in the communication with an access port or debug port.
     #[error("An error occurred in the communication with an access port or debug port.")]
     Dap(#[from] DapError),
+
+    /// The debug port selected with `TARGETSEL` is not the requested target.
+    #[error(
+        "Selected debug port does not match TARGETSEL {targetsel:#010x} (TARGETID: {target_id:#010x}, DLPIDR: {dlpidr:#010x})"
+    )]
+    TargetSelectionMismatch {
+        /// The requested `TARGETSEL` value.
+        targetsel: u32,
+        /// The `TARGETID` value read from the selected debug port.
+        target_id: u32,
+        /// The `DLPIDR` value read from the selected debug port.
+        dlpidr: u32,
+    },
 }
 /// A typed interface to be implemented on drivers that can control a debug port.
 pub trait DpAccess {
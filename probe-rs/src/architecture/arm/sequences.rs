@@ -551,7 +551,7 @@ pub trait ArmDebugSequence: Send + Sync + Debug {
                     }
 
                     // Execute at least >5 TCK cycles with TMS high to enter the Test-Logic-Reset state
-                    interface.swj_sequence(6, 0x3F)?;
+                    interface.tap_reset_sequence()?;
 
                     // Enter Run-Test-Idle state, as required by the DAP_Transfer command when using JTAG
                     interface.jtag_sequence(1, false, 0x01)?;
@@ -962,6 +962,11 @@ pub trait ArmDebugSequence: Send + Sync + Debug {
         const RESET_RECOVERY_TIMEOUT: Duration = Duration::from_secs(1);
         const RESET_RECOVERY_RETRY_INTERVAL: Duration = Duration::from_millis(5);
 
+        let line_reset_retries = interface
+            .dap_config()
+            .map_or(1, |config| config.line_reset_retries);
+        let mut attempts = SwdConnectAttempts::new(line_reset_retries);
+
         // Enter SWD Line Reset State, afterwards at least 2 idle cycles (SWDIO/TMS Low)
         // Guard gives time for the target to recover
         let guard = Instant::now();
@@ -990,7 +995,7 @@ pub trait ArmDebugSequence: Send + Sync + Debug {
             match interface.raw_read_register(RegisterAddress::DpRegister(DPIDR::ADDRESS)) {
                 Ok(x) => break x,
                 Err(z) => {
-                    if guard.elapsed() > RESET_RECOVERY_TIMEOUT {
+                    if !attempts.retry(&z, guard.elapsed() > RESET_RECOVERY_TIMEOUT) {
                         tracing::debug!("DPIDR didn't become readable within guard time");
                         return Err(z);
                     }
@@ -1030,22 +1035,9 @@ pub trait ArmDebugSequence: Send + Sync + Debug {
             interface.raw_write_register(SelectV1::ADDRESS.into(), 3)?;
             let dlpidr = interface.raw_read_register(DLPIDR::ADDRESS.into())?;
 
-            const TARGETID_MASK: u32 = 0x0FFF_FFFF;
-            const DLPIDR_MASK: u32 = 0xF000_0000;
-
-            let targetid_match = (target_id & TARGETID_MASK) == (targetsel & TARGETID_MASK);
-            let dlpdir_match = (dlpidr & DLPIDR_MASK) == (targetsel & DLPIDR_MASK);
-
-            if !(targetid_match && dlpdir_match) {
-                tracing::warn!(
-                    "Target ID and DLPIDR do not match, failed to select debug port. Target ID: {:#x?}, DLPIDR: {:#x?}",
-                    target_id,
-                    dlpidr
-                );
-                return Err(ArmError::Other(
-                    "Target ID and DLPIDR do not match, failed to select debug port".to_string(),
-                ));
-            }
+            check_multidrop_target(targetsel, target_id, dlpidr).inspect_err(|e| {
+                tracing::warn!("Failed to select debug port: {e}");
+            })?;
         }
 
         interface.raw_write_register(SelectV1::ADDRESS.into(), 0)?;
@@ -1152,3 +1144,285 @@ fn swd_line_reset(interface: &mut dyn DapProbe, swdio_low_cycles: u8) -> Result<
 
     Ok(())
 }
+
+// Additional line resets when connecting over SWD, if the DP doesn't acknowledge the
+// DPIDR read, which often happens once while it is getting in sync after the switch sequence
+const CONNECT_NOACK_RETRIES: usize = 2;
+
+/// Tracks the line resets tried when connecting over SWD, by
+/// [`ArmDebugSequence::debug_port_connect`] and
+/// [`RawDapAccess::auto_select_protocol`](super::RawDapAccess::auto_select_protocol).
+pub(crate) struct SwdConnectAttempts {
+    /// Line resets tried so far
+    line_resets: usize,
+    /// Line resets which are tried even after the guard time has elapsed
+    min_line_resets: usize,
+    /// Remaining retries of a read which wasn't acknowledged, these don't count as line resets
+    noack_retries: usize,
+}
+
+impl SwdConnectAttempts {
+    pub(crate) fn new(min_line_resets: usize) -> Self {
+        Self {
+            line_resets: 0,
+            min_line_resets: min_line_resets.max(1),
+            noack_retries: CONNECT_NOACK_RETRIES,
+        }
+    }
+
+    /// Record a `DPIDR` read which failed with `error`, and return whether to try another
+    /// line reset.
+    pub(crate) fn retry(&mut self, error: &ArmError, guard_elapsed: bool) -> bool {
+        if matches!(error, ArmError::Dap(DapError::NoAcknowledge)) && self.noack_retries > 0 {
+            tracing::debug!("No acknowledge for DPIDR read, retrying");
+            self.noack_retries -= 1;
+            return true;
+        }
+
+        self.retry_line_reset(guard_elapsed)
+    }
+
+    /// Record a line reset after which `DPIDR` couldn't be read, e.g. because it returned
+    /// an invalid value, and return whether to try another line reset.
+    pub(crate) fn retry_line_reset(&mut self, guard_elapsed: bool) -> bool {
+        self.line_resets += 1;
+
+        !guard_elapsed || self.line_resets < self.min_line_resets
+    }
+}
+
+/// Verify that the debug port selected with `TARGETSEL` is the requested target.
+///
+/// The TARGETID fields of `targetsel` have to match `TARGETID`, and the
+/// instance number has to match `DLPIDR.TINSTANCE`. `DLPIDR.PROTVSN` has to
+/// report SWD protocol version 2, which is required for multi-drop.
+fn check_multidrop_target(
+    targetsel: u32,
+    target_id: u32,
+    dlpidr: u32,
+) -> Result<(), DebugPortError> {
+    const TARGETID_MASK: u32 = 0x0FFF_FFFF;
+
+    let dlpidr_fields = DLPIDR::try_from(dlpidr)?;
+
+    let targetid_match = (target_id & TARGETID_MASK) == (targetsel & TARGETID_MASK);
+    let tinstance_match = u32::from(dlpidr_fields.tinstance()) == targetsel >> 28;
+    let protocol_match = dlpidr_fields.protsvn() == 1;
+
+    if targetid_match && tinstance_match && protocol_match {
+        Ok(())
+    } else {
+        Err(DebugPortError::TargetSelectionMismatch {
+            targetsel,
+            target_id,
+            dlpidr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ArmDebugSequence, DefaultArmSequence, SwdConnectAttempts, check_multidrop_target};
+    use crate::CoreStatus;
+    use crate::architecture::arm::communication_interface::DapProbe;
+    use crate::architecture::arm::dp::{DPIDR, DebugPortError, DpAddress, DpRegister};
+    use crate::architecture::arm::{ArmError, DapError, RawDapAccess, RegisterAddress};
+    use crate::probe::{DebugProbe, DebugProbeError, WireProtocol};
+
+    const TARGETSEL: u32 = 0x1100_2927;
+    const TARGET_ID: u32 = 0x0100_2927;
+
+    /// A SWD probe which counts line resets, and fails the first `DPIDR` reads.
+    #[derive(Debug, Default)]
+    struct MockDapProbe {
+        /// Errors returned by the next `DPIDR` reads, before one succeeds
+        dpidr_errors: Vec<ArmError>,
+        line_resets: usize,
+    }
+
+    impl DebugProbe for MockDapProbe {
+        fn get_name(&self) -> &str {
+            "Mock DAP probe"
+        }
+
+        fn speed_khz(&self) -> u32 {
+            1000
+        }
+
+        fn set_speed(&mut self, speed_khz: u32) -> Result<u32, DebugProbeError> {
+            Ok(speed_khz)
+        }
+
+        fn attach(&mut self) -> Result<(), DebugProbeError> {
+            Ok(())
+        }
+
+        fn detach(&mut self) -> Result<(), crate::Error> {
+            Ok(())
+        }
+
+        fn target_reset(&mut self) -> Result<(), DebugProbeError> {
+            Ok(())
+        }
+
+        fn target_reset_assert(&mut self) -> Result<(), DebugProbeError> {
+            Ok(())
+        }
+
+        fn target_reset_deassert(&mut self) -> Result<(), DebugProbeError> {
+            Ok(())
+        }
+
+        fn select_protocol(&mut self, _protocol: WireProtocol) -> Result<(), DebugProbeError> {
+            Ok(())
+        }
+
+        fn active_protocol(&self) -> Option<WireProtocol> {
+            Some(WireProtocol::Swd)
+        }
+
+        fn into_probe(self: Box<Self>) -> Box<dyn DebugProbe> {
+            self
+        }
+    }
+
+    impl RawDapAccess for MockDapProbe {
+        fn raw_read_register(&mut self, address: RegisterAddress) -> Result<u32, ArmError> {
+            if address != RegisterAddress::DpRegister(DPIDR::ADDRESS) {
+                return Ok(0);
+            }
+
+            if self.dpidr_errors.is_empty() {
+                Ok(0x2ba0_1477)
+            } else {
+                Err(self.dpidr_errors.remove(0))
+            }
+        }
+
+        fn raw_write_register(
+            &mut self,
+            _address: RegisterAddress,
+            _value: u32,
+        ) -> Result<(), ArmError> {
+            Ok(())
+        }
+
+        fn jtag_sequence(
+            &mut self,
+            _cycles: u8,
+            _tms: bool,
+            _tdi: u64,
+        ) -> Result<(), DebugProbeError> {
+            Ok(())
+        }
+
+        fn swj_sequence(&mut self, bit_len: u8, bits: u64) -> Result<(), DebugProbeError> {
+            // 51 cycles with SWDIO high, followed by 3 low cycles
+            if bit_len == 54 && bits == 0x0007_FFFF_FFFF_FFFF {
+                self.line_resets += 1;
+            }
+
+            Ok(())
+        }
+
+        fn swj_pins(
+            &mut self,
+            _pin_out: u32,
+            _pin_select: u32,
+            _pin_wait: u32,
+        ) -> Result<u32, DebugProbeError> {
+            Ok(0)
+        }
+
+        fn into_probe(self: Box<Self>) -> Box<dyn DebugProbe> {
+            self
+        }
+
+        fn core_status_notification(&mut self, _: CoreStatus) -> Result<(), DebugProbeError> {
+            Ok(())
+        }
+    }
+
+    impl DapProbe for MockDapProbe {}
+
+    #[test]
+    fn multidrop_target_matches() {
+        check_multidrop_target(TARGETSEL, TARGET_ID, 0x1000_0001).unwrap();
+    }
+
+    #[test]
+    fn multidrop_instance_mismatch() {
+        let result = check_multidrop_target(TARGETSEL, TARGET_ID, 0x2000_0001);
+
+        assert!(matches!(
+            result,
+            Err(DebugPortError::TargetSelectionMismatch {
+                targetsel: TARGETSEL,
+                target_id: TARGET_ID,
+                dlpidr: 0x2000_0001,
+            })
+        ));
+    }
+
+    #[test]
+    fn line_resets_are_retried_within_guard_time() {
+        let mut attempts = SwdConnectAttempts::new(1);
+        let error = ArmError::Dap(DapError::FaultResponse);
+
+        assert!(attempts.retry(&error, false));
+        assert!(attempts.retry(&error, false));
+        assert!(!attempts.retry(&error, true));
+    }
+
+    #[test]
+    fn line_reset_retries_outlast_guard_time() {
+        let mut attempts = SwdConnectAttempts::new(3);
+        let error = ArmError::Dap(DapError::FaultResponse);
+
+        assert!(attempts.retry(&error, true));
+        assert!(attempts.retry(&error, true));
+        assert!(!attempts.retry(&error, true));
+    }
+
+    #[test]
+    fn noack_is_retried_after_guard_time() {
+        let mut attempts = SwdConnectAttempts::new(1);
+        let error = ArmError::Dap(DapError::NoAcknowledge);
+
+        assert!(attempts.retry(&error, true));
+        assert!(attempts.retry(&error, true));
+        assert!(!attempts.retry(&error, true));
+    }
+
+    #[test]
+    fn debug_port_connect_retries_failed_dpidr_reads() {
+        let mut probe = MockDapProbe {
+            dpidr_errors: vec![
+                ArmError::Dap(DapError::FaultResponse),
+                ArmError::Dap(DapError::FaultResponse),
+            ],
+            ..Default::default()
+        };
+
+        DefaultArmSequence(())
+            .debug_port_connect(&mut probe, DpAddress::Default)
+            .unwrap();
+
+        assert_eq!(probe.line_resets, 3);
+    }
+
+    #[test]
+    fn debug_port_connect_retries_noack() {
+        let mut probe = MockDapProbe {
+            dpidr_errors: vec![ArmError::Dap(DapError::NoAcknowledge)],
+            ..Default::default()
+        };
+
+        DefaultArmSequence(())
+            .debug_port_connect(&mut probe, DpAddress::Default)
+            .unwrap();
+
+        assert_eq!(probe.line_resets, 2);
+        assert!(probe.dpidr_errors.is_empty());
+    }
+}
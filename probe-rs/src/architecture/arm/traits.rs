@@ -1,16 +1,32 @@
+use std::{
+    sync::{Arc, atomic::AtomicBool},
+    time::{Duration, Instant},
+};
+
+use bitvec::vec::BitVec;
+
 use crate::{
     CoreStatus,
-    probe::{DebugProbe, DebugProbeError},
+    probe::{DebugProbe, DebugProbeError, JtagIrOpcodes, ProbeStatistics, WireProtocol},
 };
 
 use super::{
     ArmError,
+    ap::{AddressIncrement, ApRegister, BASE, CSW, DRW, DataSize, IDR, TAR},
     communication_interface::DapProbe,
-    dp::{DpAddress, DpRegisterAddress},
+    dp::{
+        BASEPTR0, BASEPTR1, Ctrl, DPIDR, DebugPortVersion, DpAddress, DpRegister,
+        DpRegisterAddress, Select1, SelectV1, SelectV3,
+    },
+    memory::romtable::CORESIGHT_ROM_TABLE_ARCHID,
 };
 
 pub(crate) mod polyfill;
 
+pub use polyfill::{
+    DapTransfer, TransferDirection, TransferStatus, swd_data_parity, swd_request_parity,
+};
+
 /// Specifies the address of register to access in a debug or access port.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum RegisterAddress {
@@ -205,6 +221,92 @@ impl FullyQualifiedApAddress {
     }
 }
 
+/// How WAIT responses are retried by [`RawDapAccess`] implementations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// How often a transfer is retried after a WAIT response.
+    pub num_retries_after_wait: usize,
+    /// The idle cycles after a retried transfer are doubled on every retry, up to this number.
+    pub max_retry_idle_cycles_after_wait: usize,
+}
+
+/// Options of the transfers performed by [`RawDapAccess`] implementations.
+///
+/// Read the current options with [`RawDapAccess::dap_config`], change the ones of
+/// interest and apply them with [`RawDapAccess::configure_dap`], so the defaults of
+/// the probe are kept for the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-reports", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct DapConfig {
+    /// The value SWDIO is driven to during idle cycles.
+    ///
+    /// The SWD specification has the line driven low while idle, but some targets with
+    /// clock gating quirks need it to be driven high instead.
+    pub idle_cycle_swdio: bool,
+
+    /// Read CTRL/STAT at the end of each JTAG transfer batch, to check for errors.
+    ///
+    /// JTAG-DPs report FAULT as OK, so this read is the only way to notice a failed
+    /// transfer. Disabling it saves two transfers per batch, but sticky errors are then
+    /// only noticed by later batches which do check CTRL/STAT.
+    pub verify_jtag_ctrl: bool,
+
+    /// Read the IDR of each AP before its first access, and fail if it reads as zero.
+    ///
+    /// Accesses to an AP which doesn't exist are ignored by the DP, and reads return zero,
+    /// so without this check using the wrong AP address silently returns garbage.
+    pub validate_ap_presence: bool,
+
+    /// Number of idle cycles appended to each SWD IO call.
+    ///
+    /// Probes which tristate the lines between calls can glitch the first bit of the next
+    /// call, parking the lines in the idle state avoids that.
+    pub parking_idle_cycles: usize,
+
+    /// Warn about SWD reads returning all zeros or all ones.
+    ///
+    /// A stuck SWDIO line reads as one of these values with a valid parity bit. As they are
+    /// also common register contents, this is only useful while debugging the connection.
+    pub flag_suspicious_reads: bool,
+
+    /// Clear the sticky error flags in CTRL/STAT before each JTAG transfer batch.
+    ///
+    /// This makes sure a stale flag left behind by earlier accesses doesn't fail the batch,
+    /// at the cost of reading CTRL/STAT and writing it back.
+    pub preclear_jtag_sticky_errors: bool,
+
+    /// Number of idle cycles sent once at the start of each SWD transfer batch.
+    ///
+    /// Some DPs need a few idle cycles before they recognize the start bit of a request,
+    /// e.g. after the lines were quiet for a long time.
+    pub leading_idle_cycles: usize,
+
+    /// Minimum number of line resets tried when connecting over SWD.
+    ///
+    /// Line resets are repeated until the 1 s guard time has elapsed, and at least this
+    /// many times. A DP confused by e.g. a failed multi-drop select may need more
+    /// attempts than fit into the guard time. Values below 1 are treated as 1.
+    pub line_reset_retries: usize,
+
+    /// The IR opcodes used to select the DAP registers over JTAG.
+    ///
+    /// Some SoCs remap them, the default is the ARM opcode map.
+    pub jtag_ir_opcodes: JtagIrOpcodes,
+
+    /// Retry a DAP register write once, if it failed with a write data error.
+    ///
+    /// The write data error is always cleared, this only controls whether the write is
+    /// tried again.
+    pub retry_write_after_wdataerr: bool,
+
+    /// Whether the probe samples SWDIO on the falling edge of the clock.
+    ///
+    /// Probes sampling on the falling edge see the response one bit earlier than probes
+    /// sampling on the rising edge, in place of the turnaround bit.
+    pub sample_on_falling_edge: bool,
+}
+
 /// Low-level DAP register access.
 ///
 /// Operations on this trait closely match the transactions on the wire. Implementors
@@ -259,6 +361,16 @@ pub trait RawDapAccess {
         Ok(())
     }
 
+    /// Whether the probe implements [`raw_read_block`](Self::raw_read_block) and
+    /// [`raw_write_block`](Self::raw_write_block) with native block transfer commands.
+    ///
+    /// Otherwise, block transfers are decomposed into individual transfers, either by the
+    /// default implementations or by the polyfill for bit-banging probes.
+    /// Probes with native block commands override both block functions and this one.
+    fn supports_native_block(&self) -> bool {
+        false
+    }
+
     /// Flush any outstanding writes.
     ///
     /// By default, this does nothing -- but in probes that implement write
@@ -284,6 +396,24 @@ pub trait RawDapAccess {
     /// the initial reset sequence, for example.
     fn swj_sequence(&mut self, bit_len: u8, bits: u64) -> Result<(), DebugProbeError>;
 
+    /// Move the JTAG TAP to the Test-Logic-Reset state.
+    ///
+    /// This clocks TCK at least five times with TMS high, which resets the TAP
+    /// from any state.
+    fn tap_reset_sequence(&mut self) -> Result<(), DebugProbeError> {
+        self.swj_sequence(6, 0x3F)
+    }
+
+    /// Shift `ir_len` bits of `ir_value` into the JTAG instruction register, LSB first.
+    ///
+    /// The TAP has to be in the Run-Test/Idle state, and is returned to it afterwards.
+    /// Returns the bits captured on TDO while shifting.
+    fn jtag_scan_ir(&mut self, _ir_value: u64, _ir_len: u8) -> Result<BitVec, DebugProbeError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "jtag_scan_ir",
+        })
+    }
+
     /// Set the state of debugger output pins directly.
     ///
     /// The bits have the following meaning:
@@ -306,6 +436,621 @@ pub trait RawDapAccess {
 
     /// Inform the probe of the [`CoreStatus`] of the chip attached to the probe.
     fn core_status_notification(&mut self, state: CoreStatus) -> Result<(), DebugProbeError>;
+
+    /// Perform a list of transfers exactly as given, and return them with their results.
+    ///
+    /// Unlike the other functions of this trait, no transfers are added. Reads from
+    /// an AP need a following transfer to return their value, and writes need one to
+    /// report their status, so the list has to contain these as well, e.g. reads from
+    /// RDBUFF. WAIT responses are retried.
+    ///
+    /// This is intended to reproduce problems with a recorded sequence of transfers.
+    fn replay_transfers(
+        &mut self,
+        _transfers: &[DapTransfer],
+    ) -> Result<Vec<DapTransfer>, ArmError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "replay_transfers",
+        }
+        .into())
+    }
+
+    /// Find the first protocol of `order` the target responds to.
+    ///
+    /// For each protocol, the SWJ-DP switch sequence to it is sent, followed by a read
+    /// of DPIDR. The first protocol which returns a valid DPIDR is left selected and
+    /// returned. When using JTAG, the scan chain has to be configured beforehand.
+    fn auto_select_protocol(&mut self, _order: &[WireProtocol]) -> Result<WireProtocol, ArmError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "auto_select_protocol",
+        }
+        .into())
+    }
+
+    /// Change how WAIT responses are retried.
+    ///
+    /// This allows tuning the retries for a specific target, e.g. one with slow memory.
+    fn configure_retries(&mut self, _config: RetryConfig) -> Result<(), ArmError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "configure_retries",
+        }
+        .into())
+    }
+
+    /// Returns the options currently used for transfers, see [`DapConfig`].
+    fn dap_config(&mut self) -> Result<DapConfig, ArmError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "dap_config",
+        }
+        .into())
+    }
+
+    /// Change the options used for transfers, see [`DapConfig`].
+    fn configure_dap(&mut self, _config: DapConfig) -> Result<(), ArmError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "configure_dap",
+        }
+        .into())
+    }
+
+    /// Returns the architecture version of the DP, as decoded from the last `DPIDR` read.
+    ///
+    /// Returns `None` if `DPIDR` wasn't read since the last line reset. Features like
+    /// multi-drop are only available from DPv2 onwards.
+    fn dp_version(&mut self) -> Result<Option<DebugPortVersion>, ArmError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "dp_version",
+        }
+        .into())
+    }
+
+    /// Return the statistics of the transfers performed so far, and reset them.
+    ///
+    /// Calling this before and after an operation measures the transfers it needed.
+    fn take_statistics(&mut self) -> Result<ProbeStatistics, ArmError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "take_statistics",
+        }
+        .into())
+    }
+
+    /// Measure the round-trip time of a single DP register read.
+    ///
+    /// DPIDR is read a few times and the median duration is returned. Higher layers
+    /// can use this to decide how many transfers to batch.
+    fn measure_rtt(&mut self) -> Result<Duration, ArmError> {
+        const SAMPLES: usize = 5;
+
+        let mut samples = Vec::with_capacity(SAMPLES);
+        for _ in 0..SAMPLES {
+            let start = Instant::now();
+            self.raw_read_register(RegisterAddress::DpRegister(DPIDR::ADDRESS))?;
+            samples.push(start.elapsed());
+        }
+
+        samples.sort();
+
+        Ok(samples[SAMPLES / 2])
+    }
+
+    /// Set a callback, which reports the number of completed transfers while a batch
+    /// of transfers is performed.
+    ///
+    /// This can be used to report the progress of long operations, e.g. large block writes.
+    /// Passing `None` removes a previously set callback.
+    fn set_transfer_progress_callback(
+        &mut self,
+        _callback: Option<TransferProgressCallback>,
+    ) -> Result<(), DebugProbeError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "set_transfer_progress_callback",
+        })
+    }
+
+    /// Set a flag, which cancels a batch of transfers in progress when it is set.
+    ///
+    /// The flag is checked between the chunks a long batch is split into, so a batch sent
+    /// in a single call to the probe is never cancelled. A cancelled batch fails with
+    /// [`DebugProbeError::Cancelled`]. The flag is not cleared by the probe, so following
+    /// batches are cancelled as well until it is cleared again.
+    /// Passing `None` removes a previously set flag.
+    fn set_cancel_flag(&mut self, _flag: Option<Arc<AtomicBool>>) -> Result<(), DebugProbeError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "set_cancel_flag",
+        })
+    }
+
+    /// Set an observer, which is notified of every transfer once its result is known.
+    ///
+    /// Only the transfers requested by the caller are reported, not the `SELECT` writes
+    /// and `RDBUFF` reads added to perform them. Transfers of a batch which fails with an
+    /// error are not reported. Passing `None` removes a previously set observer.
+    fn set_transfer_observer(
+        &mut self,
+        _observer: Option<Box<dyn TransferObserver>>,
+    ) -> Result<(), DebugProbeError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "set_transfer_observer",
+        })
+    }
+
+    /// Perform a single JTAG `DPACC` or `APACC` scan for `transfer`, and return the raw
+    /// response shifted out of the DR.
+    ///
+    /// This is meant for diagnosing debug ports which return malformed responses, so the
+    /// status is not checked and the scan is not retried. The response of a JTAG access is
+    /// only shifted out during the next scan, so this is the response to the previous access.
+    fn raw_jtag_dr_scan(&mut self, _transfer: &DapTransfer) -> Result<JtagDrResponse, ArmError> {
+        Err(ArmError::Probe(
+            DebugProbeError::CommandNotSupportedByProbe {
+                command_name: "raw_jtag_dr_scan",
+            },
+        ))
+    }
+
+    /// Clear the power-up requests in `CTRL/STAT`, so the debug and system power domains
+    /// can power down, e.g. to let the target enter low-power modes.
+    ///
+    /// With `reset_dp`, a debug reset is requested with `CDBGRSTREQ` first, and released
+    /// together with the power-up requests. A debug reset which is not acknowledged is only
+    /// logged, as implementing it is optional. This waits until the power-down is acknowledged.
+    /// Afterwards, the debug port has to be powered up again before an AP can be accessed.
+    fn disconnect(&mut self, reset_dp: bool) -> Result<(), ArmError> {
+        // CTRL/STAT is in bank 0
+        self.raw_write_register(SelectV1::ADDRESS.into(), 0)?;
+
+        if reset_dp {
+            let mut ctrl = Ctrl(0);
+            ctrl.set_cdbgpwrupreq(true);
+            ctrl.set_csyspwrupreq(true);
+            ctrl.set_c_dbg_rst_req(true);
+            self.raw_write_register(Ctrl::ADDRESS.into(), ctrl.into())?;
+
+            if let Err(e) = wait_for_ctrl(self, |ctrl| ctrl.cdbgrstack()) {
+                tracing::warn!("Debug reset was not acknowledged, continuing to power down: {e}");
+            }
+        }
+
+        self.raw_write_register(Ctrl::ADDRESS.into(), 0)?;
+
+        wait_for_ctrl(self, |ctrl| {
+            !(ctrl.csyspwrupack() || ctrl.cdbgpwrupack() || ctrl.cdbgrstack())
+        })
+    }
+
+    /// Read the `IDR` register of the ADIv5 access port `ap`.
+    ///
+    /// This selects the AP and the register bank with a write to `SELECT`, which is left
+    /// pointing to the `IDR` bank afterwards. An [`ArmCommunicationInterface`] caches the
+    /// value of `SELECT`, so this must not be mixed with register accesses through an
+    /// interface using the same probe.
+    ///
+    /// ADIv6 access ports are not supported, on a DPv3 this returns
+    /// [`ArmError::WrongApVersion`].
+    ///
+    /// [`ArmCommunicationInterface`]: crate::architecture::arm::ArmCommunicationInterface
+    fn read_ap_idr(&mut self, ap: u8) -> Result<IDR, ArmError> {
+        read_v1_ap_register(self, ap)
+    }
+
+    /// Read the `BASE` register of the ADIv5 access port `ap`.
+    ///
+    /// Like [`read_ap_idr`](Self::read_ap_idr), this leaves `SELECT` pointing to the
+    /// accessed register bank, and is only supported on ADIv5.
+    fn read_ap_base(&mut self, ap: u8) -> Result<BASE, ArmError> {
+        read_v1_ap_register(self, ap)
+    }
+
+    /// Read a block of memory at `address`, through the ADIv5 memory access port `ap`.
+    ///
+    /// Each element of `data` receives the value of one access of `width`, taken from the
+    /// byte lanes of `DRW` it was read on. `CSW` is configured for accesses of `width` with
+    /// auto-increment, and `TAR` is written again whenever the address crosses a 1 KiB
+    /// boundary, where the auto-increment of `TAR` may wrap. `address` has to be aligned
+    /// to `width`, and accesses wider than 32 bits are not supported.
+    ///
+    /// `SELECT` and `CSW` are written directly, like in [`read_ap_idr`](Self::read_ap_idr),
+    /// so this must not be mixed with register accesses through an
+    /// [`ArmCommunicationInterface`], which caches their values. ADIv6 access ports are not
+    /// supported, on a DPv3 this returns [`ArmError::WrongApVersion`].
+    ///
+    /// [`ArmCommunicationInterface`]: crate::architecture::arm::ArmCommunicationInterface
+    fn read_memory_block(
+        &mut self,
+        ap: u8,
+        mut address: u32,
+        width: DataSize,
+        mut data: &mut [u32],
+    ) -> Result<(), ArmError> {
+        let bytes = configure_memory_access(self, ap, address, width)?;
+
+        while !data.is_empty() {
+            let (chunk, rest) = data.split_at_mut(tar_chunk_len(address, bytes).min(data.len()));
+
+            self.raw_write_register(RegisterAddress::ApRegister(TAR::ADDRESS as u8), address)?;
+            self.raw_read_block(RegisterAddress::ApRegister(DRW::ADDRESS as u8), chunk)?;
+
+            for value in chunk.iter_mut() {
+                *value = from_lane(address, bytes, *value);
+                address = address.wrapping_add(bytes);
+            }
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Write a block of memory at `address`, through the ADIv5 memory access port `ap`.
+    ///
+    /// Each element of `data` is written with one access of `width`, on the byte lanes of
+    /// `DRW` which match its address, so only its lowest bits are used for accesses
+    /// narrower than 32 bits. `CSW` and `TAR` are configured like for
+    /// [`read_memory_block`](Self::read_memory_block), with the same restrictions.
+    fn write_memory_block(
+        &mut self,
+        ap: u8,
+        mut address: u32,
+        width: DataSize,
+        mut data: &[u32],
+    ) -> Result<(), ArmError> {
+        let bytes = configure_memory_access(self, ap, address, width)?;
+
+        while !data.is_empty() {
+            let (chunk, rest) = data.split_at(tar_chunk_len(address, bytes).min(data.len()));
+
+            self.raw_write_register(RegisterAddress::ApRegister(TAR::ADDRESS as u8), address)?;
+
+            let mut lanes = Vec::with_capacity(chunk.len());
+            for value in chunk {
+                lanes.push(to_lane(address, bytes, *value));
+                address = address.wrapping_add(bytes);
+            }
+            self.raw_write_block(RegisterAddress::ApRegister(DRW::ADDRESS as u8), &lanes)?;
+
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Find the access ports of the debug port, and read their `IDR`s.
+    ///
+    /// On ADIv5, the `IDR` of each possible `APSEL` is read, and APs with an `IDR` of zero
+    /// are not present. The scan stops after a run of missing APs, so gaps in the numbering
+    /// are allowed.
+    ///
+    /// On ADIv6, the APs are memory mapped, and are found by walking the ROM tables starting
+    /// at `BASEPTR0/1`. As when the APs of an ADIv6 debug port are found during a normal
+    /// attach, only MEM-APs are reported.
+    ///
+    /// Returns the addresses of the present APs with their `IDR` values.
+    fn enumerate_aps(&mut self) -> Result<Vec<(ApAddress, u32)>, ArmError> {
+        let dpidr = DPIDR::try_from(self.raw_read_register(DPIDR::ADDRESS.into())?)?;
+        if DebugPortVersion::from(dpidr.version()) == DebugPortVersion::DPv3 {
+            return enumerate_v2_aps(self);
+        }
+
+        let mut aps = Vec::new();
+        let mut missing = 0;
+
+        for ap in 0..=u8::MAX {
+            select_v1_ap_bank(self, ap, IDR::ADDRESS)?;
+
+            let idr = self.raw_read_register(RegisterAddress::ApRegister(IDR::ADDRESS as u8))?;
+            if idr == 0 {
+                missing += 1;
+                if missing == MAX_MISSING_APS {
+                    break;
+                }
+            } else {
+                missing = 0;
+                aps.push((ApAddress::V1(ap), idr));
+            }
+        }
+
+        Ok(aps)
+    }
+}
+
+// Time the power domains have to acknowledge a request in `disconnect`
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Poll `CTRL/STAT` until `done` returns true for it.
+fn wait_for_ctrl<P: RawDapAccess + ?Sized>(
+    probe: &mut P,
+    done: impl Fn(Ctrl) -> bool,
+) -> Result<(), ArmError> {
+    let start = Instant::now();
+    loop {
+        let ctrl = Ctrl(probe.raw_read_register(Ctrl::ADDRESS.into())?);
+        if done(ctrl) {
+            return Ok(());
+        }
+
+        if start.elapsed() >= DISCONNECT_TIMEOUT {
+            return Err(ArmError::Timeout);
+        }
+
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// Number of consecutive missing APs after which `enumerate_aps` stops
+const MAX_MISSING_APS: usize = 8;
+
+// Offsets of the identification registers of a CoreSight component
+const COMPONENT_DEVARCH: u64 = 0xFBC;
+const COMPONENT_CIDR1: u64 = 0xFF4;
+
+// Component classes in `CIDR1`
+const CLASS_ROM_TABLE: u32 = 0x1;
+const CLASS_CORESIGHT: u32 = 0x9;
+
+// `DEVARCH.PRESENT`, set if `DEVARCH.ARCHID` is valid
+const DEVARCH_PRESENT: u32 = 1 << 20;
+
+// `DEVARCH.ARCHID` of a MEM-AP
+const MEM_AP_ARCHID: u16 = 0x0a17;
+
+// Number of 32-bit entries a ROM table of each class can hold at most
+const CLASS_ROM_TABLE_ENTRIES: u64 = 960;
+const CORESIGHT_ROM_TABLE_ENTRIES: u64 = 512;
+
+// ROM tables nested deeper than this are not followed
+const MAX_ROM_TABLE_DEPTH: usize = 8;
+
+// TAR is only guaranteed to auto-increment within blocks of this size
+const TAR_AUTOINCR_LIMIT: usize = 0x400;
+
+/// Find the MEM-APs of an ADIv6 debug port, starting at the component `BASEPTR0/1` point to.
+fn enumerate_v2_aps<P: RawDapAccess + ?Sized>(
+    probe: &mut P,
+) -> Result<Vec<(ApAddress, u32)>, ArmError> {
+    let base_ptr0: BASEPTR0 = read_v3_dp_register(probe)?;
+    let base_ptr1: BASEPTR1 = read_v3_dp_register(probe)?;
+    if !base_ptr0.valid() {
+        return Err(ArmError::Other(
+            "DP has no valid base address defined.".into(),
+        ));
+    }
+    let base = (u64::from(base_ptr1.ptr()) << 32) | u64::from(base_ptr0.ptr() << 12);
+
+    let mut space = DpAddressSpace { select1: None };
+    let mut aps = Vec::new();
+    space.scan_component(probe, base, 0, &mut aps)?;
+
+    Ok(aps)
+}
+
+/// Select the DP bank of `R` on a DPv3, and read the register.
+fn read_v3_dp_register<R: DpRegister, P: RawDapAccess + ?Sized>(
+    probe: &mut P,
+) -> Result<R, ArmError> {
+    select_v3_dp_bank(probe, R::ADDRESS)?;
+    let value = probe.raw_read_register(R::ADDRESS.into())?;
+
+    Ok(R::try_from(value)?)
+}
+
+/// Select the DP bank of the register at `address` on a DPv3, with `SELECT.ADDR` cleared.
+fn select_v3_dp_bank<P: RawDapAccess + ?Sized>(
+    probe: &mut P,
+    address: DpRegisterAddress,
+) -> Result<(), ArmError> {
+    let mut select = SelectV3(0);
+    select.set_dp_bank_sel(address.bank.unwrap_or(0));
+
+    probe.raw_write_register(SelectV3::ADDRESS.into(), select.0)
+}
+
+/// Word accesses to the address space of an ADIv6 debug port.
+struct DpAddressSpace {
+    /// The value last written to `SELECT1`, if any.
+    select1: Option<u32>,
+}
+
+impl DpAddressSpace {
+    /// Read the word at `address`, selecting its four word block in `SELECT` and `SELECT1`.
+    fn read<P: RawDapAccess + ?Sized>(
+        &mut self,
+        probe: &mut P,
+        address: u64,
+    ) -> Result<u32, ArmError> {
+        let upper = (address >> 32) as u32;
+        if self.select1 != Some(upper) {
+            select_v3_dp_bank(probe, Select1::ADDRESS)?;
+            probe.raw_write_register(Select1::ADDRESS.into(), upper)?;
+            self.select1 = Some(upper);
+        }
+
+        // Bits [3:2] of the address are part of the AP access itself.
+        let mut select = SelectV3(0);
+        select.set_addr(((address >> 4) & 0x0FFF_FFFF) as u32);
+        probe.raw_write_register(SelectV3::ADDRESS.into(), select.0)?;
+
+        probe.raw_read_register(RegisterAddress::ApRegister(address as u8))
+    }
+
+    /// Add the component at `address` to `aps` if it is a MEM-AP, or the MEM-APs it points
+    /// to if it is a ROM table.
+    fn scan_component<P: RawDapAccess + ?Sized>(
+        &mut self,
+        probe: &mut P,
+        address: u64,
+        depth: usize,
+        aps: &mut Vec<(ApAddress, u32)>,
+    ) -> Result<(), ArmError> {
+        let class = (self.read(probe, address + COMPONENT_CIDR1)? >> 4) & 0xf;
+
+        let arch_id = if class == CLASS_CORESIGHT {
+            let devarch = self.read(probe, address + COMPONENT_DEVARCH)?;
+            (devarch & DEVARCH_PRESENT != 0).then_some(devarch as u16)
+        } else {
+            None
+        };
+
+        let entries = match (class, arch_id) {
+            (CLASS_ROM_TABLE, _) => CLASS_ROM_TABLE_ENTRIES,
+            (CLASS_CORESIGHT, Some(CORESIGHT_ROM_TABLE_ARCHID)) => CORESIGHT_ROM_TABLE_ENTRIES,
+            (CLASS_CORESIGHT, Some(MEM_AP_ARCHID)) => {
+                let idr = self.read(probe, address + IDR::ADDRESS)?;
+                aps.push((ApAddress::V2(ApV2Address::new(address)), idr));
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        if depth == MAX_ROM_TABLE_DEPTH {
+            tracing::warn!(
+                "ROM table at {:#x} is nested too deeply, skipping it",
+                address
+            );
+            return Ok(());
+        }
+
+        for offset in (0..entries).map(|i| i * 4) {
+            let entry = self.read(probe, address + offset)?;
+
+            // The end of the entries is marked by an all zero entry.
+            if entry == 0 {
+                break;
+            }
+            if entry & 1 == 0 {
+                continue;
+            }
+
+            // The offset of the component is a signed multiple of 4 KiB.
+            let component = address.wrapping_add_signed(i64::from((entry & 0xFFFF_F000) as i32));
+            self.scan_component(probe, component, depth + 1, aps)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Select the bank of the register at `address` on the ADIv5 access port `ap`.
+fn select_v1_ap_bank<P: RawDapAccess + ?Sized>(
+    probe: &mut P,
+    ap: u8,
+    address: u64,
+) -> Result<(), ArmError> {
+    let mut select = SelectV1(0);
+    select.set_ap_sel(ap);
+    select.set_ap_bank_sel(((address >> 4) & 0xf) as u8);
+
+    probe.raw_write_register(RegisterAddress::DpRegister(SelectV1::ADDRESS), select.0)
+}
+
+/// Check that the debug port is not a DPv3, where `SELECT` has the ADIv6 layout of
+/// [`SelectV3`] and the ADIv5 helpers can't be used.
+fn ensure_v1_dp<P: RawDapAccess + ?Sized>(probe: &mut P) -> Result<(), ArmError> {
+    let dpidr = DPIDR::try_from(probe.raw_read_register(DPIDR::ADDRESS.into())?)?;
+    if DebugPortVersion::from(dpidr.version()) == DebugPortVersion::DPv3 {
+        return Err(ArmError::WrongApVersion);
+    }
+
+    Ok(())
+}
+
+/// Select the bank of `R` on the ADIv5 access port `ap`, and read the register.
+fn read_v1_ap_register<R: ApRegister, P: RawDapAccess + ?Sized>(
+    probe: &mut P,
+    ap: u8,
+) -> Result<R, ArmError> {
+    ensure_v1_dp(probe)?;
+    select_v1_ap_bank(probe, ap, R::ADDRESS)?;
+    let value = probe.raw_read_register(RegisterAddress::ApRegister(R::ADDRESS as u8))?;
+
+    Ok(R::try_from(value)?)
+}
+
+/// Check `width` and the alignment of `address`, and configure `CSW` of the ADIv5 memory
+/// access port `ap` for auto-incremented accesses of `width`.
+///
+/// Returns the number of bytes of each access.
+fn configure_memory_access<P: RawDapAccess + ?Sized>(
+    probe: &mut P,
+    ap: u8,
+    address: u32,
+    width: DataSize,
+) -> Result<u32, ArmError> {
+    let bytes = match width {
+        DataSize::U8 | DataSize::U16 | DataSize::U32 => width.to_byte_count(),
+        _ => {
+            return Err(ArmError::UnsupportedTransferWidth(
+                width.to_byte_count() * 8,
+            ));
+        }
+    };
+    if address as usize % bytes != 0 {
+        return Err(ArmError::alignment_error(address.into(), bytes));
+    }
+
+    ensure_v1_dp(probe)?;
+    select_v1_ap_bank(probe, ap, CSW::ADDRESS)?;
+
+    let csw_address = RegisterAddress::ApRegister(CSW::ADDRESS as u8);
+    let mut csw = CSW::try_from(probe.raw_read_register(csw_address)?)?;
+    csw.SIZE = width;
+    csw.AddrInc = AddressIncrement::Single;
+    probe.raw_write_register(csw_address, csw.into())?;
+
+    Ok(bytes as u32)
+}
+
+/// Number of accesses of `bytes` from `address` up to the next auto-increment boundary.
+fn tar_chunk_len(address: u32, bytes: u32) -> usize {
+    let remaining = (address as usize + 1).next_multiple_of(TAR_AUTOINCR_LIMIT) - address as usize;
+
+    remaining / bytes as usize
+}
+
+/// Place the lowest `bytes` of `value` on the byte lanes of `DRW` used by an access to
+/// `address`.
+fn to_lane(address: u32, bytes: u32, value: u32) -> u32 {
+    (value & lane_mask(bytes)) << (8 * (address % 4))
+}
+
+/// Take the value of an access of `bytes` to `address` from the byte lanes of `drw`.
+fn from_lane(address: u32, bytes: u32, drw: u32) -> u32 {
+    (drw >> (8 * (address % 4))) & lane_mask(bytes)
+}
+
+/// Mask of the lowest `bytes` of a word.
+fn lane_mask(bytes: u32) -> u32 {
+    u32::MAX >> (32 - 8 * bytes)
+}
+
+/// Callback reporting the number of completed transfers, see
+/// [`RawDapAccess::set_transfer_progress_callback`].
+pub type TransferProgressCallback = Box<dyn FnMut(usize) + Send>;
+
+/// Observer of the performed transfers, see [`RawDapAccess::set_transfer_observer`].
+pub trait TransferObserver: Send {
+    /// Called after `transfer` was performed, with its final `status`.
+    fn on_transfer(&mut self, transfer: &DapTransfer, status: &TransferStatus);
+}
+
+/// Raw response of a JTAG DR scan, see [`RawDapAccess::raw_jtag_dr_scan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JtagDrResponse {
+    /// The 35 bits shifted out of the DR.
+    pub raw: u64,
+    /// The value, bits 35:3 of the response.
+    pub value: u32,
+    /// The status, bits 2:0 of the response.
+    pub status: u8,
+}
+
+impl JtagDrResponse {
+    /// Split a raw 35 bit DR response into the value and the status.
+    pub fn from_raw(raw: u64) -> Self {
+        Self {
+            raw,
+            value: (raw >> 3) as u32,
+            status: (raw & 0b111) as u8,
+        }
+    }
 }
 
 /// High-level DAP register access.
@@ -430,3 +1175,392 @@ pub trait DapAccess {
     /// Gain mutable access to the Probe that implements this trait
     fn try_dap_probe_mut(&mut self) -> Option<&mut dyn DapProbe>;
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::{ApAddress, ApV2Address, RawDapAccess, RegisterAddress};
+    use crate::{
+        architecture::arm::{
+            ArmError,
+            ap::{ApRegister, CSW, DRW, DataSize, TAR},
+            dp::{BASEPTR0, BASEPTR1, Ctrl, DPIDR, DpRegister, SelectV1, SelectV3},
+        },
+        probe::fake_probe::FakeProbe,
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum Access {
+        Read(RegisterAddress),
+        Write(RegisterAddress, u32),
+    }
+
+    #[test]
+    fn read_memory_block_across_autoincrement_boundary() {
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        let next_word = Arc::new(Mutex::new(0u32));
+
+        let mut probe = FakeProbe::new();
+        probe.set_dap_register_read_handler({
+            let accesses = accesses.clone();
+            Box::new(move |address| {
+                accesses.lock().unwrap().push(Access::Read(address));
+
+                match address {
+                    // DPv1
+                    RegisterAddress::DpRegister(DPIDR::ADDRESS) => Ok(0x2ba0_1477),
+                    // 8 bit accesses without auto-increment
+                    RegisterAddress::ApRegister(0x00) => Ok(0x2300_0040),
+                    _ => {
+                        let mut next_word = next_word.lock().unwrap();
+                        *next_word += 1;
+                        Ok(*next_word)
+                    }
+                }
+            })
+        });
+        probe.set_dap_register_write_handler({
+            let accesses = accesses.clone();
+            Box::new(move |address, value| {
+                accesses.lock().unwrap().push(Access::Write(address, value));
+                Ok(())
+            })
+        });
+
+        let mut data = [0; 4];
+        probe
+            .read_memory_block(1, 0x2000_03f8, DataSize::U32, &mut data)
+            .unwrap();
+
+        assert_eq!(data, [1, 2, 3, 4]);
+
+        let csw = RegisterAddress::ApRegister(CSW::ADDRESS as u8);
+        let tar = RegisterAddress::ApRegister(TAR::ADDRESS as u8);
+        let drw = RegisterAddress::ApRegister(DRW::ADDRESS as u8);
+        assert_eq!(
+            *accesses.lock().unwrap(),
+            [
+                Access::Read(RegisterAddress::DpRegister(DPIDR::ADDRESS)),
+                Access::Write(RegisterAddress::DpRegister(SelectV1::ADDRESS), 0x0100_0000),
+                Access::Read(csw),
+                Access::Write(csw, 0x2300_0052),
+                Access::Write(tar, 0x2000_03f8),
+                Access::Read(drw),
+                Access::Read(drw),
+                // TAR is written again at the 1 KiB boundary
+                Access::Write(tar, 0x2000_0400),
+                Access::Read(drw),
+                Access::Read(drw),
+            ]
+        );
+    }
+
+    /// A probe recording all register accesses, with a DPv1 and 8 bit accesses in `CSW`.
+    fn recording_probe() -> (FakeProbe, Arc<Mutex<Vec<Access>>>) {
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+
+        let mut probe = FakeProbe::new();
+        probe.set_dap_register_read_handler({
+            let accesses = accesses.clone();
+            Box::new(move |address| {
+                accesses.lock().unwrap().push(Access::Read(address));
+                Ok(match address {
+                    // DPv1
+                    RegisterAddress::DpRegister(DPIDR::ADDRESS) => 0x2ba0_1477,
+                    // 8 bit accesses without auto-increment
+                    _ => 0x2300_0040,
+                })
+            })
+        });
+        probe.set_dap_register_write_handler({
+            let accesses = accesses.clone();
+            Box::new(move |address, value| {
+                accesses.lock().unwrap().push(Access::Write(address, value));
+                Ok(())
+            })
+        });
+
+        (probe, accesses)
+    }
+
+    #[test]
+    fn write_memory_block_8bit() {
+        let (mut probe, accesses) = recording_probe();
+
+        probe
+            .write_memory_block(1, 0x4000_0001, DataSize::U8, &[0xab, 0xcd])
+            .unwrap();
+
+        let csw = RegisterAddress::ApRegister(CSW::ADDRESS as u8);
+        let tar = RegisterAddress::ApRegister(TAR::ADDRESS as u8);
+        let drw = RegisterAddress::ApRegister(DRW::ADDRESS as u8);
+        assert_eq!(
+            *accesses.lock().unwrap(),
+            [
+                Access::Read(RegisterAddress::DpRegister(DPIDR::ADDRESS)),
+                Access::Write(RegisterAddress::DpRegister(SelectV1::ADDRESS), 0x0100_0000),
+                Access::Read(csw),
+                Access::Write(csw, 0x2300_0050),
+                Access::Write(tar, 0x4000_0001),
+                // Each byte is placed on the lane of its address
+                Access::Write(drw, 0x0000_ab00),
+                Access::Write(drw, 0x00cd_0000),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_memory_block_16bit() {
+        let (mut probe, accesses) = recording_probe();
+
+        probe
+            .write_memory_block(1, 0x4000_0002, DataSize::U16, &[0x1234])
+            .unwrap();
+
+        let csw = RegisterAddress::ApRegister(CSW::ADDRESS as u8);
+        let tar = RegisterAddress::ApRegister(TAR::ADDRESS as u8);
+        let drw = RegisterAddress::ApRegister(DRW::ADDRESS as u8);
+        assert_eq!(
+            *accesses.lock().unwrap(),
+            [
+                Access::Read(RegisterAddress::DpRegister(DPIDR::ADDRESS)),
+                Access::Write(RegisterAddress::DpRegister(SelectV1::ADDRESS), 0x0100_0000),
+                Access::Read(csw),
+                Access::Write(csw, 0x2300_0051),
+                Access::Write(tar, 0x4000_0002),
+                // The upper half-word lane
+                Access::Write(drw, 0x1234_0000),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_memory_block_8bit_takes_the_byte_lane() {
+        let mut probe = FakeProbe::new();
+        probe.set_dap_register_read_handler(Box::new(|address| {
+            Ok(match address {
+                RegisterAddress::DpRegister(DPIDR::ADDRESS) => 0x2ba0_1477,
+                RegisterAddress::ApRegister(0x00) => 0x2300_0040,
+                _ => 0x4433_2211,
+            })
+        }));
+
+        let mut data = [0; 2];
+        probe
+            .read_memory_block(1, 0x4000_0002, DataSize::U8, &mut data)
+            .unwrap();
+
+        assert_eq!(data, [0x33, 0x44]);
+    }
+
+    #[test]
+    fn enumerate_aps_skips_gaps() {
+        const IDR_AP0: u32 = 0x2477_0011;
+        const IDR_AP2: u32 = 0x0477_0031;
+
+        let apsel = Arc::new(Mutex::new(0u8));
+
+        let mut probe = FakeProbe::new();
+        probe.set_dap_register_write_handler({
+            let apsel = apsel.clone();
+            Box::new(move |address, value| {
+                if address == RegisterAddress::DpRegister(SelectV1::ADDRESS) {
+                    *apsel.lock().unwrap() = SelectV1(value).ap_sel();
+                }
+                Ok(())
+            })
+        });
+        probe.set_dap_register_read_handler(Box::new(move |address| {
+            Ok(match address {
+                // DPv1
+                RegisterAddress::DpRegister(DPIDR::ADDRESS) => 0x2ba0_1477,
+                RegisterAddress::ApRegister(0xfc) => match *apsel.lock().unwrap() {
+                    0 => IDR_AP0,
+                    2 => IDR_AP2,
+                    _ => 0,
+                },
+                _ => 0,
+            })
+        }));
+
+        let aps = probe.enumerate_aps().unwrap();
+
+        assert_eq!(
+            aps,
+            [(ApAddress::V1(0), IDR_AP0), (ApAddress::V1(2), IDR_AP2)]
+        );
+    }
+
+    /// A fake DPv3, with `memory` mapped into its address space at `BASEPTR0`.
+    fn fake_dpv3(base_ptr0: u32, memory: fn(u64) -> u32) -> FakeProbe {
+        let select = Arc::new(Mutex::new(SelectV3(0)));
+
+        let mut probe = FakeProbe::new();
+        probe.set_dap_register_write_handler({
+            let select = select.clone();
+            Box::new(move |address, value| {
+                if address == RegisterAddress::DpRegister(SelectV3::ADDRESS) {
+                    *select.lock().unwrap() = SelectV3(value);
+                }
+                Ok(())
+            })
+        });
+        probe.set_dap_register_read_handler(Box::new(move |address| {
+            let select = *select.lock().unwrap();
+            Ok(match address {
+                // DPv3
+                RegisterAddress::DpRegister(DPIDR::ADDRESS) => 0x2ba0_3477,
+                RegisterAddress::DpRegister(BASEPTR0::ADDRESS) if select.dp_bank_sel() == 2 => {
+                    base_ptr0
+                }
+                RegisterAddress::DpRegister(BASEPTR1::ADDRESS) if select.dp_bank_sel() == 3 => 0,
+                RegisterAddress::ApRegister(lsb) => {
+                    memory((u64::from(select.addr()) << 4) | u64::from(lsb & 0xc))
+                }
+                address => panic!("Unexpected read of {address:?}"),
+            })
+        }));
+
+        probe
+    }
+
+    #[test]
+    fn enumerate_aps_walks_adiv6_rom_table() {
+        const IDR_AP1: u32 = 0x0477_0025;
+        const IDR_AP3: u32 = 0x2477_0016;
+
+        let mut probe = fake_dpv3(0x8000_0001, |address| match address {
+            // CoreSight ROM table, with a MEM-AP, a CTI, a missing entry and a MEM-AP
+            0x8000_0000 => 0x0000_1003,
+            0x8000_0004 => 0x0000_2003,
+            0x8000_0008 => 0x0000_3002,
+            0x8000_000c => 0xffff_f003,
+            0x8000_0fbc => 0x4770_0af7,
+            0x8000_0ff4 => 0x90,
+            0x8000_1dfc => IDR_AP1,
+            0x8000_1fbc => 0x4770_0a17,
+            0x8000_1ff4 => 0x90,
+            0x8000_2fbc => 0x4770_1a14,
+            0x8000_2ff4 => 0x90,
+            0x7fff_fdfc => IDR_AP3,
+            0x7fff_ffbc => 0x4770_0a17,
+            0x7fff_fff4 => 0x90,
+            _ => 0,
+        });
+
+        let aps = probe.enumerate_aps().unwrap();
+
+        assert_eq!(
+            aps,
+            [
+                (ApAddress::V2(ApV2Address::new(0x8000_1000)), IDR_AP1),
+                (ApAddress::V2(ApV2Address::new(0x7fff_f000)), IDR_AP3)
+            ]
+        );
+    }
+
+    #[test]
+    fn enumerate_aps_without_adiv6_base_address() {
+        let mut probe = fake_dpv3(0x8000_0000, |_| 0);
+
+        let result = probe.enumerate_aps();
+
+        assert!(matches!(result, Err(ArmError::Other(_))));
+    }
+
+    #[test]
+    fn disconnect_clears_power_up_requests() {
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        // The target is powered up, the acknowledge bits follow the request bits.
+        let ctrl = Arc::new(Mutex::new(0x5000_0000u32));
+
+        let mut probe = FakeProbe::new();
+        probe.set_dap_register_write_handler({
+            let accesses = accesses.clone();
+            let ctrl = ctrl.clone();
+            Box::new(move |address, value| {
+                accesses.lock().unwrap().push(Access::Write(address, value));
+                if address == RegisterAddress::DpRegister(Ctrl::ADDRESS) {
+                    *ctrl.lock().unwrap() = value;
+                }
+                Ok(())
+            })
+        });
+        probe.set_dap_register_read_handler(Box::new(move |_| {
+            let requests = *ctrl.lock().unwrap();
+            Ok(requests | ((requests & 0x5400_0000) << 1))
+        }));
+
+        probe.disconnect(true).unwrap();
+
+        let ctrl = RegisterAddress::DpRegister(Ctrl::ADDRESS);
+        assert_eq!(
+            *accesses.lock().unwrap(),
+            [
+                Access::Write(RegisterAddress::DpRegister(SelectV1::ADDRESS), 0),
+                // Debug reset request, with the power kept up
+                Access::Write(ctrl, 0x5400_0000),
+                Access::Write(ctrl, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn disconnect_without_debug_reset_ack() {
+        let ctrl = Arc::new(Mutex::new(0x5000_0000u32));
+
+        let mut probe = FakeProbe::new();
+        probe.set_dap_register_write_handler({
+            let ctrl = ctrl.clone();
+            Box::new(move |address, value| {
+                if address == RegisterAddress::DpRegister(Ctrl::ADDRESS) {
+                    *ctrl.lock().unwrap() = value;
+                }
+                Ok(())
+            })
+        });
+        // Only the power-up requests are acknowledged, CDBGRSTACK stays clear.
+        probe.set_dap_register_read_handler(Box::new(move |_| {
+            let requests = *ctrl.lock().unwrap();
+            Ok(requests | ((requests & 0x5000_0000) << 1))
+        }));
+
+        probe.disconnect(true).unwrap();
+    }
+
+    #[test]
+    fn read_memory_block_unaligned() {
+        let mut probe = FakeProbe::new();
+
+        let mut data = [0; 1];
+        let result = probe.read_memory_block(1, 0x2000_0002, DataSize::U32, &mut data);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn memory_helpers_reject_dpv3() {
+        let mut probe = fake_dpv3(0x8000_0001, |_| 0);
+
+        let mut data = [0; 1];
+        let result = probe.read_memory_block(1, 0x2000_0000, DataSize::U32, &mut data);
+        assert!(matches!(result, Err(ArmError::WrongApVersion)));
+
+        let result = probe.read_ap_idr(0);
+        assert!(matches!(result, Err(ArmError::WrongApVersion)));
+    }
+
+    #[test]
+    fn read_memory_block_rejects_64bit_accesses() {
+        let mut probe = FakeProbe::new();
+
+        let mut data = [0; 2];
+        let result = probe.read_memory_block(1, 0x2000_0000, DataSize::U64, &mut data);
+
+        assert!(matches!(
+            result,
+            Err(ArmError::UnsupportedTransferWidth(64))
+        ));
+    }
+}
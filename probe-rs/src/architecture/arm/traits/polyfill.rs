@@ -4,30 +4,48 @@
 //!
 //! See <https://developer.arm.com/documentation/ihi0031/f/?lang=en> for the ADIv5 specification.
 
-use bitvec::{bitvec, field::BitField, slice::BitSlice, vec::BitVec};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use bitvec::{bitvec, field::BitField, order::Lsb0, slice::BitSlice, vec::BitVec, view::BitView};
 
 use crate::{
     Error,
     architecture::arm::{
-        ArmError, DapError, FullyQualifiedApAddress, RawDapAccess, RegisterAddress,
-        ap::AccessPortError,
-        dp::{Abort, Ctrl, DPIDR, DebugPortError, DpRegister, RdBuff},
+        ArmError, DapConfig, DapError, FullyQualifiedApAddress, JtagDrResponse, RawDapAccess,
+        RegisterAddress, RetryConfig, TransferObserver, TransferProgressCallback,
+        ap::{AccessPortError, ApRegister, IDR},
+        dp::{
+            Abort, Ctrl, DPIDR, DebugPortError, DebugPortVersion, DpRegister, DpRegisterAddress,
+            RdBuff, Select1, SelectV1, SelectV3,
+        },
+        sequences::SwdConnectAttempts,
     },
     probe::{
         CommandQueue, CommandResult, DebugProbe, DebugProbeError, IoSequenceItem, JtagAccess,
-        JtagSequence, JtagWriteCommand, RawSwdIo, WireProtocol, common::bits_to_byte,
+        JtagIrOpcodes, JtagSequence, JtagWriteCommand, ProbeStatistics, RawSwdIo, RdBuffReason,
+        WireProtocol, common::bits_to_byte,
     },
 };
 
 const CTRL_PORT: RegisterAddress = RegisterAddress::DpRegister(Ctrl::ADDRESS);
 
+// The DP bank SELECT1 is accessed in, on ADIv6
+const SELECT1_BANK: u8 = 5;
+
+// A line reset needs at least 50 cycles with SWDIO high
+const LINE_RESET_CYCLES: usize = 51;
+// Idle cycles needed after a line reset, before the first request
+const IDLE_CYCLES_AFTER_LINE_RESET: usize = 2;
+
 // Constant to be written to ABORT
 const JTAG_ABORT_VALUE: u64 = 0x8;
 
-// IR values for JTAG registers
-const JTAG_ABORT_IR_VALUE: u32 = 0x8; // A DAP abort, compatible with DPv0
-const JTAG_DEBUG_PORT_IR_VALUE: u32 = 0xA;
-const JTAG_ACCESS_PORT_IR_VALUE: u32 = 0xB;
+// Sticky flags in CTRL/STAT, which are write-one-to-clear on a JTAG-DP
+const CTRL_STICKYORUN: u32 = 1 << 1;
+const CTRL_STICKYERR: u32 = 1 << 5;
 
 const JTAG_STATUS_WAIT: u32 = 0x1;
 /// OK/FAULT response
@@ -37,13 +55,13 @@ const JTAG_STATUS_OK: u32 = 0x2;
 const JTAG_DR_BIT_LENGTH: u32 = 35;
 
 // Build a JTAG payload
-fn build_jtag_payload_and_address(transfer: &DapTransfer) -> (u64, u32) {
+fn build_jtag_payload_and_address(transfer: &DapTransfer, opcodes: &JtagIrOpcodes) -> (u64, u32) {
     if transfer.is_abort() {
-        (JTAG_ABORT_VALUE, JTAG_ABORT_IR_VALUE)
+        (JTAG_ABORT_VALUE, opcodes.abort)
     } else {
         let address = match transfer.address.is_ap() {
-            false => JTAG_DEBUG_PORT_IR_VALUE,
-            true => JTAG_ACCESS_PORT_IR_VALUE,
+            false => opcodes.dpacc,
+            true => opcodes.apacc,
         };
 
         let port_address = transfer.address.a2_and_3();
@@ -65,29 +83,95 @@ fn parse_jtag_response(data: &BitSlice) -> u64 {
     data.load_le::<u64>()
 }
 
-/// Perform a single JTAG transfer and parse the results
+/// Parse the response of a batched JTAG transfer, other than an ABORT.
+fn transform_jtag_response(
+    _command: &JtagWriteCommand,
+    response: &BitSlice,
+) -> Result<CommandResult, Error> {
+    let received = parse_jtag_response(response);
+
+    // Received value is bits [35:3]
+    let received_value = (received >> 3) as u32;
+    // Status is bits [2:0]
+    let status = (received & 0b111) as u32;
+
+    let error = match status {
+        s if s == JTAG_STATUS_OK => return Ok(CommandResult::U32(received_value)),
+        s if s == JTAG_STATUS_WAIT => DapError::WaitResponse,
+        _ => {
+            tracing::debug!("Unexpected DAP response: {}", status);
+
+            DapError::NoAcknowledge
+        }
+    };
+
+    Err(Error::Arm(ArmError::AccessPort {
+        address: FullyQualifiedApAddress::v1_with_default_dp(0), // Dummy value, unused
+        source: AccessPortError::DebugPort(DebugPortError::Dap(error)),
+    }))
+}
+
+/// Set the idle cycles the probe inserts after each JTAG transfer.
 ///
-/// Return is (value, status)
-fn perform_jtag_transfer<P: JtagAccess + RawSwdIo>(
+/// Probes support a limited number of idle cycles, the rest is dropped. Dropping most of
+/// the requested cycles usually means the idle settings are misconfigured, so this is
+/// reported as a warning.
+fn apply_idle_cycles<P: JtagAccess + RawSwdIo>(
+    probe: &mut P,
+    idle_cycles: usize,
+) -> Result<(), DebugProbeError> {
+    let applied = probe.set_idle_cycles(idle_cycles)? as usize;
+
+    if idle_cycles / 2 > applied {
+        tracing::warn!(
+            "Probe only supports {} of {} idle cycles, check the idle cycle settings",
+            applied,
+            idle_cycles
+        );
+        probe.probe_statistics().report_idle_cycles_clamped();
+    } else if applied < idle_cycles {
+        tracing::trace!(
+            "Probe only supports {} of {} idle cycles",
+            applied,
+            idle_cycles
+        );
+    }
+
+    Ok(())
+}
+
+/// Perform the DR scan of a single JTAG transfer, and return the raw response.
+fn perform_jtag_scan<P: JtagAccess + RawSwdIo>(
     probe: &mut P,
     transfer: &DapTransfer,
-) -> Result<(u32, TransferStatus), DebugProbeError> {
+) -> Result<u64, DebugProbeError> {
     // Determine what JTAG IR address and value to send
-    let (payload, address) = build_jtag_payload_and_address(transfer);
+    let opcodes = probe.swd_settings().jtag_ir_opcodes;
+    let (payload, address) = build_jtag_payload_and_address(transfer, &opcodes);
     let data = payload.to_le_bytes();
 
     let idle_cycles = probe.idle_cycles();
-    probe.set_idle_cycles(transfer.idle_cycles_after.min(255) as u8)?;
+    apply_idle_cycles(probe, transfer.idle_cycles_after)?;
 
     // This is a bit confusing, but a read from any port is still
     // a JTAG write as we have to transmit the address
     let result = probe.write_register(address, &data[..], JTAG_DR_BIT_LENGTH);
 
-    probe.set_idle_cycles(idle_cycles)?;
+    probe.set_idle_cycles(idle_cycles.into())?;
 
     let result = result?;
 
-    let received = parse_jtag_response(&result);
+    Ok(parse_jtag_response(&result))
+}
+
+/// Perform a single JTAG transfer and parse the results
+///
+/// Return is (value, status)
+fn perform_jtag_transfer<P: JtagAccess + RawSwdIo>(
+    probe: &mut P,
+    transfer: &DapTransfer,
+) -> Result<(u32, TransferStatus), DebugProbeError> {
+    let received = perform_jtag_scan(probe, transfer)?;
 
     if transfer.is_abort() {
         // No responses returned from this
@@ -112,6 +196,31 @@ fn perform_jtag_transfer<P: JtagAccess + RawSwdIo>(
     Ok((received_value, transfer_status))
 }
 
+/// Clear the sticky error flags before a JTAG batch.
+///
+/// Like the clear after a failed batch, this writes CTRL/STAT back with the flags set,
+/// since they are write-one-to-clear on a JTAG-DP. CTRL/STAT is read first, so that the
+/// power-up requests and the other settings in it are kept.
+fn preclear_jtag_sticky_errors<P: JtagAccess + RawSwdIo>(
+    probe: &mut P,
+) -> Result<(), DebugProbeError> {
+    tracing::trace!("Clearing sticky errors before JTAG batch");
+
+    perform_jtag_transfer(probe, &DapTransfer::read(Ctrl::ADDRESS))?;
+    let (ctrl, status) = perform_jtag_transfer(probe, &DapTransfer::read(RdBuff::ADDRESS))?;
+    if status != TransferStatus::Ok {
+        tracing::debug!("Failed to read CTRL/STAT before JTAG batch: {:?}", status);
+        return Ok(());
+    }
+
+    perform_jtag_transfer(
+        probe,
+        &DapTransfer::write(Ctrl::ADDRESS, ctrl | CTRL_STICKYERR | CTRL_STICKYORUN),
+    )?;
+
+    Ok(())
+}
+
 /// Perform a batch of JTAG transfers.
 ///
 /// Each transfer is sent one at a time using the JtagAccess trait
@@ -119,26 +228,39 @@ fn perform_jtag_transfers<P: JtagAccess + RawSwdIo>(
     probe: &mut P,
     transfers: &mut [DapTransfer],
 ) -> Result<(), DebugProbeError> {
+    assert!(!transfers.is_empty());
+
+    if probe.swd_settings().preclear_jtag_sticky_errors {
+        preclear_jtag_sticky_errors(probe)?;
+    }
+
+    let opcodes = probe.swd_settings().jtag_ir_opcodes;
+
     // Set up the command queue.
     let mut queue = CommandQueue::new();
 
+    // One result index per queued command, in the order the commands are sent.
     let mut results = vec![];
 
     for transfer in transfers.iter() {
-        results.push(queue.schedule(transfer.jtag_write()));
+        results.push(queue.schedule(transfer.jtag_write(&opcodes)));
     }
 
     let last_is_abort = transfers[transfers.len() - 1].is_abort();
     let last_is_rdbuff = transfers[transfers.len() - 1].is_rdbuff();
-    if !last_is_abort && !last_is_rdbuff {
+
+    let verify_ctrl = !last_is_abort && probe.swd_settings().verify_jtag_ctrl;
+
+    // A trailing RDBUFF read gets its ack from the CTRL/STAT check, if there is one.
+    if !last_is_abort && (!last_is_rdbuff || !verify_ctrl) {
         // Need to issue a fake read to get final ack
-        results.push(queue.schedule(DapTransfer::read(RdBuff::ADDRESS).jtag_write()));
+        results.push(queue.schedule(DapTransfer::read(RdBuff::ADDRESS).jtag_write(&opcodes)));
     }
 
-    if !last_is_abort {
+    if verify_ctrl {
         // Check CTRL/STATUS to make sure OK/FAULT meant OK
-        results.push(queue.schedule(DapTransfer::read(Ctrl::ADDRESS).jtag_write()));
-        results.push(queue.schedule(DapTransfer::read(RdBuff::ADDRESS).jtag_write()));
+        results.push(queue.schedule(DapTransfer::read(Ctrl::ADDRESS).jtag_write(&opcodes)));
+        results.push(queue.schedule(DapTransfer::read(RdBuff::ADDRESS).jtag_write(&opcodes)));
     }
 
     let mut status_responses = vec![TransferStatus::Pending; results.len()];
@@ -151,7 +273,7 @@ fn perform_jtag_transfers<P: JtagAccess + RawSwdIo>(
         .max()
         .unwrap_or(0);
     let idle_cycles = probe.idle_cycles();
-    probe.set_idle_cycles(max_idle_cycles.min(255) as u8)?;
+    apply_idle_cycles(probe, max_idle_cycles)?;
 
     // Execute as much of the queue as we can. We'll handle the rest in a following iteration
     // if we can.
@@ -162,7 +284,7 @@ fn perform_jtag_transfers<P: JtagAccess + RawSwdIo>(
             jtag_results = r;
         }
         Err(e) => {
-            let current_idx = e.results.len();
+            let current_idx = e.results.len().min(results.len());
             status_responses[..current_idx].fill(TransferStatus::Ok);
             jtag_results = e.results;
 
@@ -173,7 +295,9 @@ fn perform_jtag_transfers<P: JtagAccess + RawSwdIo>(
                 }) => {
                     // Mark all subsequent transactions with the same failure.
                     status_responses[current_idx..].fill(TransferStatus::Failed(failure));
-                    jtag_results.push(&results[current_idx], CommandResult::None);
+                    if let Some(failed) = results.get(current_idx) {
+                        jtag_results.push(failed, CommandResult::None);
+                    }
                 }
                 Error::Probe(error) => return Err(error),
                 _other => unreachable!(),
@@ -181,39 +305,59 @@ fn perform_jtag_transfers<P: JtagAccess + RawSwdIo>(
         }
     }
 
-    probe.set_idle_cycles(idle_cycles)?;
+    probe.set_idle_cycles(idle_cycles.into())?;
 
-    // Process the results. At this point we should only have OK/FAULT responses.
-    for (i, transfer) in transfers.iter_mut().enumerate() {
-        transfer.status = *status_responses.get(i + 1).unwrap_or(&TransferStatus::Ok);
-    }
+    // The response to a command is shifted out during the next command, so both the status
+    // and the read value of transfer `i` are found at index `i + 1`. The only transfer
+    // without a following command is a trailing ABORT, which doesn't have a response.
+    let mut results = results.into_iter().map(Some).collect::<Vec<_>>();
+    let num_transfers = transfers.len();
 
-    // Pluck off the extra 2 results that do error checking
-    let ctrl_value = if !last_is_abort {
-        _ = results
-            .pop()
-            .expect("Failed to pop value that was pushed here.");
-        let rdbuff_result = results
+    // The CTRL/STAT value is returned by the RDBUFF read following the CTRL/STAT read, which
+    // is always the last command in the queue.
+    let ctrl_value = if verify_ctrl {
+        results
             .pop()
-            .expect("Failed to pop value that was pushed here.");
-
-        Some(rdbuff_result)
+            .expect("The CTRL/STAT check was scheduled above, this is a bug!")
     } else {
         None
     };
 
-    // Shift the results.
-    // Each response is read in the next transaction, so skip 1
-    for (i, result) in results.into_iter().skip(1).enumerate() {
-        let transfer = &mut transfers[i];
-        if transfer.is_abort() || transfer.is_rdbuff() {
+    for (i, transfer) in transfers.iter_mut().enumerate() {
+        if transfer.is_abort() {
+            // No responses returned from this
             transfer.status = TransferStatus::Ok;
             continue;
         }
 
-        if transfer.status == TransferStatus::Ok && transfer.direction == TransferDirection::Read {
-            let response = jtag_results.take(result).unwrap();
-            transfer.value = response.into_u32();
+        transfer.status = status_responses
+            .get(i + 1)
+            .copied()
+            .unwrap_or(TransferStatus::Ok);
+
+        let is_read = transfer.direction == TransferDirection::Read;
+        if transfer.status != TransferStatus::Ok || !is_read {
+            continue;
+        }
+
+        let response = results
+            .get_mut(i + 1)
+            .and_then(Option::take)
+            .map(|index| jtag_results.take(index));
+
+        match response {
+            Some(Ok(CommandResult::U32(value))) => transfer.value = value,
+            other => {
+                // This happens if the read is directly followed by an ABORT, which doesn't
+                // shift out the response of the previous command.
+                tracing::debug!(
+                    "No response for JTAG read {}/{}: {:?}",
+                    i + 1,
+                    num_transfers,
+                    other
+                );
+                transfer.status = TransferStatus::Failed(DapError::Protocol(WireProtocol::Jtag));
+            }
         }
     }
 
@@ -254,40 +398,116 @@ fn perform_swd_transfers<P: RawSwdIo>(
     probe: &mut P,
     transfers: &mut [DapTransfer],
 ) -> Result<(), DebugProbeError> {
-    let mut io_sequence = IoSequence::new();
+    let idle_bit = probe.swd_settings().idle_cycle_swdio;
+    let max_io_items = probe.swd_settings().max_io_items_per_call;
+    let leading_idle_cycles = probe.swd_settings().leading_idle_cycles;
+    let parking_idle_cycles = probe.swd_settings().parking_idle_cycles;
+    let flag_suspicious_reads = probe.swd_settings().flag_suspicious_reads;
+    let sample_on_falling_edge = probe.swd_settings().sample_on_falling_edge;
+
+    let mut completed = 0;
+
+    while completed < transfers.len() {
+        let cancelled = probe
+            .raw_dap_state()
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed));
+        if completed > 0 && cancelled {
+            tracing::debug!("Transfer batch cancelled after {} transfers", completed);
+            return Err(DebugProbeError::Cancelled { completed });
+        }
 
-    for transfer in transfers.iter() {
-        io_sequence.extend(&transfer.io_sequence());
-    }
+        let mut io_sequence = IoSequence::new();
+        let mut chunk_len = 0;
 
-    let result = probe.swd_io(io_sequence.io_items())?;
+        // Only the first call of the batch starts with the leading idle cycles.
+        let leading_len = if completed == 0 {
+            leading_idle_cycles
+        } else {
+            0
+        };
+        io_sequence.reserve(leading_len);
+        for _ in 0..leading_len {
+            io_sequence.add_output(idle_bit);
+        }
 
-    let mut result_bits = &result[..];
+        for transfer in transfers[completed..].iter() {
+            let transfer_sequence = transfer.io_sequence(idle_bit);
 
-    for (i, transfer) in transfers.iter_mut().enumerate() {
-        // There are eight request bits, the response comes directly after.
-        let response_offset = 8;
-        let response = parse_swd_response(&result_bits[response_offset..], transfer.direction);
+            // Always send at least one transfer.
+            let chunk_full = max_io_items.is_some_and(|max| {
+                chunk_len > 0 && io_sequence.len() + transfer_sequence.len() > max
+            });
+            if chunk_full {
+                break;
+            }
 
-        probe.probe_statistics().report_swd_response(&response);
+            io_sequence.extend(&transfer_sequence);
+            chunk_len += 1;
+        }
+
+        // Leave the line in a known state until the next call.
+        io_sequence.reserve(parking_idle_cycles);
+        for _ in 0..parking_idle_cycles {
+            io_sequence.add_output(idle_bit);
+        }
+
+        let result = probe.swd_io(io_sequence.io_items())?;
+
+        let mut result_bits = &result[leading_len..];
+
+        for (i, transfer) in transfers[completed..completed + chunk_len]
+            .iter_mut()
+            .enumerate()
+        {
+            // There are eight request bits, the response comes directly after.
+            let response_offset = 8;
+            let response = parse_swd_response(
+                &result_bits[response_offset..],
+                transfer.direction,
+                sample_on_falling_edge,
+            );
 
-        transfer.status = match response {
-            Ok(response) => {
-                transfer.value = response;
-                TransferStatus::Ok
+            probe.probe_statistics().report_swd_response(&response);
+
+            transfer.status = match response {
+                Ok(response) => {
+                    transfer.value = response;
+                    TransferStatus::Ok
+                }
+
+                Err(e) => TransferStatus::Failed(e),
+            };
+
+            let suspicious_read = flag_suspicious_reads
+                && transfer.direction == TransferDirection::Read
+                && transfer.status == TransferStatus::Ok
+                && matches!(transfer.value, 0 | u32::MAX);
+            if suspicious_read {
+                tracing::warn!(
+                    "SWD read {} returned {:#010x}, which is also what a stuck SWDIO line reads",
+                    completed + i,
+                    transfer.value
+                );
+                probe.probe_statistics().report_suspicious_read();
             }
 
-            Err(e) => TransferStatus::Failed(e),
-        };
+            tracing::trace!(
+                "Transfer result {}: {:?} {:x?}",
+                completed + i,
+                transfer.status,
+                transfer.value
+            );
 
-        tracing::trace!(
-            "Transfer result {}: {:?} {:x?}",
-            i,
-            transfer.status,
-            transfer.value
-        );
+            result_bits = &result_bits[transfer.swd_response_length()..];
+        }
 
-        result_bits = &result_bits[transfer.swd_response_length()..];
+        completed += chunk_len;
+
+        if let Some(progress) = probe.raw_dap_state().transfer_progress.as_mut() {
+            progress(completed);
+        }
     }
 
     Ok(())
@@ -298,13 +518,307 @@ fn perform_swd_transfers<P: RawSwdIo>(
 /// Certain transfers require additional transfers to
 /// get the result. This is handled by this function.
 ///
+/// Transfers to banked DP registers get the `SELECT` writes needed to reach
+/// their bank, and the bank is restored at the end of the batch.
+///
 /// Retries on WAIT responses are automatically handled.
 ///
 /// Other errors are not handled, so the debug interface might be in an error state
 /// after this function returns.
+///
+/// `transfers` must not be empty; the block access methods return early for empty slices.
 fn perform_transfers<P: DebugProbe + RawSwdIo + JtagAccess>(
     probe: &mut P,
     transfers: &mut [DapTransfer],
+) -> Result<(), ArmError> {
+    debug_assert!(
+        !transfers.is_empty(),
+        "perform_transfers called without any transfers"
+    );
+
+    if probe.swd_settings().validate_ap_presence {
+        validate_ap_presence(probe, transfers)?;
+    }
+
+    let state = probe.raw_dap_state();
+    let (select_writes, select_after, select1_after) =
+        plan_dp_selects(state.dp_select, state.dp_select1, transfers);
+
+    let writes_select = !select_writes.is_empty()
+        || transfers
+            .iter()
+            .any(|t| t.is_select_write() || t.is_select1_write());
+
+    // If the batch fails halfway through, we don't know which values SELECT and SELECT1 ended up with.
+    if writes_select {
+        probe.raw_dap_state().dp_select = None;
+        probe.raw_dap_state().dp_select1 = None;
+    }
+
+    if select_writes.is_empty() {
+        perform_unbanked_transfers(probe, transfers)?;
+    } else {
+        let mut banked_transfers = Vec::with_capacity(transfers.len() + select_writes.len());
+        let mut original_indices = Vec::with_capacity(transfers.len());
+
+        let mut select_writes = select_writes.into_iter().peekable();
+
+        for (i, transfer) in transfers.iter().enumerate() {
+            while let Some((_, select)) = select_writes.next_if(|(index, _)| *index == i) {
+                banked_transfers.push(select);
+                probe.probe_statistics().record_extra_transfer();
+            }
+
+            original_indices.push(banked_transfers.len());
+            banked_transfers.push(transfer.clone());
+        }
+
+        // Restore the bank and AP address the caller expects.
+        for (_, select) in select_writes {
+            banked_transfers.push(select);
+            probe.probe_statistics().record_extra_transfer();
+        }
+
+        let result = perform_unbanked_transfers(probe, &mut banked_transfers);
+        count_cancelled_transfers(result, original_indices.iter().copied())?;
+
+        // A transfer following a failed bank switch accessed the wrong bank.
+        let mut select_status = TransferStatus::Ok;
+        let mut next_transfer = 0;
+        for (transfer, index) in transfers.iter_mut().zip(original_indices) {
+            for inserted in &banked_transfers[next_transfer..index] {
+                if inserted.status != TransferStatus::Ok {
+                    select_status = inserted.status;
+                }
+            }
+            next_transfer = index + 1;
+
+            let banked = &banked_transfers[index];
+            transfer.status = match banked.status {
+                TransferStatus::Ok => select_status,
+                status => status,
+            };
+            transfer.value = banked.value;
+        }
+    }
+
+    if !writes_select || transfers.iter().all(|t| t.status == TransferStatus::Ok) {
+        probe.raw_dap_state().dp_select = select_after;
+        probe.raw_dap_state().dp_select1 = select1_after;
+    }
+
+    if let Some(observer) = probe.raw_dap_state().transfer_observer.as_mut() {
+        for transfer in transfers.iter() {
+            observer.on_transfer(transfer, &transfer.status);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the APs accessed by `transfers` exist, by reading their IDR.
+///
+/// The AP of each transfer is taken from the `APSEL` field of `SELECT`, which assumes
+/// ADIv5 addressing. APs which were found before are not checked again. If the current
+/// value of `SELECT` is unknown, nothing is checked.
+fn validate_ap_presence<P: DebugProbe + RawSwdIo + JtagAccess>(
+    probe: &mut P,
+    transfers: &[DapTransfer],
+) -> Result<(), ArmError> {
+    let Some(original_select) = probe.raw_dap_state().dp_select else {
+        return Ok(());
+    };
+
+    let idr_bank = ((IDR::ADDRESS >> 4) & 0xf) as u8;
+    let idr_address = RegisterAddress::ApRegister(IDR::ADDRESS as u8);
+
+    let mut select = SelectV1(original_select);
+    let mut unchecked_aps = Vec::new();
+
+    for transfer in transfers {
+        if transfer.is_select_write() {
+            select = SelectV1(transfer.value);
+            continue;
+        }
+
+        // APs on ADIv6 are selected by their address, not by APSEL.
+        if !transfer.address.is_ap() || transfer.ap_v2_address.is_some() {
+            continue;
+        }
+
+        // Reading the IDR is how the presence of an AP is checked in the first place.
+        let is_idr_read = transfer.direction == TransferDirection::Read
+            && transfer.address.a2_and_3() == idr_address.a2_and_3()
+            && select.ap_bank_sel() == idr_bank;
+        if is_idr_read {
+            continue;
+        }
+
+        let ap = select.ap_sel();
+        if !probe.raw_dap_state().present_aps.contains(&ap) && !unchecked_aps.contains(&ap) {
+            unchecked_aps.push(ap);
+        }
+    }
+
+    for ap in unchecked_aps {
+        let mut idr_select = SelectV1(original_select);
+        idr_select.set_ap_sel(ap);
+        idr_select.set_ap_bank_sel(idr_bank);
+
+        let mut check = [
+            DapTransfer::write(SelectV1::ADDRESS, idr_select.0),
+            DapTransfer::read(idr_address),
+            DapTransfer::write(SelectV1::ADDRESS, original_select),
+        ];
+        for _ in 0..check.len() {
+            probe.probe_statistics().record_extra_transfer();
+        }
+
+        // None of the caller's transfers were performed yet.
+        count_cancelled_transfers(perform_unbanked_transfers(probe, &mut check), [])?;
+
+        for transfer in &check {
+            if let TransferStatus::Failed(err) = transfer.status {
+                return Err(err.into());
+            }
+        }
+
+        if check[1].value == 0 {
+            tracing::warn!("AP {} is not present, its IDR reads as zero", ap);
+            return Err(ArmError::ApDoesNotExist(
+                FullyQualifiedApAddress::v1_with_default_dp(ap),
+            ));
+        }
+
+        probe.raw_dap_state().present_aps.insert(ap);
+    }
+
+    Ok(())
+}
+
+/// Determine the `SELECT` and `SELECT1` writes needed to access the DP bank of each
+/// transfer, and the ADIv6 AP register of transfers created with [`DapTransfer::read_ap_v2`]
+/// or [`DapTransfer::write_ap_v2`].
+///
+/// Returns the index of the transfer each write has to be inserted before, together with
+/// the write, and the values `SELECT` and `SELECT1` have once the batch is done. Writes
+/// with an index past the last transfer restore the bank and AP address at the end of
+/// the batch.
+///
+/// If the current value of `SELECT` is unknown, no writes are inserted and the caller
+/// is responsible for selecting the right bank. The same goes for ADIv6 AP accesses if
+/// the value of `SELECT1` is unknown.
+fn plan_dp_selects(
+    mut select: Option<u32>,
+    mut select1: Option<u32>,
+    transfers: &[DapTransfer],
+) -> (Vec<(usize, DapTransfer)>, Option<u32>, Option<u32>) {
+    let mut select_writes = Vec::new();
+
+    // The values of SELECT and SELECT1 the caller expects after the batch.
+    let mut expected = select;
+    let mut expected1 = select1;
+
+    for (i, transfer) in transfers.iter().enumerate() {
+        if transfer.is_select_write() {
+            select = Some(transfer.value);
+            expected = select;
+            continue;
+        }
+
+        if let Some(address) = transfer.ap_v2_address {
+            plan_ap_v2_select(&mut select_writes, i, &mut select, &mut select1, address);
+            continue;
+        }
+
+        if let (Some(current), Some(bank)) = (select, transfer.dp_bank()) {
+            let mut current = SelectV1(current);
+            if current.dp_bank_sel() != bank {
+                current.set_dp_bank_sel(bank);
+                select_writes.push((i, DapTransfer::write(SelectV1::ADDRESS, current.0)));
+                select = Some(current.0);
+            }
+        }
+
+        if transfer.is_select1_write() {
+            select1 = Some(transfer.value);
+            expected1 = select1;
+        }
+    }
+
+    let end = transfers.len();
+
+    if let Some(restore) = expected1.filter(|&expected| select1 != Some(expected)) {
+        plan_select1_write(&mut select_writes, end, &mut select, restore);
+    }
+
+    if let Some(restore) = expected.filter(|&expected| select != Some(expected)) {
+        select_writes.push((end, DapTransfer::write(SelectV1::ADDRESS, restore)));
+    }
+
+    (select_writes, expected, expected1)
+}
+
+/// Add the writes selecting the ADIv6 AP register at `address` before the transfer at `index`.
+fn plan_ap_v2_select(
+    select_writes: &mut Vec<(usize, DapTransfer)>,
+    index: usize,
+    select: &mut Option<u32>,
+    select1: &mut Option<u32>,
+    address: u64,
+) {
+    let (Some(current), Some(current1)) = (*select, *select1) else {
+        return;
+    };
+
+    let upper = (address >> 32) as u32;
+
+    // Bits [3:2] of the address are part of the AP access itself.
+    let mut new_select = SelectV3(current);
+    new_select.set_addr(((address >> 4) & 0x0FFF_FFFF) as u32);
+
+    // Switch to the bank of SELECT1 with the same write, if it has to be written as well.
+    if current1 != upper {
+        new_select.set_dp_bank_sel(SELECT1_BANK);
+    }
+
+    if new_select.0 != current {
+        select_writes.push((index, DapTransfer::write(SelectV3::ADDRESS, new_select.0)));
+        *select = Some(new_select.0);
+    }
+
+    if current1 != upper {
+        plan_select1_write(select_writes, index, select, upper);
+        *select1 = Some(upper);
+    }
+}
+
+/// Add a write of `value` to `SELECT1` before the transfer at `index`, switching `SELECT`
+/// to the DP bank of `SELECT1` first.
+fn plan_select1_write(
+    select_writes: &mut Vec<(usize, DapTransfer)>,
+    index: usize,
+    select: &mut Option<u32>,
+    value: u32,
+) {
+    if let Some(current) = *select {
+        let mut current = SelectV3(current);
+        if current.dp_bank_sel() != SELECT1_BANK {
+            current.set_dp_bank_sel(SELECT1_BANK);
+            select_writes.push((index, DapTransfer::write(SelectV3::ADDRESS, current.0)));
+            *select = Some(current.0);
+        }
+    }
+
+    select_writes.push((index, DapTransfer::write(Select1::ADDRESS, value)));
+}
+
+/// Perform a batch of transfers, without switching DP banks.
+///
+/// See [`perform_transfers`] for details.
+fn perform_unbanked_transfers<P: DebugProbe + RawSwdIo + JtagAccess>(
+    probe: &mut P,
+    transfers: &mut [DapTransfer],
 ) -> Result<(), ArmError> {
     assert!(!transfers.is_empty());
 
@@ -360,15 +874,15 @@ fn perform_transfers<P: DebugProbe + RawSwdIo + JtagAccess>(
 
         // Now process the extra transfers needed
         let mut extra_idle_cycles = probe.swd_settings().idle_cycles_before_write_verify;
-        let mut need_extra = false;
+        let mut need_extra = None;
         if let Some(next) = transfers.get(i + 1) {
             // Check if we need to insert an additional read from the RDBUFF register
             if need_ap_read && !next.is_ap_read() {
-                need_extra = true;
+                need_extra = Some(RdBuffReason::ApRead);
                 extra_idle_cycles = 0;
             } else if buffered_write && next.must_not_stall() {
                 // We need an additional instruction to avoid losing buffered writes.
-                need_extra = true;
+                need_extra = Some(RdBuffReason::Stall);
             } else {
                 // No extra transfer needed
             }
@@ -380,24 +894,29 @@ fn perform_transfers<P: DebugProbe + RawSwdIo + JtagAccess>(
 
             // We need an additional instruction to avoid losing writes or returned values.
             if need_ap_read || write_response_pending {
-                need_extra = true;
+                need_extra = Some(RdBuffReason::FinalFlush);
             }
         };
 
-        if need_extra {
-            final_transfers.last_mut().unwrap().idle_cycles_after += extra_idle_cycles;
+        if let Some(reason) = need_extra {
+            final_transfers
+                .last_mut()
+                .unwrap()
+                .add_idle_cycles_after(extra_idle_cycles);
 
             // Add a read from RDBUFF, this access will be stalled by the DebugPort if the write
             // buffer is not empty.
             // This is an extra transfer, which doesn't have a reponse on it's own.
             final_transfers.push(DapTransfer::read(RdBuff::ADDRESS));
-            probe.probe_statistics().record_extra_transfer();
+            probe.probe_statistics().record_rdbuff_read(reason);
         }
     }
 
     // Add idle cycles at the end, to ensure transfer is performed
-    final_transfers.last_mut().unwrap().idle_cycles_after +=
-        probe.swd_settings().idle_cycles_after_transfer;
+    final_transfers
+        .last_mut()
+        .unwrap()
+        .add_idle_cycles_after(probe.swd_settings().idle_cycles_after_transfer);
 
     let num_transfers = final_transfers.len();
     tracing::debug!(
@@ -408,7 +927,13 @@ fn perform_transfers<P: DebugProbe + RawSwdIo + JtagAccess>(
 
     probe.probe_statistics().record_transfers(num_transfers);
 
-    perform_raw_transfers_retry(probe, &mut final_transfers)?;
+    let result = perform_raw_transfers_retry(probe, &mut final_transfers);
+    count_cancelled_transfers(
+        result,
+        result_indices
+            .iter()
+            .map(|orig| orig.index + orig.response_in_next as usize),
+    )?;
 
     // Retrieve the results
     for (transfer, orig) in transfers.iter_mut().zip(result_indices) {
@@ -430,6 +955,28 @@ fn perform_transfers<P: DebugProbe + RawSwdIo + JtagAccess>(
     Ok(())
 }
 
+/// Translate the number of completed transfers of a cancelled batch to the transfers
+/// it was built from, so that inserted `SELECT` and `RDBUFF` transfers are not counted.
+///
+/// `last_indices` contains the index of the last transfer in the batch each of the
+/// original transfers depends on, in order.
+fn count_cancelled_transfers(
+    result: Result<(), ArmError>,
+    last_indices: impl IntoIterator<Item = usize>,
+) -> Result<(), ArmError> {
+    match result {
+        Err(ArmError::Probe(DebugProbeError::Cancelled { completed })) => {
+            let completed = last_indices
+                .into_iter()
+                .take_while(|index| *index < completed)
+                .count();
+
+            Err(DebugProbeError::Cancelled { completed }.into())
+        }
+        result => result,
+    }
+}
+
 /// Perform a batch of raw transfers, retrying on WAIT responses.
 ///
 /// Other than that, the transfers are sent as-is. You might want to use `perform_transfers` instead, which
@@ -442,12 +989,18 @@ fn perform_raw_transfers_retry<P: DebugProbe + RawSwdIo + JtagAccess>(
     let mut idle_cycles = std::cmp::max(1, probe.swd_settings().num_idle_cycles_between_writes);
 
     let num_retries = probe.swd_settings().num_retries_after_wait;
+    let max_idle_cycles = probe.swd_settings().max_retry_idle_cycles_after_wait;
 
     'transfer: for _ in 0..num_retries {
         let chunk = &mut transfers[successful_transfers..];
         assert!(!chunk.is_empty());
 
-        perform_raw_transfers(probe, chunk)?;
+        perform_raw_transfers(probe, chunk).map_err(|error| match error {
+            DebugProbeError::Cancelled { completed } => DebugProbeError::Cancelled {
+                completed: successful_transfers + completed,
+            },
+            error => error,
+        })?;
 
         for transfer in chunk.iter() {
             match transfer.status {
@@ -463,13 +1016,18 @@ fn perform_raw_transfers_retry<P: DebugProbe + RawSwdIo + JtagAccess>(
                     // Increase idle cycles of the failed write transfer and the rest of the chunk
                     for transfer in &mut chunk[..] {
                         if transfer.is_write() {
-                            transfer.idle_cycles_after += idle_cycles;
+                            transfer.add_idle_cycles_after(idle_cycles);
                         }
                     }
-                    idle_cycles = std::cmp::min(
-                        probe.swd_settings().max_retry_idle_cycles_after_wait,
-                        2 * idle_cycles,
-                    );
+                    let next_idle_cycles =
+                        std::cmp::min(max_idle_cycles, idle_cycles.saturating_mul(2));
+                    if next_idle_cycles == max_idle_cycles && idle_cycles < max_idle_cycles {
+                        tracing::warn!(
+                            "WAIT backoff reached the maximum of {max_idle_cycles} idle cycles, the bus might be saturated."
+                        );
+                        probe.probe_statistics().report_wait_idle_ceiling();
+                    }
+                    idle_cycles = next_idle_cycles;
 
                     continue 'transfer;
                 }
@@ -492,9 +1050,10 @@ fn perform_raw_transfers_retry<P: DebugProbe + RawSwdIo + JtagAccess>(
     }
 
     // Timeout, abort transactions
-    tracing::debug!(
+    tracing::warn!(
         "Timeout in SWD transaction, aborting AP transactions after {num_retries} retries."
     );
+    probe.probe_statistics().report_wait_retries_exhausted();
     write_dp_register(probe, {
         let mut abort = Abort(0);
         abort.set_dapabort(true);
@@ -505,6 +1064,44 @@ fn perform_raw_transfers_retry<P: DebugProbe + RawSwdIo + JtagAccess>(
     Ok(())
 }
 
+/// Turn the fault flags in a CTRL/STAT value into a short, human readable explanation.
+fn describe_ctrl_fault(ctrl: &Ctrl) -> String {
+    let mut reasons = vec![];
+
+    if ctrl.sticky_orun() {
+        reasons.push("sticky overrun (transfers were issued too fast, try a lower speed)");
+    }
+    if ctrl.sticky_err() {
+        reasons.push("sticky error (an AP transaction failed)");
+    }
+    if ctrl.stick_cmp() {
+        reasons.push("sticky compare (pushed compare/verify mismatch)");
+    }
+    if ctrl.w_data_err() {
+        reasons.push("WDATAERR (write data error)");
+    }
+
+    if reasons.is_empty() {
+        format!("no fault flags set (CTRL/STAT = {:#010x})", ctrl.0)
+    } else {
+        reasons.join(", ")
+    }
+}
+
+/// Read the CTRL/STAT register to determine the reason for a FAULT response.
+///
+/// While the register is read, [`RawDapState::handling_fault`](crate::probe::RawDapState::handling_fault) is set, so that a fault of
+/// this read doesn't lead to reading the register again.
+fn read_ctrl_after_fault<P: DebugProbe + RawSwdIo + JtagAccess + 'static>(
+    probe: &mut P,
+) -> Result<Ctrl, ArmError> {
+    probe.raw_dap_state().handling_fault = true;
+    let response = RawDapAccess::raw_read_register(probe, CTRL_PORT);
+    probe.raw_dap_state().handling_fault = false;
+
+    Ok(Ctrl::try_from(response?)?)
+}
+
 fn clear_overrun_and_sticky_err<P: DebugProbe + RawSwdIo + JtagAccess>(
     probe: &mut P,
 ) -> Result<(), ArmError> {
@@ -518,17 +1115,60 @@ fn clear_overrun_and_sticky_err<P: DebugProbe + RawSwdIo + JtagAccess>(
     })
 }
 
-fn write_dp_register<P: DebugProbe + RawSwdIo + JtagAccess, R: DpRegister>(
+/// Clear what is left of a failed pushed compare or verify operation.
+///
+/// A mismatch sets STICKYCMP, and an aborted sequence leaves TRNCNT counting the
+/// remaining transactions. Either makes the following pushed operations fail, so both
+/// are cleared after a FAULT.
+fn clear_pushed_operation_state<P: DebugProbe + RawSwdIo + JtagAccess>(
     probe: &mut P,
-    register: R,
+    ctrl: &Ctrl,
 ) -> Result<(), ArmError> {
-    let mut transfer = DapTransfer::write(R::ADDRESS, register.into());
+    if ctrl.stick_cmp() {
+        tracing::debug!("Clearing sticky compare");
+        write_dp_register(probe, {
+            let mut abort = Abort(0);
+            abort.set_stkcmpclr(true);
+            abort
+        })?;
+    }
 
-    transfer.idle_cycles_after = probe.swd_settings().idle_cycles_before_write_verify
-        + probe.swd_settings().num_idle_cycles_between_writes;
+    if ctrl.trn_cnt() != 0 {
+        tracing::debug!("Clearing transaction counter {}", ctrl.trn_cnt());
+        write_dp_register(probe, {
+            let mut ctrl = Ctrl(ctrl.0);
+            ctrl.set_trn_cnt(0);
+            ctrl
+        })?;
+    }
 
-    // Do it
-    perform_raw_transfers(probe, std::slice::from_mut(&mut transfer))?;
+    Ok(())
+}
+
+fn clear_write_data_err<P: DebugProbe + RawSwdIo + JtagAccess>(
+    probe: &mut P,
+) -> Result<(), ArmError> {
+    tracing::debug!("Clearing write data error");
+    write_dp_register(probe, {
+        let mut abort = Abort(0);
+        abort.set_wderrclr(true);
+        abort
+    })
+}
+
+fn write_dp_register<P: DebugProbe + RawSwdIo + JtagAccess, R: DpRegister>(
+    probe: &mut P,
+    register: R,
+) -> Result<(), ArmError> {
+    let mut transfer = DapTransfer::write(R::ADDRESS, register.into());
+
+    transfer.idle_cycles_after = probe
+        .swd_settings()
+        .idle_cycles_before_write_verify
+        .saturating_add(probe.swd_settings().num_idle_cycles_between_writes);
+
+    // Do it
+    perform_raw_transfers(probe, std::slice::from_mut(&mut transfer))?;
 
     if let TransferStatus::Failed(e) = transfer.status {
         Err(e)?
@@ -551,36 +1191,101 @@ fn perform_raw_transfers<P: DebugProbe + RawSwdIo + JtagAccess>(
     }
 }
 
-#[derive(Debug, Clone)]
-struct DapTransfer {
+/// A single transfer to a DP or AP register.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DapTransfer {
     address: RegisterAddress,
     direction: TransferDirection,
     value: u32,
     status: TransferStatus,
     idle_cycles_after: usize,
+    /// The full address of an ADIv6 AP register, selected with `SELECT` and `SELECT1`.
+    ap_v2_address: Option<u64>,
 }
 
 impl DapTransfer {
-    fn read<P: Into<RegisterAddress>>(address: P) -> DapTransfer {
+    /// Create a read from the register at `address`.
+    pub fn read<P: Into<RegisterAddress>>(address: P) -> DapTransfer {
         Self {
             address: address.into(),
             direction: TransferDirection::Read,
             value: 0,
             status: TransferStatus::Pending,
             idle_cycles_after: 0,
+            ap_v2_address: None,
         }
     }
 
-    fn write<P: Into<RegisterAddress>>(address: P, value: u32) -> DapTransfer {
+    /// Create a write of `value` to the register at `address`.
+    pub fn write<P: Into<RegisterAddress>>(address: P, value: u32) -> DapTransfer {
         Self {
             address: address.into(),
             value,
             direction: TransferDirection::Write,
             status: TransferStatus::Pending,
             idle_cycles_after: 0,
+            ap_v2_address: None,
+        }
+    }
+
+    /// Create a read from the ADIv6 AP register at the full `address`.
+    ///
+    /// `SELECT` and `SELECT1` are written as needed before the read, and restored
+    /// at the end of the batch.
+    pub fn read_ap_v2(address: u64) -> DapTransfer {
+        Self {
+            ap_v2_address: Some(address),
+            ..Self::read(RegisterAddress::ApRegister(address as u8))
+        }
+    }
+
+    /// Create a write of `value` to the ADIv6 AP register at the full `address`.
+    ///
+    /// `SELECT` and `SELECT1` are written as needed before the write, and restored
+    /// at the end of the batch.
+    pub fn write_ap_v2(address: u64, value: u32) -> DapTransfer {
+        Self {
+            ap_v2_address: Some(address),
+            ..Self::write(RegisterAddress::ApRegister(address as u8), value)
         }
     }
 
+    /// Set the number of idle cycles inserted after this transfer.
+    pub fn with_idle_cycles_after(mut self, idle_cycles_after: usize) -> Self {
+        self.idle_cycles_after = idle_cycles_after;
+        self
+    }
+
+    /// The address of the accessed register.
+    pub fn address(&self) -> RegisterAddress {
+        self.address
+    }
+
+    /// Whether this transfer is a read or a write.
+    pub fn direction(&self) -> TransferDirection {
+        self.direction
+    }
+
+    /// The value written, or the value read once the transfer is done.
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    /// The result of this transfer.
+    pub fn status(&self) -> TransferStatus {
+        self.status
+    }
+
+    /// The number of idle cycles inserted after this transfer.
+    pub fn idle_cycles_after(&self) -> usize {
+        self.idle_cycles_after
+    }
+
+    /// Add idle cycles after this transfer, saturating instead of overflowing.
+    fn add_idle_cycles_after(&mut self, idle_cycles: usize) {
+        self.idle_cycles_after = self.idle_cycles_after.saturating_add(idle_cycles);
+    }
+
     fn transfer_type(&self) -> TransferType {
         match self.direction {
             TransferDirection::Read => TransferType::Read,
@@ -588,71 +1293,31 @@ impl DapTransfer {
         }
     }
 
-    fn io_sequence(&self) -> IoSequence {
+    /// Builds the SWD bit sequence for this transfer, followed by the idle cycles
+    /// with SWDIO driven to `idle_bit`.
+    fn io_sequence(&self, idle_bit: bool) -> IoSequence {
         let mut seq = build_swd_transfer(&self.address, self.transfer_type());
 
         seq.reserve(self.idle_cycles_after);
         for _ in 0..self.idle_cycles_after {
-            seq.add_output(false);
+            seq.add_output(idle_bit);
         }
 
         seq
     }
 
-    fn jtag_write(&self) -> JtagWriteCommand {
-        let (payload, address) = if self.is_abort() {
-            (JTAG_ABORT_VALUE, JTAG_ABORT_IR_VALUE)
-        } else {
-            let jtag_address = match self.address.is_ap() {
-                false => JTAG_DEBUG_PORT_IR_VALUE,
-                true => JTAG_ACCESS_PORT_IR_VALUE,
-            };
-            let port_address = self.address.a2_and_3();
-
-            let mut payload = 0u64;
-
-            // 32-bit value, bits 35:3
-            payload |= (self.value as u64) << 3;
-            // A[3:2], bits 2:1
-            payload |= (port_address as u64 & 0b1000) >> 1;
-            payload |= (port_address as u64 & 0b0100) >> 1;
-            // RnW, bit 0
-            payload |= u64::from(self.direction == TransferDirection::Read);
-
-            (payload, jtag_address)
-        };
+    pub(crate) fn jtag_write(&self, opcodes: &JtagIrOpcodes) -> JtagWriteCommand {
+        let (payload, address) = build_jtag_payload_and_address(self, opcodes);
 
         JtagWriteCommand {
             address,
             data: payload.to_le_bytes().to_vec(),
             len: JTAG_DR_BIT_LENGTH,
-            transform: |command, response| {
+            transform: if self.is_abort() {
                 // No responses returned for aborts.
-                if command.address == JTAG_ABORT_IR_VALUE {
-                    return Ok(CommandResult::None);
-                }
-
-                let received = parse_jtag_response(response);
-
-                // Received value is bits [35:3]
-                let received_value = (received >> 3) as u32;
-                // Status is bits [2:0]
-                let status = (received & 0b111) as u32;
-
-                let error = match status {
-                    s if s == JTAG_STATUS_OK => return Ok(CommandResult::U32(received_value)),
-                    s if s == JTAG_STATUS_WAIT => DapError::WaitResponse,
-                    _ => {
-                        tracing::debug!("Unexpected DAP response: {}", status);
-
-                        DapError::NoAcknowledge
-                    }
-                };
-
-                Err(Error::Arm(ArmError::AccessPort {
-                    address: FullyQualifiedApAddress::v1_with_default_dp(0), // Dummy value, unused
-                    source: AccessPortError::DebugPort(DebugPortError::Dap(error)),
-                }))
+                |_, _| Ok(CommandResult::None)
+            } else {
+                transform_jtag_response
             },
         }
     }
@@ -681,6 +1346,34 @@ impl DapTransfer {
             && self.direction == TransferDirection::Read
     }
 
+    fn is_select_write(&self) -> bool {
+        matches!(self.address, RegisterAddress::DpRegister(SelectV1::ADDRESS))
+            && self.direction == TransferDirection::Write
+    }
+
+    fn is_select1_write(&self) -> bool {
+        matches!(self.address, RegisterAddress::DpRegister(Select1::ADDRESS))
+            && self.direction == TransferDirection::Write
+    }
+
+    /// The DP bank this transfer has to be performed in, if the register is banked.
+    fn dp_bank(&self) -> Option<u8> {
+        // On ADIv5, only address 0x4 is banked, on ADIv6 address 0x0 is banked as well.
+        // Writes to address 0x0 always go to ABORT.
+        match self.address {
+            RegisterAddress::DpRegister(DpRegisterAddress { address: 0, .. })
+                if self.is_write() =>
+            {
+                None
+            }
+            RegisterAddress::DpRegister(DpRegisterAddress {
+                address: 0 | 4,
+                bank,
+            }) => Some(bank.unwrap_or(0)),
+            _ => None,
+        }
+    }
+
     fn swd_response_length(&self) -> usize {
         self.direction.swd_response_length() + self.idle_cycles_after
     }
@@ -701,9 +1394,12 @@ impl DapTransfer {
     }
 }
 
+/// The direction of a [`DapTransfer`].
 #[derive(Debug, PartialEq, Copy, Clone)]
-enum TransferDirection {
+pub enum TransferDirection {
+    /// Read from a register.
     Read,
+    /// Write to a register.
     Write,
 }
 
@@ -716,11 +1412,14 @@ impl TransferDirection {
     }
 }
 
+/// The result of a [`DapTransfer`].
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum TransferStatus {
+pub enum TransferStatus {
+    /// The transfer was not performed yet.
     Pending,
     /// OK/FAULT response
     Ok,
+    /// The transfer failed.
     Failed(DapError),
 }
 
@@ -734,6 +1433,20 @@ impl OutSequence {
         OutSequence { bits: vec![] }
     }
 
+    /// `cycles` idle cycles, with SWDIO/TMS low.
+    fn idle_cycles(cycles: usize) -> Self {
+        OutSequence {
+            bits: vec![false; cycles],
+        }
+    }
+
+    /// A line reset of `cycles` cycles, with SWDIO/TMS high.
+    fn line_reset(cycles: usize) -> Self {
+        OutSequence {
+            bits: vec![true; cycles],
+        }
+    }
+
     fn from_bytes(data: &[u8], mut bits: usize) -> Self {
         let mut this = Self::new();
 
@@ -800,6 +1513,10 @@ impl IoSequence {
         }
     }
 
+    fn len(&self) -> usize {
+        self.io.len()
+    }
+
     fn io_items(&self) -> impl Iterator<Item = IoSequenceItem> + '_ {
         self.io.iter().copied()
     }
@@ -827,6 +1544,20 @@ enum TransferType {
     Write(u32),
 }
 
+/// The parity bit of an SWD request, over the APnDP, RnW, A2 and A3 bits.
+///
+/// The parity is even, so the bit is set if an odd number of the other bits is set.
+pub fn swd_request_parity(ap: bool, read: bool, a2: bool, a3: bool) -> bool {
+    ap ^ read ^ a2 ^ a3
+}
+
+/// The parity bit following the 32 data bits of an SWD transfer.
+///
+/// The parity is even, so the bit is set if an odd number of data bits is set.
+pub fn swd_data_parity(value: u32) -> bool {
+    value.count_ones() % 2 == 1
+}
+
 fn build_swd_transfer(address: &RegisterAddress, direction: TransferType) -> IoSequence {
     // JLink operates on raw SWD bit sequences.
     // So we need to manually assemble the read and write bitsequences.
@@ -864,7 +1595,7 @@ fn build_swd_transfer(address: &RegisterAddress, direction: TransferType) -> IoS
     sequence.add_output(a3);
 
     // Odd parity bit over APnDP, RnW a2 and a3
-    sequence.add_output(ap_n_dp ^ direction_bit ^ a2 ^ a3);
+    sequence.add_output(swd_request_parity(ap_n_dp, direction_bit, a2, a3));
 
     // Stop bit (always 0).
     sequence.add_output(false);
@@ -888,7 +1619,7 @@ fn build_swd_transfer(address: &RegisterAddress, direction: TransferType) -> IoS
         }
 
         // Add the parity of the data bits.
-        sequence.add_output(value.count_ones() % 2 == 1);
+        sequence.add_output(swd_data_parity(value));
     } else {
         // Handle Read
         // Add the data bits to the SWDIO sequence.
@@ -905,7 +1636,13 @@ fn build_swd_transfer(address: &RegisterAddress, direction: TransferType) -> IoS
 }
 
 /// Parses acknowledgement and extracts the data from the response if the transfer is a Read.
-fn parse_swd_response(resp: &[bool], direction: TransferDirection) -> Result<u32, DapError> {
+///
+/// `resp` starts with the turnaround bit following the request.
+fn parse_swd_response(
+    resp: &[bool],
+    direction: TransferDirection,
+    sample_on_falling_edge: bool,
+) -> Result<u32, DapError> {
     // We need to discard the output bits that correspond to the part of the request
     // in which the probe is driving SWDIO. Additionally, there is a phase shift that
     // happens when ownership of the SWDIO line is transfered to the device.
@@ -915,6 +1652,14 @@ fn parse_swd_response(resp: &[bool], direction: TransferDirection) -> Result<u32
     // the clock. Therefore, the whole sequence seems to be leading by one bit,
     // which is why we don't discard the turnaround bit. It actually contains the
     // first ack bit.
+    //
+    // Probes sampling with the rising edge don't see this shift, so for them the
+    // turnaround bit is discarded.
+    let resp = if sample_on_falling_edge {
+        resp
+    } else {
+        &resp[1..]
+    };
 
     let (ack, response) = resp.split_at(3);
 
@@ -930,7 +1675,7 @@ fn parse_swd_response(resp: &[bool], direction: TransferDirection) -> Result<u32
             let value = bits_to_byte(response.iter().copied());
 
             // Make sure the parity is correct.
-            if value.count_ones() % 2 == response[32] as u32 {
+            if swd_data_parity(value) == response[32] {
                 tracing::trace!("DAP read {}.", value);
                 Ok(value)
             } else {
@@ -957,7 +1702,17 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
         perform_transfers(self, std::slice::from_mut(&mut transfer))?;
 
         match transfer.status {
-            TransferStatus::Ok => Ok(transfer.value),
+            TransferStatus::Ok => {
+                if address == RegisterAddress::DpRegister(DPIDR::ADDRESS)
+                    && is_valid_dpidr(transfer.value)
+                {
+                    let version = DPIDR(transfer.value).version().into();
+                    tracing::debug!("DP version is {}", version);
+                    self.raw_dap_state().dp_version = Some(version);
+                }
+
+                Ok(transfer.value)
+            }
             TransferStatus::Failed(DapError::FaultResponse) => {
                 tracing::debug!("DAP FAULT");
 
@@ -965,10 +1720,10 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
 
                 // To get a clue about the actual fault we want to read the ctrl register,
                 // which will have the fault status flags set. But we only do this
-                // if we are *not* currently reading the ctrl register, otherwise
-                // this could end up being an endless recursion.
+                // if we are *not* currently reading the ctrl register, or handling
+                // another fault, otherwise this could end up being an endless recursion.
 
-                if address == CTRL_PORT {
+                if address == CTRL_PORT || self.raw_dap_state().handling_fault {
                     //  This is not necessarily the CTRL/STAT register, because the dpbanksel field in the SELECT register
                     //  might be set so that the read wasn't actually from the CTRL/STAT register.
                     tracing::debug!(
@@ -988,10 +1743,17 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
                     // What might happen is that the read fails, but that would then trigger another fault handling,
                     // so it all ends up working.
                     tracing::debug!("Reading CTRL/AP register to determine reason for FAULT");
-                    let response = RawDapAccess::raw_read_register(self, CTRL_PORT)?;
-                    let ctrl = Ctrl::try_from(response)?;
+                    let ctrl = match read_ctrl_after_fault(self) {
+                        Ok(ctrl) => ctrl,
+                        Err(ArmError::Dap(DapError::FaultResponse)) => {
+                            tracing::debug!("Reading CTRL/AP register faulted as well");
+                            return Err(DapError::FaultResponse.into());
+                        }
+                        Err(e) => return Err(e),
+                    };
                     tracing::debug!(
-                        "Reading DAP register failed. Ctrl/Stat register value is: {:#?}",
+                        "Reading DAP register failed: {}. Ctrl/Stat register value is: {:#?}",
+                        describe_ctrl_fault(&ctrl),
                         ctrl
                     );
 
@@ -1001,6 +1763,8 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
                         // Clear the error state
                         clear_overrun_and_sticky_err(self)?;
                     }
+
+                    clear_pushed_operation_state(self, &ctrl)?;
                 }
 
                 Err(DapError::FaultResponse.into())
@@ -1019,6 +1783,10 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
         address: RegisterAddress,
         values: &mut [u32],
     ) -> Result<(), ArmError> {
+        if values.is_empty() {
+            return Ok(());
+        }
+
         let mut transfers = vec![DapTransfer::read(address); values.len()];
 
         perform_transfers(self, &mut transfers)?;
@@ -1066,16 +1834,20 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
                 // which will have the fault status flags set.
 
                 // This read might fail because the dpbanksel register is not set to 0.
-                let response = RawDapAccess::raw_read_register(self, CTRL_PORT)?;
-
-                let ctrl = Ctrl::try_from(response)?;
+                let ctrl = read_ctrl_after_fault(self)?;
                 tracing::warn!(
-                    "Writing DAP register failed. Ctrl/Stat register value is: {:#?}",
+                    "Writing DAP register failed: {}. Ctrl/Stat register value is: {:#?}",
+                    describe_ctrl_fault(&ctrl),
                     ctrl
                 );
 
                 // Check the reason for the fault
                 // Other fault reasons than overrun or write error are not handled yet.
+                if ctrl.w_data_err() {
+                    // The write data was corrupted on the wire, so the write was not performed.
+                    clear_write_data_err(self)?;
+                }
+
                 if ctrl.sticky_orun() || ctrl.sticky_err() {
                     // We did not handle a WAIT state properly
 
@@ -1083,6 +1855,21 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
                     clear_overrun_and_sticky_err(self)?;
                 }
 
+                clear_pushed_operation_state(self, &ctrl)?;
+
+                if ctrl.w_data_err() && self.swd_settings().retry_write_after_wdataerr {
+                    tracing::debug!("Retrying DAP register write after WDATAERR");
+
+                    let mut transfer = DapTransfer::write(address, value);
+                    perform_transfers(self, std::slice::from_mut(&mut transfer))?;
+
+                    if let TransferStatus::Failed(e) = transfer.status {
+                        return Err(e.into());
+                    }
+
+                    return Ok(());
+                }
+
                 Err(DapError::FaultResponse.into())
             }
             // The other errors mean that something went wrong with the protocol itself.
@@ -1099,6 +1886,10 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
         address: RegisterAddress,
         values: &[u32],
     ) -> Result<(), ArmError> {
+        if values.is_empty() {
+            return Ok(());
+        }
+
         let mut transfers = values
             .iter()
             .map(|v| DapTransfer::write(address, *v))
@@ -1162,16 +1953,281 @@ impl<Probe: DebugProbe + RawSwdIo + JtagAccess + 'static> RawDapAccess for Probe
         Ok(())
     }
 
-    fn swj_sequence(&mut self, bit_len: u8, bits: u64) -> Result<(), DebugProbeError> {
-        let protocol = self.active_protocol().unwrap();
+    fn tap_reset_sequence(&mut self) -> Result<(), DebugProbeError> {
+        self.shift_raw_sequence(JtagSequence {
+            tms: true,
+            data: bitvec![0; 6],
+            tdo_capture: false,
+        })?;
+
+        Ok(())
+    }
+
+    fn auto_select_protocol(&mut self, order: &[WireProtocol]) -> Result<WireProtocol, ArmError> {
+        for &protocol in order {
+            if let Err(e) = self.select_protocol(protocol) {
+                tracing::debug!("Probe can't use {}: {}", protocol, e);
+                continue;
+            }
+
+            // Line reset, then the SWJ-DP switch sequence from the other protocol.
+            send_swj_sequence(self, &OutSequence::line_reset(LINE_RESET_CYCLES))?;
+            // A missing acknowledge is retried like when connecting to a debug port, but
+            // without the guard time, only the configured number of line resets is tried.
+            let mut attempts = match protocol {
+                WireProtocol::Swd => {
+                    self.swj_sequence(16, 0xE79E)?;
+                    Some(SwdConnectAttempts::new(
+                        self.swd_settings().line_reset_retries,
+                    ))
+                }
+                WireProtocol::Jtag => {
+                    self.swj_sequence(16, 0xE73C)?;
+                    self.tap_reset_sequence()?;
+                    // Enter Run-Test/Idle
+                    self.jtag_sequence(1, false, 0)?;
+                    None
+                }
+            };
+
+            loop {
+                if attempts.is_some() {
+                    // Line reset, followed by the idle cycles before the first request.
+                    send_swj_sequence(self, &OutSequence::line_reset(LINE_RESET_CYCLES))?;
+                    send_swj_sequence(
+                        self,
+                        &OutSequence::idle_cycles(IDLE_CYCLES_AFTER_LINE_RESET),
+                    )?;
+                }
+
+                let dpidr = self.raw_read_register(RegisterAddress::DpRegister(DPIDR::ADDRESS));
+                let retry = match dpidr {
+                    Ok(dpidr) if is_valid_dpidr(dpidr) => {
+                        tracing::debug!("Found DPIDR {:#010x} using {}", dpidr, protocol);
+                        return Ok(protocol);
+                    }
+                    Ok(dpidr) => {
+                        tracing::debug!("Invalid DPIDR {:#010x} using {}", dpidr, protocol);
+                        attempts
+                            .as_mut()
+                            .is_some_and(|attempts| attempts.retry_line_reset(true))
+                    }
+                    Err(e) => {
+                        tracing::debug!("Failed to read DPIDR using {}: {}", protocol, e);
+                        attempts
+                            .as_mut()
+                            .is_some_and(|attempts| attempts.retry(&e, true))
+                    }
+                };
 
+                if !retry {
+                    break;
+                }
+            }
+        }
+
+        Err(DebugProbeError::TargetNotFound.into())
+    }
+
+    fn configure_retries(&mut self, config: RetryConfig) -> Result<(), ArmError> {
+        let settings = self.swd_settings_mut();
+        settings.num_retries_after_wait = config.num_retries_after_wait;
+        settings.max_retry_idle_cycles_after_wait = config.max_retry_idle_cycles_after_wait;
+
+        Ok(())
+    }
+
+    fn dap_config(&mut self) -> Result<DapConfig, ArmError> {
+        let settings = self.swd_settings();
+
+        Ok(DapConfig {
+            idle_cycle_swdio: settings.idle_cycle_swdio,
+            verify_jtag_ctrl: settings.verify_jtag_ctrl,
+            validate_ap_presence: settings.validate_ap_presence,
+            parking_idle_cycles: settings.parking_idle_cycles,
+            flag_suspicious_reads: settings.flag_suspicious_reads,
+            preclear_jtag_sticky_errors: settings.preclear_jtag_sticky_errors,
+            leading_idle_cycles: settings.leading_idle_cycles,
+            line_reset_retries: settings.line_reset_retries,
+            jtag_ir_opcodes: settings.jtag_ir_opcodes,
+            retry_write_after_wdataerr: settings.retry_write_after_wdataerr,
+            sample_on_falling_edge: settings.sample_on_falling_edge,
+        })
+    }
+
+    fn configure_dap(&mut self, config: DapConfig) -> Result<(), ArmError> {
+        let settings = self.swd_settings_mut();
+        settings.idle_cycle_swdio = config.idle_cycle_swdio;
+        settings.verify_jtag_ctrl = config.verify_jtag_ctrl;
+        settings.validate_ap_presence = config.validate_ap_presence;
+        settings.parking_idle_cycles = config.parking_idle_cycles;
+        settings.flag_suspicious_reads = config.flag_suspicious_reads;
+        settings.preclear_jtag_sticky_errors = config.preclear_jtag_sticky_errors;
+        settings.leading_idle_cycles = config.leading_idle_cycles;
+        settings.line_reset_retries = config.line_reset_retries;
+        settings.jtag_ir_opcodes = config.jtag_ir_opcodes;
+        settings.retry_write_after_wdataerr = config.retry_write_after_wdataerr;
+        settings.sample_on_falling_edge = config.sample_on_falling_edge;
+
+        Ok(())
+    }
+
+    fn dp_version(&mut self) -> Result<Option<DebugPortVersion>, ArmError> {
+        Ok(self.raw_dap_state().dp_version)
+    }
+
+    fn take_statistics(&mut self) -> Result<ProbeStatistics, ArmError> {
+        Ok(self.probe_statistics().take())
+    }
+
+    fn replay_transfers(
+        &mut self,
+        transfers: &[DapTransfer],
+    ) -> Result<Vec<DapTransfer>, ArmError> {
+        let mut transfers = transfers.to_vec();
+        if transfers.is_empty() {
+            return Ok(transfers);
+        }
+
+        for transfer in transfers.iter_mut() {
+            transfer.status = TransferStatus::Pending;
+        }
+
+        // The replayed transfers can select any DP bank.
+        if transfers.iter().any(DapTransfer::is_select_write) {
+            self.raw_dap_state().dp_select = None;
+        }
+        if transfers.iter().any(DapTransfer::is_select1_write) {
+            self.raw_dap_state().dp_select1 = None;
+        }
+
+        perform_raw_transfers_retry(self, &mut transfers)?;
+
+        Ok(transfers)
+    }
+
+    fn jtag_scan_ir(&mut self, ir_value: u64, ir_len: u8) -> Result<BitVec, DebugProbeError> {
+        if ir_len == 0 || ir_len > 64 {
+            return Err(DebugProbeError::Other(format!(
+                "Invalid IR length {ir_len}, must be between 1 and 64"
+            )));
+        }
+
+        let ir_bits = &ir_value.view_bits::<Lsb0>()[..ir_len as usize];
+        let (ir_head, ir_last) = ir_bits.split_at(ir_len as usize - 1);
+
+        let mut captured = BitVec::with_capacity(ir_len as usize);
+
+        // Run-Test/Idle -> Select-DR-Scan -> Select-IR-Scan
+        self.shift_raw_sequence(JtagSequence {
+            tms: true,
+            data: bitvec![0; 2],
+            tdo_capture: false,
+        })?;
+
+        // -> Capture-IR -> Shift-IR
+        self.shift_raw_sequence(JtagSequence {
+            tms: false,
+            data: bitvec![0; 2],
+            tdo_capture: false,
+        })?;
+
+        if !ir_head.is_empty() {
+            captured.extend_from_bitslice(&self.shift_raw_sequence(JtagSequence {
+                tms: false,
+                data: ir_head.iter().by_vals().collect(),
+                tdo_capture: true,
+            })?);
+        }
+
+        // The last bit is shifted while moving to Exit1-IR
+        captured.extend_from_bitslice(&self.shift_raw_sequence(JtagSequence {
+            tms: true,
+            data: ir_last.iter().by_vals().collect(),
+            tdo_capture: true,
+        })?);
+
+        // -> Update-IR
+        self.shift_raw_sequence(JtagSequence {
+            tms: true,
+            data: bitvec![0; 1],
+            tdo_capture: false,
+        })?;
+
+        // -> Run-Test/Idle
+        self.shift_raw_sequence(JtagSequence {
+            tms: false,
+            data: bitvec![0; 1],
+            tdo_capture: false,
+        })?;
+
+        Ok(captured)
+    }
+
+    fn swj_sequence(&mut self, bit_len: u8, bits: u64) -> Result<(), DebugProbeError> {
         let io_sequence = OutSequence::from_bytes(&bits.to_le_bytes(), bit_len as usize);
-        send_sequence(self, protocol, &io_sequence)
+        send_swj_sequence(self, &io_sequence)
     }
 
     fn core_status_notification(&mut self, _: crate::CoreStatus) -> Result<(), DebugProbeError> {
         Ok(())
     }
+
+    fn set_transfer_progress_callback(
+        &mut self,
+        callback: Option<TransferProgressCallback>,
+    ) -> Result<(), DebugProbeError> {
+        self.raw_dap_state().transfer_progress = callback;
+        Ok(())
+    }
+
+    fn set_cancel_flag(&mut self, flag: Option<Arc<AtomicBool>>) -> Result<(), DebugProbeError> {
+        self.raw_dap_state().cancel = flag;
+        Ok(())
+    }
+
+    fn set_transfer_observer(
+        &mut self,
+        observer: Option<Box<dyn TransferObserver>>,
+    ) -> Result<(), DebugProbeError> {
+        self.raw_dap_state().transfer_observer = observer;
+        Ok(())
+    }
+
+    fn raw_jtag_dr_scan(&mut self, transfer: &DapTransfer) -> Result<JtagDrResponse, ArmError> {
+        let protocol = self.active_protocol();
+        if protocol != Some(WireProtocol::Jtag) {
+            return Err(ArmError::Probe(DebugProbeError::UnsupportedProtocol(
+                protocol.unwrap_or(WireProtocol::Swd),
+            )));
+        }
+
+        let response = JtagDrResponse::from_raw(perform_jtag_scan(self, transfer)?);
+        tracing::debug!("Raw JTAG DR response: {:#011x}", response.raw);
+
+        Ok(response)
+    }
+}
+
+/// Bit 0 of DPIDR reads as one, and a missing target reads as all ones.
+fn is_valid_dpidr(dpidr: u32) -> bool {
+    dpidr & 1 == 1 && dpidr != u32::MAX
+}
+
+/// Send `sequence` like [`RawDapAccess::swj_sequence`].
+fn send_swj_sequence<P: DebugProbe + RawSwdIo + JtagAccess>(
+    probe: &mut P,
+    sequence: &OutSequence,
+) -> Result<(), DebugProbeError> {
+    let protocol = probe.active_protocol().unwrap();
+
+    // A line reset can be followed by a TARGETSEL, selecting a DP with a different SELECT value.
+    probe.raw_dap_state().dp_select = None;
+    probe.raw_dap_state().dp_select1 = None;
+    probe.raw_dap_state().dp_version = None;
+    probe.raw_dap_state().present_aps.clear();
+
+    send_sequence(probe, protocol, sequence)
 }
 
 fn send_sequence<P: RawSwdIo + JtagAccess>(
@@ -1213,25 +2269,27 @@ fn send_sequence<P: RawSwdIo + JtagAccess>(
 mod test {
     use crate::{
         architecture::arm::{
-            ApAddress, RawDapAccess, RegisterAddress,
-            dp::{Ctrl, DpRegister, RdBuff},
+            ApAddress, ArmError, DapError, RawDapAccess, RegisterAddress,
+            ap::{ApClass, ApType, BaseAddrFormat},
+            dp::{Abort, Ctrl, DPIDR, DebugPortVersion, DpRegister, RdBuff},
         },
         error::Error,
         probe::{
-            DebugProbe, DebugProbeError, IoSequenceItem, JtagAccess, JtagSequence, ProbeStatistics,
-            RawSwdIo, SwdSettings, WireProtocol,
+            DebugProbe, DebugProbeError, IoSequenceItem, JtagAccess, JtagIrOpcodes, JtagSequence,
+            ProbeStatistics, RawDapState, RawSwdIo, SwdSettings, WireProtocol,
         },
     };
     use probe_rs_target::ScanChainElement;
+    use std::time::Duration;
 
     use super::{
-        JTAG_ABORT_IR_VALUE, JTAG_ACCESS_PORT_IR_VALUE, JTAG_DEBUG_PORT_IR_VALUE,
-        JTAG_DR_BIT_LENGTH, JTAG_STATUS_OK, JTAG_STATUS_WAIT,
+        DapTransfer, JTAG_DR_BIT_LENGTH, JTAG_STATUS_OK, JTAG_STATUS_WAIT, OutSequence,
+        TransferDirection, TransferStatus, describe_ctrl_fault, parse_swd_response,
+        swd_data_parity, swd_request_parity,
     };
 
     use bitvec::prelude::*;
 
-    #[expect(dead_code)]
     enum DapAcknowledge {
         Ok,
         Wait,
@@ -1253,12 +2311,17 @@ mod test {
         io_input: Option<Vec<IoSequenceItem>>,
         transfer_responses: Vec<Vec<bool>>,
         jtag_transactions: Vec<ExpectedJtagTransaction>,
+        /// TMS value and length of each raw JTAG sequence
+        jtag_sequences: Vec<(bool, usize)>,
 
         expected_transfer_count: usize,
         performed_transfer_count: usize,
 
         swd_settings: SwdSettings,
         probe_statistics: ProbeStatistics,
+        raw_dap_state: RawDapState,
+        /// Delay of each call to `swd_io`
+        io_delay: Duration,
 
         protocol: WireProtocol,
 
@@ -1271,12 +2334,15 @@ mod test {
                 io_input: None,
                 transfer_responses: vec![vec![]],
                 jtag_transactions: vec![],
+                jtag_sequences: vec![],
 
                 expected_transfer_count: 1,
                 performed_transfer_count: 0,
 
-                swd_settings: SwdSettings::default(),
+                swd_settings: Self::default_swd_settings(),
                 probe_statistics: ProbeStatistics::default(),
+                raw_dap_state: RawDapState::default(),
+                io_delay: Duration::ZERO,
 
                 protocol: WireProtocol::Swd,
 
@@ -1322,7 +2388,7 @@ mod test {
 
         fn add_jtag_abort(&mut self) {
             let expected = ExpectedJtagTransaction {
-                ir_address: JTAG_ABORT_IR_VALUE,
+                ir_address: self.swd_settings.jtag_ir_opcodes.abort,
                 address: 0,
                 value: 0,
                 read: false,
@@ -1355,9 +2421,9 @@ mod test {
 
             let expected = ExpectedJtagTransaction {
                 ir_address: if matches!(port, RegisterAddress::DpRegister(_)) {
-                    JTAG_DEBUG_PORT_IR_VALUE
+                    self.swd_settings.jtag_ir_opcodes.dpacc
                 } else {
-                    JTAG_ACCESS_PORT_IR_VALUE
+                    self.swd_settings.jtag_ir_opcodes.apacc
                 },
                 address,
                 value: input_value,
@@ -1425,8 +2491,14 @@ mod test {
     }
 
     impl JtagAccess for MockJaylink {
-        fn shift_raw_sequence(&mut self, _: JtagSequence) -> Result<BitVec, DebugProbeError> {
-            todo!()
+        fn shift_raw_sequence(
+            &mut self,
+            sequence: JtagSequence,
+        ) -> Result<BitVec, DebugProbeError> {
+            let len = sequence.data.len();
+            self.jtag_sequences.push((sequence.tms, len));
+
+            Ok(bitvec![0; len])
         }
 
         fn set_scan_chain(&mut self, _: &[ScanChainElement]) -> Result<(), DebugProbeError> {
@@ -1445,9 +2517,9 @@ mod test {
             todo!()
         }
 
-        fn set_idle_cycles(&mut self, idle_cycles: u8) -> Result<(), DebugProbeError> {
-            self.idle_cycles = idle_cycles;
-            Ok(())
+        fn set_idle_cycles(&mut self, idle_cycles: usize) -> Result<u8, DebugProbeError> {
+            self.idle_cycles = idle_cycles.min(u8::MAX as usize) as u8;
+            Ok(self.idle_cycles)
         }
 
         fn idle_cycles(&self) -> u8 {
@@ -1474,7 +2546,7 @@ mod test {
                 self.jtag_transactions.len()
             );
 
-            if jtag_transaction.ir_address != JTAG_ABORT_IR_VALUE {
+            if jtag_transaction.ir_address != self.swd_settings.jtag_ir_opcodes.abort {
                 let value = (jtag_value >> 3) as u32;
                 let rnw = jtag_value & 1 == 1;
                 let dap_address = ((jtag_value & 0x6) << 1) as u32;
@@ -1506,6 +2578,8 @@ mod test {
         {
             self.io_input = Some(swdio.into_iter().collect());
 
+            std::thread::sleep(self.io_delay);
+
             let transfer_response = self.transfer_responses.remove(0);
 
             let io_bits = self.io_input.as_ref().map(|v| v.len()).unwrap();
@@ -1539,9 +2613,17 @@ mod test {
             &self.swd_settings
         }
 
+        fn swd_settings_mut(&mut self) -> &mut SwdSettings {
+            &mut self.swd_settings
+        }
+
         fn probe_statistics(&mut self) -> &mut ProbeStatistics {
             &mut self.probe_statistics
         }
+
+        fn raw_dap_state(&mut self) -> &mut RawDapState {
+            &mut self.raw_dap_state
+        }
     }
 
     /// This is just a blanket impl that will crash if used (only relevant in tests,
@@ -1595,53 +2677,379 @@ mod test {
     }
 
     #[test]
-    fn read_register() {
-        let read_value = 12;
-
+    fn auto_select_protocol_falls_back_to_jtag() {
         let mut mock = MockJaylink::new();
 
+        // SWD switch sequence
+        mock.add_idle_cycles(51);
+        mock.add_transfer();
+        mock.add_idle_cycles(16);
+        mock.add_transfer();
+        mock.add_idle_cycles(51);
+        mock.add_transfer();
+        mock.add_idle_cycles(2);
+
+        // SWD DPIDR read returns garbage
+        mock.add_transfer();
         mock.add_read_response(DapAcknowledge::Ok, 0);
-        mock.add_read_response(DapAcknowledge::Ok, read_value);
         mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
 
-        let result = mock.raw_read_register(ApAddress::V1(4).into()).unwrap();
-
-        assert_eq!(result, read_value);
-    }
-
-    #[test]
-    fn read_register_jtag() {
-        let read_value = 12;
-
-        let mut mock = MockJaylink::new();
-
-        let result = mock.select_protocol(WireProtocol::Jtag);
-        assert!(result.is_ok());
-
-        // Read request
-        mock.add_jtag_response(ApAddress::V1(4), true, DapAcknowledge::Ok, 0, 0);
-        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, read_value, 0);
+        // JTAG DPIDR read
+        mock.add_jtag_response(DPIDR::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x2ba0_1477, 0);
         // Check CTRL
         mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
         mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
 
-        let result = mock.raw_read_register(ApAddress::V1(4).into()).unwrap();
+        let protocol = mock
+            .auto_select_protocol(&[WireProtocol::Swd, WireProtocol::Jtag])
+            .unwrap();
 
-        assert_eq!(result, read_value);
+        assert_eq!(protocol, WireProtocol::Jtag);
+        assert_eq!(mock.active_protocol(), Some(WireProtocol::Jtag));
+        assert!(mock.jtag_transactions.is_empty());
     }
 
     #[test]
-    fn read_register_with_wait_response() {
-        let read_value = 47;
+    fn auto_select_protocol_retries_line_reset() {
         let mut mock = MockJaylink::new();
+        mock.swd_settings.line_reset_retries = 3;
 
-        mock.add_read_response(DapAcknowledge::Ok, 0);
-        mock.add_read_response(DapAcknowledge::Wait, 0);
-        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+        // SWD switch sequence
+        mock.add_idle_cycles(51);
+        mock.add_transfer();
+        mock.add_idle_cycles(16);
 
-        //  When a wait response is received, the sticky overrun bit has to be cleared
+        // The first two DPIDR reads after the line reset fail
+        for _ in 0..2 {
+            mock.add_transfer();
+            mock.add_idle_cycles(51);
+            mock.add_transfer();
+            mock.add_idle_cycles(2);
 
-        mock.add_transfer();
+            mock.add_transfer();
+            mock.add_read_response(DapAcknowledge::NoAck, 0);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+        }
+
+        mock.add_transfer();
+        mock.add_idle_cycles(51);
+        mock.add_transfer();
+        mock.add_idle_cycles(2);
+
+        mock.add_transfer();
+        mock.add_read_response(DapAcknowledge::Ok, 0x2ba0_1477);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        let protocol = mock.auto_select_protocol(&[WireProtocol::Swd]).unwrap();
+        assert_eq!(protocol, WireProtocol::Swd);
+
+        // Each line reset after the switch sequence was followed by idle cycles and a read.
+        assert!(mock.transfer_responses.is_empty());
+        assert_eq!(mock.performed_transfer_count, 2 + 3 * 3);
+    }
+
+    #[test]
+    fn auto_select_protocol_retries_noack() {
+        let mut mock = MockJaylink::new();
+        assert_eq!(mock.swd_settings.line_reset_retries, 1);
+
+        // SWD switch sequence
+        mock.add_idle_cycles(51);
+        mock.add_transfer();
+        mock.add_idle_cycles(16);
+
+        // The first DPIDR read isn't acknowledged, the second one succeeds.
+        for (ack, dpidr) in [
+            (DapAcknowledge::NoAck, 0),
+            (DapAcknowledge::Ok, 0x2ba0_1477),
+        ] {
+            mock.add_transfer();
+            mock.add_idle_cycles(51);
+            mock.add_transfer();
+            mock.add_idle_cycles(2);
+
+            mock.add_transfer();
+            mock.add_read_response(ack, dpidr);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+        }
+
+        let protocol = mock.auto_select_protocol(&[WireProtocol::Swd]).unwrap();
+        assert_eq!(protocol, WireProtocol::Swd);
+
+        assert!(mock.transfer_responses.is_empty());
+        assert_eq!(mock.performed_transfer_count, 2 + 2 * 3);
+    }
+
+    #[test]
+    fn noack_is_an_error_after_connecting() {
+        let mut mock = MockJaylink::new();
+
+        mock.add_read_response(DapAcknowledge::NoAck, 0);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        let result = mock.raw_read_register(RegisterAddress::DpRegister(DPIDR::ADDRESS));
+
+        assert!(matches!(
+            result,
+            Err(ArmError::Dap(DapError::NoAcknowledge))
+        ));
+        assert_eq!(mock.performed_transfer_count, 1);
+    }
+
+    #[test]
+    fn tap_reset_sequence() {
+        let mut mock = MockJaylink::new();
+        mock.select_protocol(WireProtocol::Jtag).unwrap();
+
+        mock.tap_reset_sequence().unwrap();
+
+        let [(tms, len)] = mock.jtag_sequences[..] else {
+            panic!("Expected a single sequence: {:?}", mock.jtag_sequences);
+        };
+        assert!(tms);
+        assert!(len >= 5);
+    }
+
+    #[test]
+    fn out_sequence_builders() {
+        assert!(OutSequence::idle_cycles(0).bits().is_empty());
+        assert_eq!(OutSequence::idle_cycles(2).bits(), [false; 2]);
+        assert_eq!(OutSequence::idle_cycles(8).bits(), [false; 8]);
+
+        assert_eq!(OutSequence::line_reset(1).bits(), [true]);
+        assert_eq!(OutSequence::line_reset(51).bits(), [true; 51]);
+
+        // The same line reset as sent with `swj_sequence`
+        assert_eq!(
+            OutSequence::line_reset(51).bits(),
+            OutSequence::from_bytes(&0x0007_FFFF_FFFF_FFFFu64.to_le_bytes(), 51).bits()
+        );
+    }
+
+    #[test]
+    fn jtag_scan_ir_tms_sequence() {
+        let mut mock = MockJaylink::new();
+        mock.select_protocol(WireProtocol::Jtag).unwrap();
+
+        let captured = mock.jtag_scan_ir(0b1010, 4).unwrap();
+        assert_eq!(captured.len(), 4);
+
+        assert_eq!(
+            mock.jtag_sequences,
+            [
+                // Enter Shift-IR
+                (true, 2),
+                (false, 2),
+                // IR bits, the last one exits to Exit1-IR
+                (false, 3),
+                (true, 1),
+                // Update-IR and back to Run-Test/Idle
+                (true, 1),
+                (false, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn measure_rtt() {
+        let mut mock = MockJaylink::new();
+        mock.io_delay = Duration::from_millis(5);
+
+        for i in 0..5 {
+            if i > 0 {
+                mock.add_transfer();
+            }
+            mock.add_read_response(DapAcknowledge::Ok, 0x2ba0_1477);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+        }
+
+        let rtt = mock.measure_rtt().unwrap();
+
+        assert!(rtt >= Duration::from_millis(5), "{rtt:?}");
+        assert!(rtt < Duration::from_millis(500), "{rtt:?}");
+    }
+
+    #[test]
+    fn replay_transfers() {
+        let mut mock = MockJaylink::new();
+
+        let recorded = [
+            DapTransfer::read(DPIDR::ADDRESS).with_idle_cycles_after(3),
+            DapTransfer::write(Abort::ADDRESS, 0x1e).with_idle_cycles_after(5),
+        ];
+
+        // The transfers are sent as-is, without any additional transfers.
+        for i in 0..2 {
+            if i > 0 {
+                mock.add_transfer();
+            }
+            mock.add_read_response(DapAcknowledge::Ok, 0x2ba0_1477);
+            mock.add_idle_cycles(3);
+            mock.add_write_response(DapAcknowledge::Ok, 5);
+        }
+
+        let first = mock.replay_transfers(&recorded).unwrap();
+        let second = mock.replay_transfers(&recorded).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(first[0].value(), 0x2ba0_1477);
+        assert_eq!(first[0].direction(), TransferDirection::Read);
+        assert_eq!(first[1].idle_cycles_after(), 5);
+        assert!(first.iter().all(|t| t.status() == TransferStatus::Ok));
+    }
+
+    #[test]
+    fn ctrl_fault_description() {
+        // STICKYORUN | STICKYERR
+        let ctrl = Ctrl(0b10_0010);
+        assert_eq!(
+            describe_ctrl_fault(&ctrl),
+            "sticky overrun (transfers were issued too fast, try a lower speed), sticky error (an AP transaction failed)"
+        );
+
+        // WDATAERR
+        let ctrl = Ctrl(1 << 7);
+        assert_eq!(describe_ctrl_fault(&ctrl), "WDATAERR (write data error)");
+
+        let ctrl = Ctrl(0xf000_0000);
+        assert_eq!(
+            describe_ctrl_fault(&ctrl),
+            "no fault flags set (CTRL/STAT = 0xf0000000)"
+        );
+    }
+
+    #[test]
+    fn read_register() {
+        let read_value = 12;
+
+        let mut mock = MockJaylink::new();
+
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        mock.add_read_response(DapAcknowledge::Ok, read_value);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        let result = mock.raw_read_register(ApAddress::V1(4).into()).unwrap();
+
+        assert_eq!(result, read_value);
+    }
+
+    #[test]
+    fn statistics_are_taken() {
+        let mut mock = MockJaylink::new();
+
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        mock.add_read_response(DapAcknowledge::Ok, 12);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        mock.raw_read_register(ApAddress::V1(4).into()).unwrap();
+
+        let statistics = mock.take_statistics().unwrap();
+        assert_eq!(statistics.num_transfers, 2);
+        assert_eq!(statistics.num_extra_transfers, 1);
+        assert_eq!(statistics.num_final_flush, 1);
+        assert_eq!(statistics.num_ap_read_rdbuff, 0);
+
+        // The counters start over.
+        assert_eq!(mock.take_statistics().unwrap().num_transfers, 0);
+    }
+
+    #[test]
+    fn read_dpidr_decodes_dp_version() {
+        // DPIDR of the RP2040, which implements DPv2
+        let dpidr = 0x0bc1_2477;
+
+        let mut mock = MockJaylink::new();
+        assert_eq!(mock.dp_version().unwrap(), None);
+
+        mock.add_read_response(DapAcknowledge::Ok, dpidr);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        let result = mock
+            .raw_read_register(RegisterAddress::DpRegister(DPIDR::ADDRESS))
+            .unwrap();
+
+        assert_eq!(result, dpidr);
+        assert_eq!(mock.dp_version().unwrap(), Some(DebugPortVersion::DPv2));
+    }
+
+    #[test]
+    fn read_ap_idr_and_base() {
+        // AHB-AP designed by ARM, with a ROM table at 0xE00F_F000
+        let idr = 0x2477_0011;
+        let base = 0xe00f_f003;
+
+        let mut mock = MockJaylink::new();
+
+        for (i, value) in [idr, base].into_iter().enumerate() {
+            if i > 0 {
+                mock.add_transfer();
+            }
+
+            // SELECT write
+            mock.add_write_response(
+                DapAcknowledge::Ok,
+                mock.swd_settings.num_idle_cycles_between_writes,
+            );
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            // AP read
+            mock.add_transfer();
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_read_response(DapAcknowledge::Ok, value);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+        }
+
+        let idr = mock.read_ap_idr(2).unwrap();
+        assert_eq!(idr.CLASS, ApClass::MemAp);
+        assert_eq!(idr.TYPE, ApType::AmbaAhb3);
+        assert_eq!(idr.DESIGNER, jep106::JEP106Code::new(4, 0x3b));
+        assert_eq!(idr.REVISION, 2);
+
+        let base = mock.read_ap_base(2).unwrap();
+        assert!(base.present);
+        assert_eq!(base.Format, BaseAddrFormat::ADIv5);
+        assert_eq!(base.BASEADDR, 0xe00ff);
+
+        // APSEL 2, bank 0xF
+        assert_eq!(mock.raw_dap_state.dp_select, Some(0x0200_00f0));
+    }
+
+    #[test]
+    fn read_register_jtag() {
+        let read_value = 12;
+
+        let mut mock = MockJaylink::new();
+
+        let result = mock.select_protocol(WireProtocol::Jtag);
+        assert!(result.is_ok());
+
+        // Read request
+        mock.add_jtag_response(ApAddress::V1(4), true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, read_value, 0);
+        // Check CTRL
+        mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+
+        let result = mock.raw_read_register(ApAddress::V1(4).into()).unwrap();
+
+        assert_eq!(result, read_value);
+    }
+
+    #[test]
+    fn read_register_with_wait_response() {
+        let read_value = 47;
+        let mut mock = MockJaylink::new();
+
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        mock.add_read_response(DapAcknowledge::Wait, 0);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        //  When a wait response is received, the sticky overrun bit has to be cleared
+
+        mock.add_transfer();
         mock.add_write_response(
             DapAcknowledge::Ok,
             mock.swd_settings.num_idle_cycles_between_writes,
@@ -1701,12 +3109,136 @@ mod test {
     }
 
     #[test]
-    fn write_register_jtag() {
+    fn write_block_single_trailing_rdbuff() {
+        let mut mock = MockJaylink::new();
+
+        let idle_cycles = mock.swd_settings.num_idle_cycles_between_writes;
+
+        for _ in 0..16 {
+            mock.add_write_response(DapAcknowledge::Ok, idle_cycles);
+        }
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        mock.raw_write_block(ApAddress::V1(0xc).into(), &[0x1234_5678; 16])
+            .expect("Failed to write block");
+
+        assert_eq!(mock.probe_statistics.num_transfers, 17);
+    }
+
+    #[test]
+    fn missing_ap_is_reported() {
+        let mut mock = MockJaylink::new();
+        mock.swd_settings.validate_ap_presence = true;
+
+        let idle_cycles = mock.swd_settings.num_idle_cycles_between_writes;
+
+        // Select the IDR bank of AP 0
+        mock.add_write_response(DapAcknowledge::Ok, idle_cycles);
+        // Read the IDR, which is returned by the following RDBUFF read
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        // Restore SELECT
+        mock.add_write_response(DapAcknowledge::Ok, idle_cycles);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        let result = mock.raw_read_register(ApAddress::V1(4).into());
+
+        assert!(matches!(result, Err(ArmError::ApDoesNotExist(_))));
+        assert!(mock.raw_dap_state.present_aps.is_empty());
+    }
+
+    #[test]
+    fn empty_block_access() {
         let mut mock = MockJaylink::new();
 
+        mock.raw_read_block(ApAddress::V1(0xc).into(), &mut [])
+            .expect("Failed to read empty block");
+        mock.raw_write_block(ApAddress::V1(0xc).into(), &[])
+            .expect("Failed to write empty block");
+
+        assert_eq!(mock.probe_statistics.num_transfers, 0);
+    }
+
+    #[test]
+    fn no_native_block_support() {
+        // The polyfill decomposes block transfers into individual transfers.
+        assert!(!MockJaylink::new().supports_native_block());
+    }
+
+    #[test]
+    fn write_register_jtag_without_ctrl_check() {
+        let mut mock = MockJaylink::new();
+        mock.swd_settings.verify_jtag_ctrl = false;
+
+        let result = mock.select_protocol(WireProtocol::Jtag);
+        assert!(result.is_ok());
+
+        mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x123, 0x0);
+
+        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
+            .expect("Failed to write register");
+
+        // The mock panics on the CTRL/STAT read if it is performed.
+        assert!(mock.jtag_transactions.is_empty());
+    }
+
+    #[test]
+    fn read_register_jtag_without_ctrl_check() {
+        let read_value = 12;
+
+        let mut mock = MockJaylink::new();
+        mock.swd_settings.verify_jtag_ctrl = false;
+
+        let result = mock.select_protocol(WireProtocol::Jtag);
+        assert!(result.is_ok());
+
+        mock.add_jtag_response(ApAddress::V1(4), true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, read_value, 0);
+
+        let result = mock.raw_read_register(ApAddress::V1(4).into()).unwrap();
+
+        assert_eq!(result, read_value);
+        assert!(mock.jtag_transactions.is_empty());
+    }
+
+    #[test]
+    fn read_rdbuff_jtag_without_ctrl_check() {
+        let read_value = 0xAABB_CCDD;
+
+        let mut mock = MockJaylink::new();
+        mock.swd_settings.verify_jtag_ctrl = false;
+
+        let result = mock.select_protocol(WireProtocol::Jtag);
+        assert!(result.is_ok());
+
+        // Without the CTRL/STAT check, a fake read is needed to get the response.
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, read_value, 0);
+
+        let result = mock.raw_read_register(RdBuff::ADDRESS.into()).unwrap();
+
+        assert_eq!(result, read_value);
+        assert!(mock.jtag_transactions.is_empty());
+    }
+
+    #[test]
+    fn write_register_jtag_with_sticky_preclear() {
+        let mut mock = MockJaylink::new();
+        mock.swd_settings.preclear_jtag_sticky_errors = true;
+
         let result = mock.select_protocol(WireProtocol::Jtag);
         assert!(result.is_ok());
 
+        // CTRL/STAT is written back with the sticky flags set before the batch.
+        mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0xf000_0020, 0);
+        mock.add_jtag_response(Ctrl::ADDRESS, false, DapAcknowledge::Ok, 0, 0xf000_0022);
+
         mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
         mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x123, 0x0);
         // Check CTRL
@@ -1715,299 +3247,1429 @@ mod test {
 
         mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
             .expect("Failed to write register");
+
+        assert!(mock.jtag_transactions.is_empty());
     }
 
-    #[test]
-    fn write_register_with_wait_response() {
-        let mut mock = MockJaylink::new();
-        let idle_cycles = mock.swd_settings.num_idle_cycles_between_writes;
+    #[test]
+    fn write_register_jtag_with_extreme_idle_cycles() {
+        let mut mock = MockJaylink::new();
+        mock.swd_settings.num_idle_cycles_between_writes = usize::MAX;
+        mock.swd_settings.idle_cycles_after_transfer = usize::MAX;
+
+        let result = mock.select_protocol(WireProtocol::Jtag);
+        assert!(result.is_ok());
+
+        mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x123, 0x0);
+        // Check CTRL
+        mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+
+        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
+            .expect("Failed to write register");
+
+        assert!(mock.jtag_transactions.is_empty());
+        // The idle cycles were clamped to what the probe supports.
+        assert_eq!(mock.probe_statistics.num_idle_cycles_clamped, 1);
+    }
+
+    #[test]
+    fn write_register_jtag_with_remapped_ir_opcodes() {
+        let mut mock = MockJaylink::new();
+        mock.swd_settings.preclear_jtag_sticky_errors = true;
+        mock.swd_settings.jtag_ir_opcodes = JtagIrOpcodes {
+            abort: 0x18,
+            dpacc: 0x1A,
+            apacc: 0x1B,
+        };
+
+        let result = mock.select_protocol(WireProtocol::Jtag);
+        assert!(result.is_ok());
+
+        mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0xf000_0000, 0);
+        mock.add_jtag_response(Ctrl::ADDRESS, false, DapAcknowledge::Ok, 0, 0xf000_0022);
+        mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x123, 0x0);
+        // Check CTRL
+        mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+
+        let ir_addresses: Vec<_> = mock
+            .jtag_transactions
+            .iter()
+            .map(|transaction| transaction.ir_address)
+            .collect();
+        assert_eq!(ir_addresses, [0x1A, 0x1A, 0x1A, 0x1B, 0x1A, 0x1A, 0x1A]);
+
+        // The mock checks the IR address of each transaction.
+        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
+            .expect("Failed to write register");
+
+        assert!(mock.jtag_transactions.is_empty());
+    }
+
+    #[test]
+    fn write_register_jtag() {
+        let mut mock = MockJaylink::new();
+
+        let result = mock.select_protocol(WireProtocol::Jtag);
+        assert!(result.is_ok());
+
+        mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x123, 0x0);
+        // Check CTRL
+        mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+
+        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
+            .expect("Failed to write register");
+    }
+
+    #[test]
+    fn write_register_with_wait_response() {
+        let mut mock = MockJaylink::new();
+        let idle_cycles = mock.swd_settings.num_idle_cycles_between_writes;
+
+        mock.add_write_response(DapAcknowledge::Ok, idle_cycles);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+        mock.add_read_response(DapAcknowledge::Wait, 0);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        // Expect a Write to the ABORT register.
+        mock.add_transfer();
+        mock.add_write_response(DapAcknowledge::Ok, idle_cycles);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        // Second try to write register, with increased idle cycles.
+        mock.add_transfer();
+        //mock.add_write_response(DapAcknowledge::Ok, idle_cycles * 2);
+        //mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
+            .expect("Failed to write register");
+    }
+
+    #[test]
+    fn write_register_with_wdataerr() {
+        let mut mock = MockJaylink::new();
+        let between_writes = mock.swd_settings.num_idle_cycles_between_writes;
+        let before_write_verify = mock.swd_settings.idle_cycles_before_write_verify;
+        let after_transfer = mock.swd_settings.idle_cycles_after_transfer;
+
+        // The write is faulted in the response to the RDBUFF read.
+        mock.add_write_response(DapAcknowledge::Ok, between_writes);
+        mock.add_idle_cycles(before_write_verify);
+        mock.add_read_response(DapAcknowledge::Fault, 0);
+        mock.add_idle_cycles(after_transfer);
+
+        // CTRL/STAT with WDATAERR set
+        mock.add_transfer();
+        mock.add_read_response(DapAcknowledge::Ok, 0x0000_0080);
+        mock.add_idle_cycles(after_transfer);
+
+        // Expect a write to the ABORT register.
+        mock.add_transfer();
+        mock.add_write_response(DapAcknowledge::Ok, before_write_verify + between_writes);
+
+        let result = mock.raw_write_register(ApAddress::V1(4).into(), 0x123);
+        assert!(matches!(
+            result,
+            Err(ArmError::Dap(DapError::FaultResponse))
+        ));
+
+        let mut abort = Abort(0);
+        abort.set_wderrclr(true);
+        let expected = DapTransfer::write(Abort::ADDRESS, abort.into())
+            .with_idle_cycles_after(before_write_verify + between_writes);
+        assert_eq!(
+            mock.io_input.as_deref().unwrap(),
+            expected.io_sequence(false).io_items().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn write_register_retried_after_wdataerr() {
+        let mut mock = MockJaylink::new();
+        mock.swd_settings.retry_write_after_wdataerr = true;
+        let between_writes = mock.swd_settings.num_idle_cycles_between_writes;
+        let before_write_verify = mock.swd_settings.idle_cycles_before_write_verify;
+        let after_transfer = mock.swd_settings.idle_cycles_after_transfer;
+
+        // The write is faulted in the response to the RDBUFF read.
+        mock.add_write_response(DapAcknowledge::Ok, between_writes);
+        mock.add_idle_cycles(before_write_verify);
+        mock.add_read_response(DapAcknowledge::Fault, 0);
+        mock.add_idle_cycles(after_transfer);
+
+        // CTRL/STAT with WDATAERR set
+        mock.add_transfer();
+        mock.add_read_response(DapAcknowledge::Ok, 0x0000_0080);
+        mock.add_idle_cycles(after_transfer);
+
+        // Expect a write to the ABORT register.
+        mock.add_transfer();
+        mock.add_write_response(DapAcknowledge::Ok, before_write_verify + between_writes);
+
+        // The write is repeated, and succeeds this time.
+        mock.add_transfer();
+        mock.add_write_response(DapAcknowledge::Ok, between_writes);
+        mock.add_idle_cycles(before_write_verify);
+        mock.add_read_response(DapAcknowledge::Ok, 0);
+        mock.add_idle_cycles(after_transfer);
+
+        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
+            .expect("Failed to write register");
+
+        assert_eq!(mock.performed_transfer_count, 4);
+    }
+
+    #[test]
+    fn swd_request_parity_of_common_requests() {
+        // Read DPIDR, 0xA5
+        assert!(swd_request_parity(false, true, false, false));
+        // Write ABORT, 0x81
+        assert!(!swd_request_parity(false, false, false, false));
+        // Read RDBUFF, 0xBD
+        assert!(swd_request_parity(false, true, true, true));
+        // Write SELECT, 0xB1
+        assert!(swd_request_parity(false, false, false, true));
+        // Read AP register 0x0, 0x87
+        assert!(!swd_request_parity(true, true, false, false));
+    }
+
+    #[test]
+    fn swd_data_parity_of_values() {
+        assert!(!swd_data_parity(0));
+        assert!(!swd_data_parity(u32::MAX));
+        assert!(swd_data_parity(0x8000_0000));
+        // DPIDR of a Cortex-M4, with 14 bits set
+        assert!(!swd_data_parity(0x2ba0_1477));
+    }
+
+    /// The response to a read of `value` with an OK acknowledge, as sampled by a probe.
+    ///
+    /// Probes sampling on the rising edge see the turnaround bit before the acknowledge.
+    fn sampled_read_response(value: u32, turnaround_bit: bool) -> Vec<bool> {
+        let mut bits = Vec::new();
+        if turnaround_bit {
+            bits.push(false);
+        }
+        bits.extend([true, false, false]);
+        bits.extend((0..32).map(|i| value & (1 << i) != 0));
+        bits.push(value.count_ones() % 2 == 1);
+        bits.extend([false, false]);
+        bits
+    }
+
+    #[test]
+    fn parse_swd_response_sampled_on_falling_edge() {
+        let response = sampled_read_response(0x1234_5678, false);
+
+        assert_eq!(
+            parse_swd_response(&response, TransferDirection::Read, true),
+            Ok(0x1234_5678)
+        );
+        // Skipping the first bit would lose the first acknowledge bit.
+        assert!(parse_swd_response(&response, TransferDirection::Read, false).is_err());
+    }
+
+    #[test]
+    fn parse_swd_response_sampled_on_rising_edge() {
+        let response = sampled_read_response(0x1234_5678, true);
+
+        assert_eq!(
+            parse_swd_response(&response, TransferDirection::Read, false),
+            Ok(0x1234_5678)
+        );
+        assert_eq!(
+            parse_swd_response(&response, TransferDirection::Write, false),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn read_register_clears_sticky_compare() {
+        let mut mock = MockJaylink::new();
+        let between_writes = mock.swd_settings.num_idle_cycles_between_writes;
+        let before_write_verify = mock.swd_settings.idle_cycles_before_write_verify;
+        let after_transfer = mock.swd_settings.idle_cycles_after_transfer;
+
+        mock.add_read_response(DapAcknowledge::Fault, 0);
+        mock.add_idle_cycles(after_transfer);
+
+        // CTRL/STAT with STICKYCMP set, and three transactions left in TRNCNT
+        mock.add_transfer();
+        mock.add_read_response(DapAcknowledge::Ok, 0x0000_3010);
+        mock.add_idle_cycles(after_transfer);
+
+        // ABORT write to clear STICKYCMP
+        mock.add_transfer();
+        mock.add_write_response(DapAcknowledge::Ok, before_write_verify + between_writes);
+
+        // CTRL/STAT write to clear TRNCNT
+        mock.add_transfer();
+        mock.add_write_response(DapAcknowledge::Ok, before_write_verify + between_writes);
+
+        let result = mock.raw_read_register(DPIDR::ADDRESS.into());
+        assert!(matches!(
+            result,
+            Err(ArmError::Dap(DapError::FaultResponse))
+        ));
+
+        let expected = DapTransfer::write(Ctrl::ADDRESS, 0x0000_0010)
+            .with_idle_cycles_after(before_write_verify + between_writes);
+        assert_eq!(
+            mock.io_input.as_deref().unwrap(),
+            expected.io_sequence(false).io_items().collect::<Vec<_>>()
+        );
+        assert_eq!(mock.performed_transfer_count, 4);
+    }
+
+    #[test]
+    fn read_register_with_faulting_ctrl_read() {
+        let mut mock = MockJaylink::new();
+        let between_writes = mock.swd_settings.num_idle_cycles_between_writes;
+        let before_write_verify = mock.swd_settings.idle_cycles_before_write_verify;
+        let after_transfer = mock.swd_settings.idle_cycles_after_transfer;
+
+        mock.add_read_response(DapAcknowledge::Fault, 0);
+        mock.add_idle_cycles(after_transfer);
+
+        // The CTRL/STAT read to determine the reason faults as well.
+        mock.add_transfer();
+        mock.add_read_response(DapAcknowledge::Fault, 0);
+        mock.add_idle_cycles(after_transfer);
+
+        // Only the sticky errors are cleared, CTRL/STAT is not read again.
+        mock.add_transfer();
+        mock.add_write_response(DapAcknowledge::Ok, before_write_verify + between_writes);
+
+        let result = mock.raw_read_register(DPIDR::ADDRESS.into());
+        assert!(matches!(
+            result,
+            Err(ArmError::Dap(DapError::FaultResponse))
+        ));
+
+        assert!(mock.transfer_responses.is_empty());
+        assert_eq!(mock.performed_transfer_count, 3);
+        assert!(!mock.raw_dap_state.handling_fault);
+    }
+
+    #[test]
+    fn write_register_with_wait_response_jtag() {
+        let mut mock = MockJaylink::new();
+
+        let result = mock.select_protocol(WireProtocol::Jtag);
+        assert!(result.is_ok());
+
+        mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Wait, 0x0, 0x0);
+
+        // Expect a Write to the ABORT register.
+        mock.add_jtag_abort();
+
+        // Second try to write register.
+        mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x123, 0x0);
+        // Check CTRL
+        mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+
+        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
+            .expect("Failed to write register");
+    }
+
+    /// Test the correct handling of several transfers, with
+    /// the appropriate extra reads added as necessary.
+    mod transfer_handling {
+        use super::{
+            super::{DapTransfer, IoSequence, TransferStatus, perform_transfers},
+            DapAcknowledge, MockJaylink,
+        };
+        use crate::{
+            architecture::arm::{
+                ApAddress, ArmError, DapError, RawDapAccess, RegisterAddress, RetryConfig,
+                TransferObserver,
+                dp::{
+                    Abort, Ctrl, DPIDR, DpRegister, DpRegisterAddress, RdBuff, Select1, SelectV1,
+                    SelectV3, TARGETID,
+                },
+            },
+            probe::{DebugProbeError, IoSequenceItem, JtagIrOpcodes},
+        };
+        use std::sync::{
+            Arc, Mutex,
+            atomic::{AtomicBool, Ordering},
+        };
+
+        #[test]
+        fn single_dp_register_read() {
+            let register_value = 32354;
+
+            let mut transfers = vec![DapTransfer::read(DPIDR::ADDRESS)];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_read_response(DapAcknowledge::Ok, register_value);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            let transfer_result = &transfers[0];
+
+            assert_eq!(transfer_result.status, TransferStatus::Ok);
+            assert_eq!(transfer_result.value, register_value);
+        }
+
+        #[test]
+        fn single_ap_register_read() {
+            let register_value = 0x11_22_33_44u32;
+
+            let mut transfers = vec![DapTransfer::read(ApAddress::V1(0))];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_read_response(DapAcknowledge::Ok, register_value);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            let transfer_result = &transfers[0];
+
+            assert_eq!(transfer_result.status, TransferStatus::Ok);
+            assert_eq!(transfer_result.value, register_value);
+        }
+
+        #[test]
+        fn ap_then_dp_register_read() {
+            // When reading from the AP first, and then from the DP,
+            // we need to insert an additional read from the RDBUFF register to
+            // get the result for the AP read.
+
+            let ap_read_value = 0x123223;
+            let dp_read_value = 0xFFAABB;
+
+            let mut transfers = vec![
+                DapTransfer::read(ApAddress::V1(4)),
+                DapTransfer::read(DpRegisterAddress {
+                    address: 3,
+                    bank: None,
+                }),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_read_response(DapAcknowledge::Ok, ap_read_value);
+            mock.add_read_response(DapAcknowledge::Ok, dp_read_value);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, ap_read_value);
+
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].value, dp_read_value);
+        }
+
+        #[test]
+        fn dp_then_ap_register_read() {
+            // When reading from the DP first, and then from the AP,
+            // we need to insert an additional read from the RDBUFF register at the end
+            // to get the result for the AP read.
+
+            let ap_read_value = 0x123223;
+            let dp_read_value = 0xFFAABB;
+
+            let mut transfers = vec![
+                DapTransfer::read(DpRegisterAddress {
+                    address: 3,
+                    bank: None,
+                }),
+                DapTransfer::read(ApAddress::V1(4)),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_read_response(DapAcknowledge::Ok, dp_read_value);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_read_response(DapAcknowledge::Ok, ap_read_value);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, dp_read_value);
+
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].value, ap_read_value);
+        }
+
+        #[test]
+        fn ap_write_then_read_same_register() {
+            // The AP read is stalled by the DP until the buffered write has completed,
+            // so no extra transfer is needed between them. The read value is returned
+            // by the RDBUFF read at the end.
+
+            let written_value = 0x1234_5678;
+
+            let mut transfers = vec![
+                DapTransfer::write(ApAddress::V1(4), written_value),
+                DapTransfer::read(ApAddress::V1(4)),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_write_response(
+                DapAcknowledge::Ok,
+                mock.swd_settings.num_idle_cycles_between_writes,
+            );
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_read_response(DapAcknowledge::Ok, written_value);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].value, written_value);
+
+            assert_eq!(mock.probe_statistics.num_transfers, 3);
+        }
+
+        #[test]
+        fn extra_rdbuff_reads_are_categorized() {
+            let ap_read_value = 0x1234_5678;
+            let dpidr_value = 0x2ba0_1477;
+
+            let mut transfers = vec![
+                // The result of the AP read is retrieved before the DP read.
+                DapTransfer::read(ApAddress::V1(4)),
+                DapTransfer::read(DpRegisterAddress {
+                    address: 3,
+                    bank: None,
+                }),
+                // The buffered write must be completed before reading DPIDR.
+                DapTransfer::write(ApAddress::V1(0), 0xABAB_ABAB),
+                DapTransfer::read(DPIDR::ADDRESS),
+                // The status of the last write is retrieved at the end.
+                DapTransfer::write(ApAddress::V1(0), 0xCDCD_CDCD),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_read_response(DapAcknowledge::Ok, ap_read_value);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+
+            mock.add_write_response(
+                DapAcknowledge::Ok,
+                mock.swd_settings.num_idle_cycles_between_writes,
+            );
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_read_response(DapAcknowledge::Ok, dpidr_value);
+
+            mock.add_write_response(
+                DapAcknowledge::Ok,
+                mock.swd_settings.num_idle_cycles_between_writes,
+            );
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert!(transfers.iter().all(|t| t.status == TransferStatus::Ok));
+            assert_eq!(transfers[0].value, ap_read_value);
+            assert_eq!(transfers[3].value, dpidr_value);
+
+            let statistics = &mock.probe_statistics;
+            assert_eq!(statistics.num_ap_read_rdbuff, 1);
+            assert_eq!(statistics.num_stall_rdbuff, 1);
+            assert_eq!(statistics.num_final_flush, 1);
+            assert_eq!(statistics.num_transfers, 8);
+        }
+
+        #[test]
+        fn multiple_ap_read() {
+            // When reading from the AP twice, only a single additional read from the
+            // RDBUFF register is necessary.
+
+            let ap_read_values = [1, 2];
+
+            let mut transfers = vec![
+                DapTransfer::read(ApAddress::V1(4)),
+                DapTransfer::read(ApAddress::V1(4)),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_read_response(DapAcknowledge::Ok, ap_read_values[0]);
+            mock.add_read_response(DapAcknowledge::Ok, ap_read_values[1]);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, ap_read_values[0]);
+
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].value, ap_read_values[1]);
+        }
+
+        #[test]
+        fn multiple_dp_read() {
+            // When reading from the DP twice, no additional reads have to be inserted.
+
+            let dp_read_values = [1, 2];
+
+            let mut transfers = vec![
+                DapTransfer::read(Ctrl::ADDRESS),
+                DapTransfer::read(Ctrl::ADDRESS),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_read_response(DapAcknowledge::Ok, dp_read_values[0]);
+            mock.add_read_response(DapAcknowledge::Ok, dp_read_values[1]);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, dp_read_values[0]);
+
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].value, dp_read_values[1]);
+        }
+
+        #[test]
+        fn single_dp_register_write() {
+            let mut transfers = vec![DapTransfer::write(Abort::ADDRESS, 0x1234_5678)];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_write_response(
+                DapAcknowledge::Ok,
+                mock.swd_settings.num_idle_cycles_between_writes,
+            );
+
+            // To verify that the write was successful, an additional read is performed.
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            let transfer_result = &transfers[0];
+
+            assert_eq!(transfer_result.status, TransferStatus::Ok);
+        }
+
+        #[test]
+        fn single_ap_register_write() {
+            let mut transfers = vec![DapTransfer::write(ApAddress::V1(0), 0x1234_5678)];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_write_response(
+                DapAcknowledge::Ok,
+                mock.swd_settings.num_idle_cycles_between_writes,
+            );
+
+            // To verify that the write was successful, an additional read is performed.
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            let transfer_result = &transfers[0];
+
+            assert_eq!(transfer_result.status, TransferStatus::Ok);
+        }
+
+        #[test]
+        fn multiple_ap_register_write() {
+            let mut transfers = vec![
+                DapTransfer::write(ApAddress::V1(0), 0x1234_5678),
+                DapTransfer::write(ApAddress::V1(0), 0xABABABAB),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            mock.add_write_response(
+                DapAcknowledge::Ok,
+                mock.swd_settings.num_idle_cycles_between_writes,
+            );
+            mock.add_write_response(
+                DapAcknowledge::Ok,
+                mock.swd_settings.num_idle_cycles_between_writes,
+            );
+
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+        }
+
+        #[test]
+        fn idle_cycles_use_configured_swdio_value() {
+            for idle_bit in [false, true] {
+                let mut transfers = vec![DapTransfer::read(DPIDR::ADDRESS)];
+
+                let mut mock = MockJaylink::new();
+                mock.swd_settings.idle_cycle_swdio = idle_bit;
+
+                mock.add_read_response(DapAcknowledge::Ok, 0);
+                mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+                perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+                let io = mock.io_input.as_ref().unwrap();
+                let idle_cycles = mock.swd_settings.idle_cycles_after_transfer;
+                let idle = &io[io.len() - idle_cycles..];
+
+                assert!(
+                    idle.iter()
+                        .all(|item| *item == IoSequenceItem::Output(idle_bit))
+                );
+            }
+        }
+
+        #[test]
+        fn configured_dap_options_are_used() {
+            let mut mock = MockJaylink::new();
+
+            let mut config = mock.dap_config().expect("Failed to read the DAP options");
+            assert!(!config.idle_cycle_swdio);
+
+            config.idle_cycle_swdio = true;
+            config.verify_jtag_ctrl = false;
+            config.validate_ap_presence = true;
+            config.parking_idle_cycles = 2;
+            config.flag_suspicious_reads = true;
+            config.preclear_jtag_sticky_errors = true;
+            config.leading_idle_cycles = 4;
+            config.line_reset_retries = 3;
+            config.jtag_ir_opcodes = JtagIrOpcodes {
+                abort: 0x1,
+                dpacc: 0x2,
+                apacc: 0x3,
+            };
+            config.retry_write_after_wdataerr = true;
+            config.sample_on_falling_edge = false;
+            mock.configure_dap(config)
+                .expect("Failed to configure the DAP options");
+
+            assert!(mock.swd_settings.idle_cycle_swdio);
+            assert!(!mock.swd_settings.verify_jtag_ctrl);
+            assert!(mock.swd_settings.validate_ap_presence);
+            assert_eq!(mock.swd_settings.parking_idle_cycles, 2);
+            assert!(mock.swd_settings.flag_suspicious_reads);
+            assert!(mock.swd_settings.preclear_jtag_sticky_errors);
+            assert_eq!(mock.swd_settings.leading_idle_cycles, 4);
+            assert_eq!(mock.swd_settings.line_reset_retries, 3);
+            assert_eq!(
+                mock.swd_settings.jtag_ir_opcodes,
+                JtagIrOpcodes {
+                    abort: 0x1,
+                    dpacc: 0x2,
+                    apacc: 0x3
+                }
+            );
+            assert!(mock.swd_settings.retry_write_after_wdataerr);
+            assert!(!mock.swd_settings.sample_on_falling_edge);
+            assert_eq!(mock.dap_config().unwrap(), config);
+        }
+
+        #[test]
+        #[cfg(feature = "serialize-reports")]
+        fn dap_options_serialize() {
+            let mut mock = MockJaylink::new();
+
+            let config = mock.dap_config().expect("Failed to read the DAP options");
+            let json = serde_json::to_value(config).unwrap();
+
+            assert_eq!(json["line_reset_retries"], 1);
+            assert_eq!(json["verify_jtag_ctrl"], true);
+            assert_eq!(json["jtag_ir_opcodes"]["dpacc"], 0xA);
+        }
+
+        #[test]
+        fn leading_idle_cycles_start_batch() {
+            let register_value = 0x2ba0_1477;
+            let mut transfers = vec![DapTransfer::read(DPIDR::ADDRESS)];
+
+            let mut mock = MockJaylink::new();
+            mock.swd_settings.leading_idle_cycles = 4;
+
+            mock.add_idle_cycles(mock.swd_settings.leading_idle_cycles);
+            mock.add_read_response(DapAcknowledge::Ok, register_value);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, register_value);
+
+            let io = mock.io_input.as_ref().unwrap();
+            assert!(
+                io[..4]
+                    .iter()
+                    .all(|item| *item == IoSequenceItem::Output(false))
+            );
+            // Start bit of the request
+            assert_eq!(io[4], IoSequenceItem::Output(true));
+        }
+
+        #[test]
+        fn parking_idle_cycles_end_batch() {
+            let mut transfers = vec![DapTransfer::read(DPIDR::ADDRESS)];
+
+            let mut mock = MockJaylink::new();
+            mock.swd_settings.parking_idle_cycles = 16;
+
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            mock.add_idle_cycles(mock.swd_settings.parking_idle_cycles);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            let io = mock.io_input.as_ref().unwrap();
+            let idle_cycles = mock.swd_settings.idle_cycles_after_transfer
+                + mock.swd_settings.parking_idle_cycles;
+            let idle = &io[io.len() - idle_cycles..];
+
+            assert!(
+                idle.iter()
+                    .all(|item| *item == IoSequenceItem::Output(false))
+            );
+        }
+
+        #[test]
+        fn wait_retries_exhausted() {
+            let mut transfers = vec![DapTransfer::read(DPIDR::ADDRESS)];
+
+            let mut mock = MockJaylink::new();
+            mock.swd_settings.num_retries_after_wait = 2;
+            mock.swd_settings.max_retry_idle_cycles_after_wait = 4;
+
+            let abort_idle_cycles = mock.swd_settings.idle_cycles_before_write_verify
+                + mock.swd_settings.num_idle_cycles_between_writes;
+
+            for _ in 0..2 {
+                mock.add_read_response(DapAcknowledge::Wait, 0);
+                mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+                // Clearing the sticky errors after the WAIT
+                mock.add_transfer();
+                mock.add_write_response(DapAcknowledge::Ok, abort_idle_cycles);
+
+                mock.add_transfer();
+            }
+
+            // DAP abort after the last retry
+            mock.add_write_response(DapAcknowledge::Ok, abort_idle_cycles);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(
+                transfers[0].status,
+                TransferStatus::Failed(DapError::WaitResponse)
+            );
+            assert_eq!(mock.probe_statistics.num_wait_idle_ceiling, 1);
+            assert_eq!(mock.probe_statistics.num_wait_retries_exhausted, 1);
+        }
+
+        #[test]
+        fn suspicious_reads_are_flagged() {
+            for (value, suspicious) in [(0, 1), (u32::MAX, 1), (0x2ba0_1477, 0)] {
+                let mut transfers = vec![DapTransfer::read(DPIDR::ADDRESS)];
+
+                let mut mock = MockJaylink::new();
+                mock.swd_settings.flag_suspicious_reads = true;
+
+                mock.add_read_response(DapAcknowledge::Ok, value);
+                mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+                perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+                assert_eq!(transfers[0].status, TransferStatus::Ok);
+                assert_eq!(transfers[0].value, value);
+                assert_eq!(mock.probe_statistics.num_suspicious_reads, suspicious);
+            }
+        }
+
+        #[test]
+        fn configured_retries_are_used() {
+            let mut transfers = vec![DapTransfer::read(DPIDR::ADDRESS)];
+
+            let mut mock = MockJaylink::new();
+            mock.configure_retries(RetryConfig {
+                num_retries_after_wait: 3,
+                max_retry_idle_cycles_after_wait: 4,
+            })
+            .expect("Failed to configure retries");
+            assert_eq!(mock.swd_settings.num_retries_after_wait, 3);
+
+            let abort_idle_cycles = mock.swd_settings.idle_cycles_before_write_verify
+                + mock.swd_settings.num_idle_cycles_between_writes;
+
+            for _ in 0..3 {
+                mock.add_read_response(DapAcknowledge::Wait, 0);
+                mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+                // Clearing the sticky errors after the WAIT
+                mock.add_transfer();
+                mock.add_write_response(DapAcknowledge::Ok, abort_idle_cycles);
+
+                mock.add_transfer();
+            }
+
+            // DAP abort after the last retry
+            mock.add_write_response(DapAcknowledge::Ok, abort_idle_cycles);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(
+                transfers[0].status,
+                TransferStatus::Failed(DapError::WaitResponse)
+            );
+            assert_eq!(mock.probe_statistics.num_wait_retries_exhausted, 1);
+        }
+
+        #[test]
+        fn swd_batch_split_across_chunks() {
+            let dpidr_value = 0x2ba0_1477;
+            let ctrl_value = 0xF000_0000;
+
+            let mut transfers = vec![
+                DapTransfer::read(DPIDR::ADDRESS),
+                DapTransfer::read(Ctrl::ADDRESS),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            // Only leave room for a single read per call.
+            mock.swd_settings.max_io_items_per_call =
+                Some(DapTransfer::read(DPIDR::ADDRESS).io_sequence(false).len());
+
+            let progress = Arc::new(Mutex::new(Vec::new()));
+            let reported = progress.clone();
+            mock.raw_dap_state.transfer_progress = Some(Box::new(move |completed| {
+                reported.lock().unwrap().push(completed);
+            }));
+
+            mock.add_read_response(DapAcknowledge::Ok, dpidr_value);
+            mock.add_transfer();
+            mock.add_read_response(DapAcknowledge::Ok, ctrl_value);
+            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            assert_eq!(mock.performed_transfer_count, 2);
+
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, dpidr_value);
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].value, ctrl_value);
+
+            assert_eq!(*progress.lock().unwrap(), [1, 2]);
+        }
+
+        #[test]
+        fn swd_batch_cancelled_between_chunks() {
+            let mut transfers = vec![
+                DapTransfer::read(DPIDR::ADDRESS),
+                DapTransfer::read(Ctrl::ADDRESS),
+            ];
+
+            let mut mock = MockJaylink::new();
+
+            // Only leave room for a single read per call.
+            mock.swd_settings.max_io_items_per_call =
+                Some(DapTransfer::read(DPIDR::ADDRESS).io_sequence(false).len());
+
+            let cancel = Arc::new(AtomicBool::new(false));
+            mock.set_cancel_flag(Some(cancel.clone())).unwrap();
 
-        mock.add_write_response(DapAcknowledge::Ok, idle_cycles);
-        mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
-        mock.add_read_response(DapAcknowledge::Wait, 0);
-        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            // Cancel once the first chunk is done.
+            mock.raw_dap_state.transfer_progress = Some(Box::new(move |_| {
+                cancel.store(true, Ordering::Relaxed);
+            }));
 
-        // Expect a Write to the ABORT register.
-        mock.add_transfer();
-        mock.add_write_response(DapAcknowledge::Ok, idle_cycles);
-        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            mock.add_read_response(DapAcknowledge::Ok, 0x2ba0_1477);
 
-        // Second try to write register, with increased idle cycles.
-        mock.add_transfer();
-        //mock.add_write_response(DapAcknowledge::Ok, idle_cycles * 2);
-        //mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
-        mock.add_read_response(DapAcknowledge::Ok, 0);
-        mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            let result = perform_transfers(&mut mock, &mut transfers);
 
-        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
-            .expect("Failed to write register");
-    }
+            assert!(matches!(
+                result,
+                Err(ArmError::Probe(DebugProbeError::Cancelled { completed: 1 }))
+            ));
+            // The second chunk was not sent.
+            assert_eq!(mock.performed_transfer_count, 1);
+        }
 
-    #[test]
-    fn write_register_with_wait_response_jtag() {
-        let mut mock = MockJaylink::new();
+        #[test]
+        fn cancelled_batch_counts_original_transfers() {
+            let mut transfers = vec![
+                DapTransfer::read(ApAddress::V1(4)),
+                DapTransfer::read(DPIDR::ADDRESS),
+            ];
 
-        let result = mock.select_protocol(WireProtocol::Jtag);
-        assert!(result.is_ok());
+            let mut mock = MockJaylink::new();
 
-        mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
-        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Wait, 0x0, 0x0);
+            // Only leave room for a single read per call.
+            mock.swd_settings.max_io_items_per_call =
+                Some(DapTransfer::read(DPIDR::ADDRESS).io_sequence(false).len());
 
-        // Expect a Write to the ABORT register.
-        mock.add_jtag_abort();
+            let cancel = Arc::new(AtomicBool::new(false));
+            mock.set_cancel_flag(Some(cancel.clone())).unwrap();
 
-        // Second try to write register.
-        mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0x0, 0x123);
-        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x123, 0x0);
-        // Check CTRL
-        mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
-        mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+            mock.raw_dap_state.transfer_progress = Some(Box::new(move |_| {
+                cancel.store(true, Ordering::Relaxed);
+            }));
 
-        mock.raw_write_register(ApAddress::V1(4).into(), 0x123)
-            .expect("Failed to write register");
-    }
+            mock.add_read_response(DapAcknowledge::Ok, 0);
 
-    /// Test the correct handling of several transfers, with
-    /// the appropriate extra reads added as necessary.
-    mod transfer_handling {
-        use super::{
-            super::{DapTransfer, TransferStatus, perform_transfers},
-            DapAcknowledge, MockJaylink,
-        };
-        use crate::architecture::arm::{
-            ApAddress,
-            dp::{Abort, Ctrl, DPIDR, DpRegister, DpRegisterAddress},
-        };
+            let result = perform_transfers(&mut mock, &mut transfers);
+
+            // The value of the AP read is only returned by the RDBUFF read which follows it,
+            // so it is not completed yet.
+            assert!(matches!(
+                result,
+                Err(ArmError::Probe(DebugProbeError::Cancelled { completed: 0 }))
+            ));
+            assert_eq!(mock.performed_transfer_count, 1);
+        }
 
         #[test]
-        fn single_dp_register_read() {
-            let register_value = 32354;
+        fn dp_reads_in_different_banks() {
+            let ctrl_value = 0xF000_0000;
+            let targetid_value = 0x1002_0927;
 
-            let mut transfers = vec![DapTransfer::read(DPIDR::ADDRESS)];
+            let mut transfers = vec![
+                DapTransfer::read(Ctrl::ADDRESS),
+                DapTransfer::read(TARGETID::ADDRESS),
+            ];
 
             let mut mock = MockJaylink::new();
-
-            mock.add_read_response(DapAcknowledge::Ok, register_value);
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            let between_writes = mock.swd_settings.num_idle_cycles_between_writes;
+            let before_write_verify = mock.swd_settings.idle_cycles_before_write_verify;
+            let after_transfer = mock.swd_settings.idle_cycles_after_transfer;
+
+            mock.add_read_response(DapAcknowledge::Ok, ctrl_value);
+            mock.add_write_response(DapAcknowledge::Ok, between_writes);
+            mock.add_read_response(DapAcknowledge::Ok, targetid_value);
+            mock.add_write_response(DapAcknowledge::Ok, between_writes);
+            mock.add_idle_cycles(before_write_verify);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_idle_cycles(after_transfer);
 
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
-            let transfer_result = &transfers[0];
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, ctrl_value);
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].value, targetid_value);
 
-            assert_eq!(transfer_result.status, TransferStatus::Ok);
-            assert_eq!(transfer_result.value, register_value);
+            // SELECT is switched to bank 2 before the TARGETID read, and back to bank 0 at the end.
+            let mut expected = vec![
+                DapTransfer::read(Ctrl::ADDRESS),
+                DapTransfer::write(SelectV1::ADDRESS, 2),
+                DapTransfer::read(TARGETID::ADDRESS),
+                DapTransfer::write(SelectV1::ADDRESS, 0),
+                DapTransfer::read(RdBuff::ADDRESS),
+            ];
+            expected[1].idle_cycles_after = between_writes;
+            expected[3].idle_cycles_after = between_writes + before_write_verify;
+            expected[4].idle_cycles_after = after_transfer;
+
+            let mut expected_io = IoSequence::new();
+            for transfer in &expected {
+                expected_io.extend(&transfer.io_sequence(false));
+            }
+
+            assert_eq!(
+                mock.io_input.as_deref().unwrap(),
+                expected_io.io_items().collect::<Vec<_>>()
+            );
+            assert_eq!(mock.raw_dap_state.dp_select, Some(0));
         }
 
         #[test]
-        fn single_ap_register_read() {
-            let register_value = 0x11_22_33_44u32;
+        fn ap_v2_read_above_4gb() {
+            let idr_value = 0x6477_0004;
 
-            let mut transfers = vec![DapTransfer::read(ApAddress::V1(0))];
+            // The AP is at 0x1_0000_2000, so SELECT1 has to be written as well.
+            let mut transfers = vec![DapTransfer::read_ap_v2(0x1_0000_2DFC)];
 
             let mut mock = MockJaylink::new();
+            let between_writes = mock.swd_settings.num_idle_cycles_between_writes;
+            let before_write_verify = mock.swd_settings.idle_cycles_before_write_verify;
+            let after_transfer = mock.swd_settings.idle_cycles_after_transfer;
 
+            mock.add_write_response(DapAcknowledge::Ok, between_writes);
+            mock.add_write_response(DapAcknowledge::Ok, between_writes);
             mock.add_read_response(DapAcknowledge::Ok, 0);
-            mock.add_read_response(DapAcknowledge::Ok, register_value);
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            mock.add_read_response(DapAcknowledge::Ok, idr_value);
+            mock.add_write_response(DapAcknowledge::Ok, between_writes);
+            mock.add_write_response(DapAcknowledge::Ok, between_writes);
+            mock.add_idle_cycles(before_write_verify);
+            mock.add_read_response(DapAcknowledge::Ok, 0);
+            mock.add_idle_cycles(after_transfer);
 
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
-            let transfer_result = &transfers[0];
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, idr_value);
+
+            // SELECT is switched to the SELECT1 bank together with the lower address bits,
+            // and both registers are restored at the end.
+            let mut expected = vec![
+                DapTransfer::write(SelectV3::ADDRESS, 0x2DF5),
+                DapTransfer::write(Select1::ADDRESS, 0x1),
+                DapTransfer::read_ap_v2(0x1_0000_2DFC),
+                DapTransfer::read(RdBuff::ADDRESS),
+                DapTransfer::write(Select1::ADDRESS, 0x0),
+                DapTransfer::write(SelectV3::ADDRESS, 0x0),
+                DapTransfer::read(RdBuff::ADDRESS),
+            ];
+            expected[0].idle_cycles_after = between_writes;
+            expected[1].idle_cycles_after = between_writes;
+            expected[4].idle_cycles_after = between_writes;
+            expected[5].idle_cycles_after = between_writes + before_write_verify;
+            expected[6].idle_cycles_after = after_transfer;
+
+            let mut expected_io = IoSequence::new();
+            for transfer in &expected {
+                expected_io.extend(&transfer.io_sequence(false));
+            }
 
-            assert_eq!(transfer_result.status, TransferStatus::Ok);
-            assert_eq!(transfer_result.value, register_value);
+            assert_eq!(
+                mock.io_input.as_deref().unwrap(),
+                expected_io.io_items().collect::<Vec<_>>()
+            );
+            assert_eq!(mock.raw_dap_state.dp_select, Some(0));
+            assert_eq!(mock.raw_dap_state.dp_select1, Some(0));
         }
 
         #[test]
-        fn ap_then_dp_register_read() {
-            // When reading from the AP first, and then from the DP,
-            // we need to insert an additional read from the RDBUFF register to
-            // get the result for the AP read.
+        fn transfer_observer_sees_logical_transfers() {
+            struct Recorder(Arc<Mutex<Vec<(RegisterAddress, TransferStatus)>>>);
 
-            let ap_read_value = 0x123223;
-            let dp_read_value = 0xFFAABB;
+            impl TransferObserver for Recorder {
+                fn on_transfer(&mut self, transfer: &DapTransfer, status: &TransferStatus) {
+                    self.0.lock().unwrap().push((transfer.address(), *status));
+                }
+            }
 
             let mut transfers = vec![
-                DapTransfer::read(ApAddress::V1(4)),
-                DapTransfer::read(DpRegisterAddress {
-                    address: 3,
-                    bank: None,
-                }),
+                DapTransfer::read(Ctrl::ADDRESS),
+                DapTransfer::read(TARGETID::ADDRESS),
             ];
 
             let mut mock = MockJaylink::new();
-
+            let between_writes = mock.swd_settings.num_idle_cycles_between_writes;
+            let before_write_verify = mock.swd_settings.idle_cycles_before_write_verify;
+            let after_transfer = mock.swd_settings.idle_cycles_after_transfer;
+
+            let observed = Arc::new(Mutex::new(Vec::new()));
+            mock.set_transfer_observer(Some(Box::new(Recorder(observed.clone()))))
+                .unwrap();
+
+            mock.add_read_response(DapAcknowledge::Ok, 0xF000_0000);
+            mock.add_write_response(DapAcknowledge::Ok, between_writes);
+            mock.add_read_response(DapAcknowledge::Ok, 0x1002_0927);
+            mock.add_write_response(DapAcknowledge::Ok, between_writes);
+            mock.add_idle_cycles(before_write_verify);
             mock.add_read_response(DapAcknowledge::Ok, 0);
-            mock.add_read_response(DapAcknowledge::Ok, ap_read_value);
-            mock.add_read_response(DapAcknowledge::Ok, dp_read_value);
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            mock.add_idle_cycles(after_transfer);
 
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
-            assert_eq!(transfers[0].status, TransferStatus::Ok);
-            assert_eq!(transfers[0].value, ap_read_value);
-
-            assert_eq!(transfers[1].status, TransferStatus::Ok);
-            assert_eq!(transfers[1].value, dp_read_value);
+            // The SELECT writes and the RDBUFF read are not reported.
+            assert_eq!(
+                *observed.lock().unwrap(),
+                [
+                    (Ctrl::ADDRESS.into(), TransferStatus::Ok),
+                    (TARGETID::ADDRESS.into(), TransferStatus::Ok),
+                ]
+            );
         }
 
         #[test]
-        fn dp_then_ap_register_read() {
-            // When reading from the DP first, and then from the AP,
-            // we need to insert an additional read from the RDBUFF register at the end
-            // to get the result for the AP read.
+        fn no_bank_switch_with_unknown_select() {
+            let targetid_value = 0x1002_0927;
 
-            let ap_read_value = 0x123223;
-            let dp_read_value = 0xFFAABB;
-
-            let mut transfers = vec![
-                DapTransfer::read(DpRegisterAddress {
-                    address: 3,
-                    bank: None,
-                }),
-                DapTransfer::read(ApAddress::V1(4)),
-            ];
+            let mut transfers = vec![DapTransfer::read(TARGETID::ADDRESS)];
 
             let mut mock = MockJaylink::new();
+            mock.raw_dap_state.dp_select = None;
 
-            mock.add_read_response(DapAcknowledge::Ok, dp_read_value);
-            mock.add_read_response(DapAcknowledge::Ok, 0);
-            mock.add_read_response(DapAcknowledge::Ok, ap_read_value);
+            mock.add_read_response(DapAcknowledge::Ok, targetid_value);
             mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
 
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
             assert_eq!(transfers[0].status, TransferStatus::Ok);
-            assert_eq!(transfers[0].value, dp_read_value);
+            assert_eq!(transfers[0].value, targetid_value);
+            assert_eq!(mock.raw_dap_state.dp_select, None);
+        }
+    }
 
-            assert_eq!(transfers[1].status, TransferStatus::Ok);
-            assert_eq!(transfers[1].value, ap_read_value);
+    /// Test the result shifting of JTAG batches, depending on how the batch ends.
+    mod jtag_transfer_handling {
+        use super::{
+            super::{DapTransfer, JTAG_STATUS_OK, TransferStatus, perform_transfers},
+            DapAcknowledge, MockJaylink,
+        };
+        use crate::{
+            architecture::arm::{
+                ApAddress, DapError, RawDapAccess,
+                dp::{Abort, Ctrl, DpRegister, RdBuff},
+            },
+            probe::{DebugProbe, JtagAccess, WireProtocol},
+        };
+        use bitvec::field::BitField;
+
+        fn jtag_mock() -> MockJaylink {
+            let mut mock = MockJaylink::new();
+            mock.select_protocol(WireProtocol::Jtag).unwrap();
+            mock
         }
 
         #[test]
-        fn multiple_ap_read() {
-            // When reading from the AP twice, only a single additional read from the
-            // RDBUFF register is necessary.
+        fn write_register_capture_returns_the_dr() {
+            let mut mock = jtag_mock();
+            mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0x1234_5678, 0);
 
-            let ap_read_values = [1, 2];
+            // Read of RDBUFF: RnW in bit 0, A[3:2] in bits 1 and 2
+            let dr = 0b111u64.to_le_bytes();
+            let dpacc = mock.swd_settings.jtag_ir_opcodes.dpacc;
+
+            // The mock pads the response to full bytes.
+            let captured = mock.write_register_capture(dpacc, &dr[..5], 35).unwrap();
+
+            assert_eq!(captured.len(), 35);
+            assert_eq!(captured[..3].load_le::<u32>(), JTAG_STATUS_OK);
+            assert_eq!(captured[3..].load_le::<u32>(), 0x1234_5678);
+        }
+
+        #[test]
+        fn raw_jtag_dr_scan_returns_the_raw_response() {
+            let mut mock = jtag_mock();
+
+            // A response with an invalid status
+            let raw = (0xdead_beef_u64 << 3) | 0b101;
+            mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+            mock.jtag_transactions[0].result = raw;
+
+            let response = mock
+                .raw_jtag_dr_scan(&DapTransfer::read(RdBuff::ADDRESS))
+                .unwrap();
+
+            assert_eq!(response.raw, raw);
+            assert_eq!(response.value, 0xdead_beef);
+            assert_eq!(response.status, 0b101);
+            assert!(mock.jtag_transactions.is_empty());
+        }
+
+        #[test]
+        fn abort_and_rdbuff_are_exclusive() {
+            // A batch can't end in a transfer that is both, ABORT is a write and RDBUFF is a read.
+            assert!(!DapTransfer::write(Abort::ADDRESS, 0).is_rdbuff());
+            assert!(!DapTransfer::read(RdBuff::ADDRESS).is_abort());
+        }
+
+        #[test]
+        fn last_is_neither_abort_nor_rdbuff() {
+            let ap_read_value = 0x1234_5678;
+            let ctrl_read_value = 0xF000_0000;
 
             let mut transfers = vec![
                 DapTransfer::read(ApAddress::V1(4)),
-                DapTransfer::read(ApAddress::V1(4)),
+                DapTransfer::read(Ctrl::ADDRESS),
             ];
 
-            let mut mock = MockJaylink::new();
+            let mut mock = jtag_mock();
 
-            mock.add_read_response(DapAcknowledge::Ok, 0);
-            mock.add_read_response(DapAcknowledge::Ok, ap_read_values[0]);
-            mock.add_read_response(DapAcknowledge::Ok, ap_read_values[1]);
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            mock.add_jtag_response(ApAddress::V1(4), true, DapAcknowledge::Ok, 0, 0);
+            mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, ap_read_value, 0);
+            // Fake read to get the final response
+            mock.add_jtag_response(
+                RdBuff::ADDRESS,
+                true,
+                DapAcknowledge::Ok,
+                ctrl_read_value,
+                0,
+            );
+            // Check CTRL
+            mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+            mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
 
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
             assert_eq!(transfers[0].status, TransferStatus::Ok);
-            assert_eq!(transfers[0].value, ap_read_values[0]);
+            assert_eq!(transfers[0].value, ap_read_value);
 
             assert_eq!(transfers[1].status, TransferStatus::Ok);
-            assert_eq!(transfers[1].value, ap_read_values[1]);
+            assert_eq!(transfers[1].value, ctrl_read_value);
+
+            assert!(mock.jtag_transactions.is_empty());
         }
 
         #[test]
-        fn multiple_dp_read() {
-            // When reading from the DP twice, no additional reads have to be inserted.
-
-            let dp_read_values = [1, 2];
+        fn last_is_rdbuff() {
+            let ap_read_value = 0x1234_5678;
+            let rdbuff_read_value = 0xAABB_CCDD;
 
             let mut transfers = vec![
-                DapTransfer::read(Ctrl::ADDRESS),
-                DapTransfer::read(Ctrl::ADDRESS),
+                DapTransfer::read(ApAddress::V1(4)),
+                DapTransfer::read(RdBuff::ADDRESS),
             ];
 
-            let mut mock = MockJaylink::new();
+            let mut mock = jtag_mock();
 
-            mock.add_read_response(DapAcknowledge::Ok, dp_read_values[0]);
-            mock.add_read_response(DapAcknowledge::Ok, dp_read_values[1]);
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            mock.add_jtag_response(ApAddress::V1(4), true, DapAcknowledge::Ok, 0, 0);
+            mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, ap_read_value, 0);
+            // Check CTRL, no fake read is needed because the batch already ends with RDBUFF
+            mock.add_jtag_response(
+                Ctrl::ADDRESS,
+                true,
+                DapAcknowledge::Ok,
+                rdbuff_read_value,
+                0,
+            );
+            mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
 
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
             assert_eq!(transfers[0].status, TransferStatus::Ok);
-            assert_eq!(transfers[0].value, dp_read_values[0]);
+            assert_eq!(transfers[0].value, ap_read_value);
 
             assert_eq!(transfers[1].status, TransferStatus::Ok);
-            assert_eq!(transfers[1].value, dp_read_values[1]);
+            assert_eq!(transfers[1].value, rdbuff_read_value);
+
+            assert!(mock.jtag_transactions.is_empty());
         }
 
         #[test]
-        fn single_dp_register_write() {
-            let mut transfers = vec![DapTransfer::write(Abort::ADDRESS, 0x1234_5678)];
+        fn last_is_rdbuff_without_ctrl_check() {
+            let ap_read_value = 0x1234_5678;
+            let rdbuff_read_value = 0xAABB_CCDD;
 
-            let mut mock = MockJaylink::new();
+            let mut transfers = vec![
+                DapTransfer::read(ApAddress::V1(4)),
+                DapTransfer::read(RdBuff::ADDRESS),
+            ];
 
-            mock.add_write_response(
+            let mut mock = jtag_mock();
+            mock.swd_settings.verify_jtag_ctrl = false;
+
+            mock.add_jtag_response(ApAddress::V1(4), true, DapAcknowledge::Ok, 0, 0);
+            mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, ap_read_value, 0);
+            // Without the CTRL check, the fake read is needed to get the final response
+            mock.add_jtag_response(
+                RdBuff::ADDRESS,
+                true,
                 DapAcknowledge::Ok,
-                mock.swd_settings.num_idle_cycles_between_writes,
+                rdbuff_read_value,
+                0,
             );
 
-            // To verify that the write was successful, an additional read is performed.
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
-
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
-            let transfer_result = &transfers[0];
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[0].value, ap_read_value);
 
-            assert_eq!(transfer_result.status, TransferStatus::Ok);
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].value, rdbuff_read_value);
+
+            assert!(mock.jtag_transactions.is_empty());
         }
 
         #[test]
-        fn single_ap_register_write() {
-            let mut transfers = vec![DapTransfer::write(ApAddress::V1(0), 0x1234_5678)];
-
-            let mut mock = MockJaylink::new();
+        fn last_is_abort() {
+            let mut transfers = vec![
+                DapTransfer::write(ApAddress::V1(4), 0x123),
+                DapTransfer::write(Abort::ADDRESS, 0x1),
+            ];
 
-            mock.add_write_response(
-                DapAcknowledge::Ok,
-                mock.swd_settings.num_idle_cycles_between_writes,
-            );
+            let mut mock = jtag_mock();
 
-            // To verify that the write was successful, an additional read is performed.
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
-            mock.add_read_response(DapAcknowledge::Ok, 0);
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+            // Neither a fake read nor a CTRL check is performed after an ABORT
+            mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0, 0x123);
+            mock.add_jtag_abort();
 
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
-            let transfer_result = &transfers[0];
+            assert_eq!(transfers[0].status, TransferStatus::Ok);
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
 
-            assert_eq!(transfer_result.status, TransferStatus::Ok);
+            assert!(mock.jtag_transactions.is_empty());
         }
 
         #[test]
-        fn multiple_ap_register_write() {
+        fn read_followed_by_abort() {
             let mut transfers = vec![
-                DapTransfer::write(ApAddress::V1(0), 0x1234_5678),
-                DapTransfer::write(ApAddress::V1(0), 0xABABABAB),
+                DapTransfer::read(ApAddress::V1(4)),
+                DapTransfer::write(Abort::ADDRESS, 0x1),
             ];
 
-            let mut mock = MockJaylink::new();
+            let mut mock = jtag_mock();
 
-            mock.add_write_response(
-                DapAcknowledge::Ok,
-                mock.swd_settings.num_idle_cycles_between_writes,
-            );
-            mock.add_write_response(
-                DapAcknowledge::Ok,
-                mock.swd_settings.num_idle_cycles_between_writes,
+            mock.add_jtag_response(ApAddress::V1(4), true, DapAcknowledge::Ok, 0, 0);
+            mock.add_jtag_abort();
+
+            perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
+
+            // The ABORT doesn't return the response of the read, so the read has no value.
+            assert_eq!(
+                transfers[0].status,
+                TransferStatus::Failed(DapError::Protocol(WireProtocol::Jtag))
             );
+            assert_eq!(transfers[1].status, TransferStatus::Ok);
+        }
 
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_before_write_verify);
-            mock.add_read_response(DapAcknowledge::Ok, 0);
-            mock.add_idle_cycles(mock.swd_settings.idle_cycles_after_transfer);
+        #[test]
+        fn sticky_error_in_ctrl_check() {
+            // STICKYERR set
+            let ctrl = Ctrl(1 << 5);
+            assert!(ctrl.sticky_err());
+
+            let mut transfers = vec![DapTransfer::write(ApAddress::V1(4), 0x123)];
+
+            let mut mock = jtag_mock();
+
+            mock.add_jtag_response(ApAddress::V1(4), false, DapAcknowledge::Ok, 0, 0x123);
+            mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+            // Check CTRL, the value is returned by the RDBUFF read
+            mock.add_jtag_response(Ctrl::ADDRESS, true, DapAcknowledge::Ok, 0, 0);
+            mock.add_jtag_response(RdBuff::ADDRESS, true, DapAcknowledge::Ok, ctrl.0, 0);
+            // Clear the sticky error
+            mock.add_jtag_response(Ctrl::ADDRESS, false, DapAcknowledge::Ok, 0, ctrl.0);
 
             perform_transfers(&mut mock, &mut transfers).expect("Failed to perform transfer");
 
-            assert_eq!(transfers[0].status, TransferStatus::Ok);
-            assert_eq!(transfers[1].status, TransferStatus::Ok);
+            assert_eq!(
+                transfers[0].status,
+                TransferStatus::Failed(DapError::FaultResponse)
+            );
+
+            assert!(mock.jtag_transactions.is_empty());
         }
     }
 }
@@ -121,19 +121,29 @@ impl From<AbstractCommandErrorKind> for RiscvError {
 
 impl AbstractCommandErrorKind {
     fn parse(status: Abstractcs) -> Result<(), Self> {
-        let err = match status.cmderr() {
-            0 => return Ok(()),
+        match Self::from_cmderr(status.cmderr()) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Decode the `cmderr` field of the `abstractcs` register.
+    ///
+    /// Returns `None` if no error occurred. Only the lowest three bits of `cmderr`, which
+    /// hold the field, are used.
+    pub fn from_cmderr(cmderr: u32) -> Option<Self> {
+        let err = match cmderr & 0x7 {
+            0 => return None,
             1 => Self::Busy,
             2 => Self::NotSupported,
             3 => Self::Exception,
             4 => Self::HaltResume,
             5 => Self::Bus,
             6 => Self::_Reserved,
-            7 => Self::Other,
-            _ => unreachable!("cmderr is a 3 bit value, values higher than 7 should not occur."),
+            _ => Self::Other,
         };
 
-        Err(err)
+        Some(err)
     }
 }
 
@@ -158,8 +158,7 @@ impl<'probe> JtagDtm<'probe> {
                 Err(DmiOperationStatus::RequestInProgress) => {
                     // Operation still in progress, reset dmi status and try again.
                     self.clear_error_state()?;
-                    self.probe
-                        .set_idle_cycles(self.probe.idle_cycles().saturating_add(1))?;
+                    increase_idle_cycles(&mut *self.probe)?;
                 }
                 Err(e) => return Err(e.map_as_err().unwrap_err()),
             };
@@ -196,7 +195,7 @@ impl DtmAccess for JtagDtm<'_> {
         }
 
         // Setup the number of idle cycles between JTAG accesses
-        self.probe.set_idle_cycles(idle_cycles as u8)?;
+        self.probe.set_idle_cycles(idle_cycles as usize)?;
         self.state.abits = abits;
 
         Ok(())
@@ -259,8 +258,7 @@ impl DtmAccess for JtagDtm<'_> {
                         cmds.consume(e.results.len());
                         self.state.jtag_results.merge_from(e.results);
 
-                        self.probe
-                            .set_idle_cycles(self.probe.idle_cycles().saturating_add(1))?;
+                        increase_idle_cycles(&mut *self.probe)?;
                     }
                     Error::Riscv(error) => return Err(error),
                     Error::Probe(error) => return Err(error.into()),
@@ -422,8 +420,7 @@ impl<'probe> TunneledJtagDtm<'probe> {
                 Err(DmiOperationStatus::RequestInProgress) => {
                     // Operation still in progress, reset dmi status and try again.
                     self.clear_error_state()?;
-                    self.probe
-                        .set_idle_cycles(self.probe.idle_cycles().saturating_add(1))?;
+                    increase_idle_cycles(&mut *self.probe)?;
                 }
                 Err(e) => return Err(e.map_as_err().unwrap_err()),
             };
@@ -458,7 +455,7 @@ impl DtmAccess for TunneledJtagDtm<'_> {
         }
 
         // Setup the number of idle cycles between JTAG accesses
-        self.probe.set_idle_cycles(idle_cycles as u8)?;
+        self.probe.set_idle_cycles(idle_cycles as usize)?;
         self.state.abits = abits;
 
         Ok(())
@@ -518,8 +515,7 @@ impl DtmAccess for TunneledJtagDtm<'_> {
                         cmds.consume(e.results.len());
                         self.state.jtag_results.merge_from(e.results);
 
-                        self.probe
-                            .set_idle_cycles(self.probe.idle_cycles().saturating_add(1))?;
+                        increase_idle_cycles(&mut *self.probe)?;
                     }
                     Error::Riscv(error) => return Err(error),
                     Error::Probe(error) => return Err(error.into()),
@@ -571,6 +567,18 @@ impl DtmAccess for TunneledJtagDtm<'_> {
     }
 }
 
+/// Add an idle cycle after each DMI access, after the DMI reported an operation in progress.
+fn increase_idle_cycles(probe: &mut dyn JtagAccess) -> Result<(), DebugProbeError> {
+    let idle_cycles = probe.idle_cycles();
+
+    let applied = probe.set_idle_cycles(idle_cycles as usize + 1)?;
+    if applied <= idle_cycles {
+        tracing::debug!("Idle cycles are already at the maximum of {}", applied);
+    }
+
+    Ok(())
+}
+
 fn tunnel_select_command(
     tunnel_ir_id: u32,
     tunnel_ir_width: u32,
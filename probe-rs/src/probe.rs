@@ -17,7 +17,10 @@ pub mod wlink;
 
 use crate::architecture::arm::sequences::{ArmDebugSequence, DefaultArmSequence};
 use crate::architecture::arm::{ArmDebugInterface, ArmError, DapError};
-use crate::architecture::arm::{RegisterAddress, SwoAccess, communication_interface::DapProbe};
+use crate::architecture::arm::{
+    RegisterAddress, SwoAccess, TransferObserver, TransferProgressCallback,
+    communication_interface::DapProbe, dp::DebugPortVersion,
+};
 use crate::architecture::riscv::communication_interface::{RiscvError, RiscvInterfaceBuilder};
 use crate::architecture::xtensa::communication_interface::{
     XtensaCommunicationInterface, XtensaDebugInterfaceState, XtensaError,
@@ -33,10 +36,11 @@ use nusb::DeviceInfo;
 use probe_rs_target::ScanChainElement;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use std::path::{Path, PathBuf};
@@ -228,6 +232,15 @@ pub enum DebugProbeError {
 
     /// A timeout occurred during probe operation.
     Timeout,
+
+    /// The operation was cancelled after {completed} transfers.
+    Cancelled {
+        /// The number of transfers completed before the operation was cancelled.
+        ///
+        /// Only the transfers passed in by the caller are counted, not the ones the
+        /// probe inserted to select a bank or to read back a result.
+        completed: usize,
+    },
 }
 
 impl<T: ProbeError> From<T> for DebugProbeError {
@@ -1674,7 +1687,21 @@ pub(crate) trait RawSwdIo: DebugProbe {
 
     fn swd_settings(&self) -> &SwdSettings;
 
+    fn swd_settings_mut(&mut self) -> &mut SwdSettings;
+
+    /// The SWD settings a newly opened probe starts with.
+    ///
+    /// Probes override this to tune the settings for their hardware.
+    fn default_swd_settings() -> SwdSettings
+    where
+        Self: Sized,
+    {
+        SwdSettings::default()
+    }
+
     fn probe_statistics(&mut self) -> &mut ProbeStatistics;
+
+    fn raw_dap_state(&mut self) -> &mut RawDapState;
 }
 
 /// A trait for implementing low-level JTAG interface operations.
@@ -1731,7 +1758,7 @@ pub(crate) enum IoSequenceItem {
     Input,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct SwdSettings {
     /// Initial number of idle cycles between consecutive writes.
     ///
@@ -1769,6 +1796,92 @@ pub(crate) struct SwdSettings {
     /// It is recommended that at least 8 idle cycles are
     /// inserted.
     pub idle_cycles_after_transfer: usize,
+
+    /// The value SWDIO is driven to during idle cycles.
+    ///
+    /// The SWD specification has the line driven low while idle, but some
+    /// targets with clock gating quirks need it to be driven high instead.
+    pub idle_cycle_swdio: bool,
+
+    /// Maximum number of IO items sent in a single call to [`RawSwdIo::swd_io`].
+    ///
+    /// Larger batches are split into multiple calls, at transfer boundaries.
+    /// A single transfer is never split, even if it exceeds this limit.
+    /// If `None`, all transfers of a batch are sent at once.
+    pub max_io_items_per_call: Option<usize>,
+
+    /// Number of idle cycles sent once at the start of each SWD transfer batch.
+    ///
+    /// Some DPs need a few idle cycles before they recognize the start bit of a
+    /// request, e.g. after the lines were quiet for a long time.
+    pub leading_idle_cycles: usize,
+
+    /// Number of idle cycles appended to each call to [`RawSwdIo::swd_io`].
+    ///
+    /// Idle cycles after a transfer are only added where the protocol needs them, so the
+    /// lines are otherwise left in whatever state the last transfer ended in. Probes which
+    /// tristate the lines between calls can glitch the first bit of the next call; parking
+    /// the lines in the idle state avoids that. These cycles are not counted against
+    /// [`max_io_items_per_call`](Self::max_io_items_per_call).
+    pub parking_idle_cycles: usize,
+
+    /// Minimum number of line resets tried when connecting over SWD.
+    ///
+    /// Each line reset is followed by a `DPIDR` read, which has to succeed for the DP to
+    /// leave the reset state. When connecting to a debug port, line resets are repeated
+    /// until the 1 s guard time has elapsed, and at least this many times. A DP confused
+    /// by e.g. a failed multi-drop select may need more attempts than fit into the guard
+    /// time. Values below 1 are treated as 1.
+    pub line_reset_retries: usize,
+
+    /// Read CTRL/STAT at the end of each JTAG transfer batch, to check for errors.
+    ///
+    /// JTAG-DPs report FAULT as OK, so this read is the only way to notice a failed
+    /// transfer. Disabling it saves two transfers per batch, but sticky errors are then
+    /// only noticed by later batches which do check CTRL/STAT, and may be attributed to
+    /// the wrong transfer.
+    pub verify_jtag_ctrl: bool,
+
+    /// The IR opcodes used to select the DAP registers over JTAG.
+    ///
+    /// Some SoCs remap them, the default is the ARM opcode map.
+    pub jtag_ir_opcodes: JtagIrOpcodes,
+
+    /// Clear the sticky error flags in CTRL/STAT before each JTAG transfer batch.
+    ///
+    /// Sticky errors are normally only cleared after the CTRL/STAT check at the end of
+    /// a batch found one. Clearing them up front makes sure a stale flag left behind by
+    /// earlier accesses doesn't fail the batch, at the cost of reading CTRL/STAT and
+    /// writing it back with the flags set.
+    pub preclear_jtag_sticky_errors: bool,
+
+    /// Retry a DAP register write once, if it failed with a write data error.
+    ///
+    /// WDATAERR is set when the target received the write data with a parity error, in
+    /// which case the write was not performed. The error is always cleared, this only
+    /// controls whether the write is tried again.
+    pub retry_write_after_wdataerr: bool,
+
+    /// Read the IDR of each AP before its first access, and fail if it reads as zero.
+    ///
+    /// Accesses to an AP which doesn't exist are ignored by the DP, and reads return
+    /// zero, so without this check using the wrong AP address silently returns garbage.
+    /// APs found to be present are remembered until the next line reset.
+    pub validate_ap_presence: bool,
+
+    /// Warn about SWD reads returning all zeros or all ones.
+    ///
+    /// A stuck SWDIO line reads as one of these values with a valid parity bit, so a dead
+    /// connection can look like a working one returning zeros. As these values are also
+    /// common register contents, this is only useful while debugging the connection.
+    pub flag_suspicious_reads: bool,
+
+    /// Whether the probe samples SWDIO on the falling edge of the clock.
+    ///
+    /// The target changes SWDIO on the rising edge, so a probe sampling on the falling edge
+    /// (like the J-Link) sees the response one bit earlier, in place of the turnaround bit.
+    /// Probes sampling on the rising edge see the turnaround bit first.
+    pub sample_on_falling_edge: bool,
 }
 
 impl Default for SwdSettings {
@@ -1779,6 +1892,41 @@ impl Default for SwdSettings {
             max_retry_idle_cycles_after_wait: 128,
             idle_cycles_before_write_verify: 8,
             idle_cycles_after_transfer: 8,
+            idle_cycle_swdio: false,
+            max_io_items_per_call: None,
+            leading_idle_cycles: 0,
+            parking_idle_cycles: 0,
+            line_reset_retries: 1,
+            verify_jtag_ctrl: true,
+            jtag_ir_opcodes: JtagIrOpcodes::default(),
+            preclear_jtag_sticky_errors: false,
+            retry_write_after_wdataerr: false,
+            validate_ap_presence: false,
+            flag_suspicious_reads: false,
+            sample_on_falling_edge: true,
+        }
+    }
+}
+
+/// IR opcodes selecting the DAP scan chains of a JTAG-DP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-reports", derive(Serialize))]
+pub struct JtagIrOpcodes {
+    /// Opcode of the ABORT scan chain.
+    pub abort: u32,
+    /// Opcode of the DPACC scan chain, used for DP registers.
+    pub dpacc: u32,
+    /// Opcode of the APACC scan chain, used for AP registers.
+    pub apacc: u32,
+}
+
+impl Default for JtagIrOpcodes {
+    fn default() -> Self {
+        Self {
+            // ABORT is compatible with DPv0
+            abort: 0x8,
+            dpacc: 0xA,
+            apacc: 0xB,
         }
     }
 }
@@ -1796,6 +1944,8 @@ pub(crate) struct JtagDriverState {
     /// Idle cycles necessary between consecutive
     /// accesses to the DMI register
     pub jtag_idle_cycles: usize,
+    /// The value TDI is driven to during idle cycles.
+    pub jtag_idle_tdi: bool,
 }
 impl JtagDriverState {
     fn max_ir_address(&self) -> u32 {
@@ -1811,12 +1961,17 @@ impl Default for JtagDriverState {
             scan_chain: Vec::new(),
             chain_params: ChainParams::default(),
             jtag_idle_cycles: 0,
+            jtag_idle_tdi: false,
         }
     }
 }
 
-#[derive(Default, Debug)]
-pub(crate) struct ProbeStatistics {
+/// Statistics of the transfers performed by a probe, see
+/// [`RawDapAccess::take_statistics`](crate::architecture::arm::RawDapAccess::take_statistics).
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "serialize-reports", derive(Serialize))]
+#[non_exhaustive]
+pub struct ProbeStatistics {
     /// Number of protocol transfers performed.
     ///
     /// This includes repeated transfers, and transfers
@@ -1825,40 +1980,98 @@ pub(crate) struct ProbeStatistics {
     /// DP register will result in two transfers,
     /// because the read value is returned in the
     /// second transfer
-    num_transfers: usize,
+    pub num_transfers: usize,
 
     /// Number of extra transfers added to fullfil protocol
     /// requirements. Ideally as low as possible.
-    num_extra_transfers: usize,
+    pub num_extra_transfers: usize,
+
+    /// Number of RDBUFF reads added to retrieve the result of an AP read.
+    pub num_ap_read_rdbuff: usize,
+
+    /// Number of RDBUFF reads added to stall until the write buffer is empty,
+    /// before a transfer which must not stall.
+    pub num_stall_rdbuff: usize,
+
+    /// Number of RDBUFF reads added at the end of a batch, to retrieve the
+    /// result of its last transfer.
+    pub num_final_flush: usize,
 
     /// Number of calls to the probe IO function.
     ///
     /// A single call can perform multiple SWD transfers,
     /// so this number is ideally a lot lower than then
     /// number of SWD transfers.
-    num_io_calls: usize,
+    pub num_io_calls: usize,
 
     /// Number of SWD wait responses encountered.
-    num_wait_resp: usize,
+    pub num_wait_resp: usize,
 
     /// Number of SWD FAULT responses encountered.
-    num_faults: usize,
+    pub num_faults: usize,
+
+    /// Number of times the idle cycles inserted after a WAIT response
+    /// reached `SwdSettings::max_retry_idle_cycles_after_wait`.
+    pub num_wait_idle_ceiling: usize,
+
+    /// Number of times a transfer was aborted after exhausting
+    /// `SwdSettings::num_retries_after_wait`.
+    pub num_wait_retries_exhausted: usize,
+
+    /// Number of reads flagged by `SwdSettings::flag_suspicious_reads`.
+    pub num_suspicious_reads: usize,
+
+    /// Number of times most of the requested JTAG idle cycles were dropped, because the
+    /// probe doesn't support that many.
+    pub num_idle_cycles_clamped: usize,
 }
 
 impl ProbeStatistics {
-    pub fn record_extra_transfer(&mut self) {
+    /// Return the statistics accumulated so far, and reset all counters to zero.
+    pub(crate) fn take(&mut self) -> ProbeStatistics {
+        std::mem::take(self)
+    }
+
+    pub(crate) fn record_extra_transfer(&mut self) {
         self.num_extra_transfers += 1;
     }
 
-    pub fn record_transfers(&mut self, num_transfers: usize) {
+    /// Record an extra read from RDBUFF, which is also counted as an extra transfer.
+    pub(crate) fn record_rdbuff_read(&mut self, reason: RdBuffReason) {
+        self.num_extra_transfers += 1;
+
+        match reason {
+            RdBuffReason::ApRead => self.num_ap_read_rdbuff += 1,
+            RdBuffReason::Stall => self.num_stall_rdbuff += 1,
+            RdBuffReason::FinalFlush => self.num_final_flush += 1,
+        }
+    }
+
+    pub(crate) fn record_transfers(&mut self, num_transfers: usize) {
         self.num_transfers += num_transfers;
     }
 
-    pub fn report_io(&mut self) {
+    pub(crate) fn report_io(&mut self) {
         self.num_io_calls += 1;
     }
 
-    pub fn report_swd_response<T>(&mut self, response: &Result<T, DapError>) {
+    pub(crate) fn report_wait_idle_ceiling(&mut self) {
+        self.num_wait_idle_ceiling += 1;
+    }
+
+    pub(crate) fn report_wait_retries_exhausted(&mut self) {
+        self.num_wait_retries_exhausted += 1;
+    }
+
+    pub(crate) fn report_suspicious_read(&mut self) {
+        self.num_suspicious_reads += 1;
+    }
+
+    pub(crate) fn report_idle_cycles_clamped(&mut self) {
+        self.num_idle_cycles_clamped += 1;
+    }
+
+    pub(crate) fn report_swd_response<T>(&mut self, response: &Result<T, DapError>) {
         match response {
             Err(DapError::FaultResponse) => self.num_faults += 1,
             Err(DapError::WaitResponse) => self.num_wait_resp += 1,
@@ -1868,6 +2081,85 @@ impl ProbeStatistics {
     }
 }
 
+/// Why the [`RawSwdIo`] polyfill added a read from RDBUFF, see [`ProbeStatistics::record_rdbuff_read`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RdBuffReason {
+    /// The result of an AP read is needed, and the next transfer isn't an AP read.
+    ApRead,
+    /// A buffered AP write is followed by a transfer which must not stall.
+    Stall,
+    /// The result of the last transfer in a batch is needed.
+    FinalFlush,
+}
+
+/// Debug port state tracked by the [`RawSwdIo`] polyfill across transfer batches.
+pub(crate) struct RawDapState {
+    /// The last value written to the DP `SELECT` register, if known.
+    ///
+    /// This starts out as zero, matching the assumption of the ARM communication
+    /// interface, and becomes unknown when the wire is reset or a different DP is
+    /// selected. While it is unknown, no `SELECT` writes are inserted automatically.
+    pub dp_select: Option<u32>,
+
+    /// The last value written to the DP `SELECT1` register, if known.
+    ///
+    /// This is only used on ADIv6, and is tracked just like [`RawDapState::dp_select`].
+    pub dp_select1: Option<u32>,
+
+    /// The architecture version of the selected DP, decoded from the last valid `DPIDR` read.
+    ///
+    /// This determines which features the DP has, e.g. `TARGETSEL` is only available from
+    /// DPv2 on. It becomes unknown when the wire is reset, just like [`RawDapState::dp_select`].
+    pub dp_version: Option<DebugPortVersion>,
+
+    /// Called with the number of completed transfers after each call to [`RawSwdIo::swd_io`].
+    pub transfer_progress: Option<TransferProgressCallback>,
+
+    /// The APs of the selected DP known to exist, see [`SwdSettings::validate_ap_presence`].
+    pub present_aps: HashSet<u8>,
+
+    /// Set to cancel the transfer batch in progress, checked between calls to [`RawSwdIo::swd_io`].
+    pub cancel: Option<Arc<AtomicBool>>,
+
+    /// Notified of each transfer requested by the caller, once the batch containing it is done.
+    pub transfer_observer: Option<Box<dyn TransferObserver>>,
+
+    /// Set while CTRL/STAT is read to determine the reason for a FAULT response.
+    ///
+    /// A fault of that read is not handled by reading CTRL/STAT again.
+    pub handling_fault: bool,
+}
+
+impl Default for RawDapState {
+    fn default() -> Self {
+        Self {
+            dp_select: Some(0),
+            dp_select1: Some(0),
+            dp_version: None,
+            transfer_progress: None,
+            present_aps: HashSet::new(),
+            cancel: None,
+            transfer_observer: None,
+            handling_fault: false,
+        }
+    }
+}
+
+impl fmt::Debug for RawDapState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawDapState")
+            .field("dp_select", &self.dp_select)
+            .field("dp_select1", &self.dp_select1)
+            .field("dp_version", &self.dp_version)
+            .field("transfer_progress", &self.transfer_progress.is_some())
+            .field("present_aps", &self.present_aps)
+            .field("cancel", &self.cancel)
+            .field("transfer_observer", &self.transfer_observer.is_some())
+            .field("handling_fault", &self.handling_fault)
+            .finish()
+    }
+}
+
 /// Marker trait for bitbanging JTAG probes.
 ///
 /// This trait exists to control which probes implement [`JtagAccess`]. In some cases,
@@ -1913,11 +2205,24 @@ pub trait JtagAccess: DebugProbe {
     /// the idle state for several cycles between consecutive accesses to the DR register.
     ///
     /// This function configures the number of idle cycles which are inserted after each access.
-    fn set_idle_cycles(&mut self, idle_cycles: u8) -> Result<(), DebugProbeError>;
+    ///
+    /// Returns the number of idle cycles actually applied, which can be lower than requested
+    /// if the probe doesn't support as many.
+    fn set_idle_cycles(&mut self, idle_cycles: usize) -> Result<u8, DebugProbeError>;
 
     /// Return the currently configured idle cycles.
     fn idle_cycles(&self) -> u8;
 
+    /// Set the value TDI is driven to during the idle cycles configured with
+    /// [`set_idle_cycles`](JtagAccess::set_idle_cycles).
+    ///
+    /// TDI is driven low by default. Some TAPs need it to be high while idle.
+    fn set_idle_tdi(&mut self, _tdi: bool) -> Result<(), DebugProbeError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "set_idle_tdi",
+        })
+    }
+
     /// Selects the JTAG TAP to be used for communication.
     ///
     /// The index is the position of the TAP in the scan chain, which can
@@ -1953,6 +2258,33 @@ pub trait JtagAccess: DebugProbe {
         len: u32,
     ) -> Result<BitVec, DebugProbeError>;
 
+    /// Write to a JTAG register, and return exactly the `len` bits captured from TDO.
+    ///
+    /// This works like [`write_register`](JtagAccess::write_register), which some probes pad to
+    /// full bytes, and is meant for diagnostic DR scans. The first bit of the result is the
+    /// first bit shifted out of TDO, which is the least significant bit of the register.
+    ///
+    /// Fails if the probe returned fewer than `len` bits.
+    fn write_register_capture(
+        &mut self,
+        address: u32,
+        data: &[u8],
+        len: u32,
+    ) -> Result<BitVec, DebugProbeError> {
+        let mut captured = self.write_register(address, data, len)?;
+
+        if captured.len() < len as usize {
+            return Err(DebugProbeError::Other(format!(
+                "Captured only {} of {} bits shifted out of the DR",
+                captured.len(),
+                len
+            )));
+        }
+        captured.truncate(len as usize);
+
+        Ok(captured)
+    }
+
     /// Shift a value into the DR JTAG register
     ///
     /// The data shifted out of the DR register will be returned.
@@ -2060,6 +2392,30 @@ impl From<ShiftDrCommand> for JtagCommand {
     }
 }
 
+/// Describes the command with its IR address, bit length and data, e.g.
+/// `IR 0xa, 35 bits: 03 00 00 00 00`. The data bytes are in the order they are shifted out.
+impl fmt::Display for JtagCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (data, len) = match self {
+            JtagCommand::WriteRegister(cmd) => {
+                write!(f, "IR {:#x}", cmd.address)?;
+                (&cmd.data, cmd.len)
+            }
+            JtagCommand::ShiftDr(cmd) => {
+                write!(f, "DR")?;
+                (&cmd.data, cmd.len)
+            }
+        };
+
+        write!(f, ", {len} bits:")?;
+        for byte in data {
+            write!(f, " {byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
 /// Chain parameters to select a target tap within the chain.
 #[derive(Clone, Copy, Debug, Default)]
 pub(crate) struct ChainParams {
@@ -2218,6 +2574,11 @@ impl<T> CommandQueue<T> {
         self.commands.is_empty()
     }
 
+    /// Returns the scheduled commands, in the order they will be executed.
+    pub fn commands(&self) -> impl Iterator<Item = &T> {
+        self.commands.iter().map(|(_, command)| command)
+    }
+
     pub(crate) fn iter(&self) -> impl Iterator<Item = &(DeferredResultIndex, T)> {
         self.commands.iter()
     }
@@ -2344,6 +2705,87 @@ pub enum AttachMethod {
 mod test {
     use super::*;
 
+    #[test]
+    fn command_queue_describes_commands() {
+        let mut queue = CommandQueue::<JtagCommand>::new();
+        queue.schedule(JtagWriteCommand {
+            address: 0xa,
+            data: vec![0x03, 0x00, 0x00, 0x00, 0x00],
+            len: 35,
+            transform: |_, _| Ok(CommandResult::None),
+        });
+        queue.schedule(ShiftDrCommand {
+            data: vec![0xff, 0x01],
+            len: 9,
+            transform: |_, _| Ok(CommandResult::None),
+        });
+
+        let descriptions = queue
+            .commands()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            descriptions,
+            ["IR 0xa, 35 bits: 03 00 00 00 00", "DR, 9 bits: ff 01"]
+        );
+    }
+
+    #[test]
+    fn probe_statistics_take_resets_counters() {
+        let mut statistics = ProbeStatistics::default();
+
+        statistics.record_transfers(3);
+        statistics.record_extra_transfer();
+        statistics.report_io();
+        statistics.report_swd_response::<()>(&Err(DapError::WaitResponse));
+        statistics.report_swd_response::<()>(&Err(DapError::FaultResponse));
+
+        let taken = statistics.take();
+        assert_eq!(taken.num_transfers, 3);
+        assert_eq!(taken.num_extra_transfers, 1);
+        assert_eq!(taken.num_io_calls, 1);
+        assert_eq!(taken.num_wait_resp, 1);
+        assert_eq!(taken.num_faults, 1);
+
+        assert_eq!(statistics.num_transfers, 0);
+        assert_eq!(statistics.num_extra_transfers, 0);
+        assert_eq!(statistics.num_io_calls, 0);
+        assert_eq!(statistics.num_wait_resp, 0);
+        assert_eq!(statistics.num_faults, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "serialize-reports")]
+    fn probe_statistics_serialize() {
+        let mut statistics = ProbeStatistics::default();
+
+        statistics.record_transfers(3);
+        statistics.record_extra_transfer();
+        statistics.report_io();
+
+        let json = serde_json::to_value(&statistics).unwrap();
+        assert_eq!(json["num_transfers"], 3);
+        assert_eq!(json["num_extra_transfers"], 1);
+        assert_eq!(json["num_io_calls"], 1);
+        assert_eq!(json["num_faults"], 0);
+    }
+
+    #[test]
+    fn probe_statistics_rdbuff_reasons() {
+        let mut statistics = ProbeStatistics::default();
+
+        statistics.record_rdbuff_read(RdBuffReason::ApRead);
+        statistics.record_rdbuff_read(RdBuffReason::Stall);
+        statistics.record_rdbuff_read(RdBuffReason::FinalFlush);
+        statistics.record_rdbuff_read(RdBuffReason::FinalFlush);
+
+        assert_eq!(statistics.num_extra_transfers, 4);
+        assert_eq!(statistics.num_ap_read_rdbuff, 1);
+        assert_eq!(statistics.num_stall_rdbuff, 1);
+        assert_eq!(statistics.num_final_flush, 2);
+    }
+
     #[test]
     fn test_is_probe_factory() {
         let probe_info = DebugProbeInfo::new(
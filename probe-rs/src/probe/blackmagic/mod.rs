@@ -24,8 +24,8 @@ use crate::{
     probe::{
         AutoImplementJtagAccess, DebugProbe, DebugProbeError, DebugProbeInfo, DebugProbeKind,
         DebugProbeSelector, IoSequenceItem, JtagAccess, JtagDriverState, ProbeCreationError,
-        ProbeError, ProbeFactory, ProbeStatistics, RawJtagIo, RawSwdIo, SwdSettings, UsbFilters,
-        WireProtocol, blackmagic::arm::BlackMagicProbeArmDebug,
+        ProbeError, ProbeFactory, ProbeStatistics, RawDapState, RawJtagIo, RawSwdIo, SwdSettings,
+        UsbFilters, WireProtocol, blackmagic::arm::BlackMagicProbeArmDebug,
     },
 };
 use bitvec::vec::BitVec;
@@ -619,6 +619,7 @@ pub struct BlackMagicProbe {
     speed_khz: u32,
     jtag_state: JtagDriverState,
     probe_statistics: ProbeStatistics,
+    raw_dap_state: RawDapState,
     swd_settings: SwdSettings,
     in_bits: BitVec,
     swd_direction: SwdDirection,
@@ -693,8 +694,9 @@ impl BlackMagicProbe {
             speed_khz: 0,
             remote_protocol,
             jtag_state: JtagDriverState::default(),
-            swd_settings: SwdSettings::default(),
+            swd_settings: Self::default_swd_settings(),
             probe_statistics: ProbeStatistics::default(),
+            raw_dap_state: RawDapState::default(),
             in_bits: BitVec::new(),
             swd_direction: SwdDirection::Output,
         };
@@ -1223,9 +1225,17 @@ impl RawSwdIo for BlackMagicProbe {
         &self.swd_settings
     }
 
+    fn swd_settings_mut(&mut self) -> &mut SwdSettings {
+        &mut self.swd_settings
+    }
+
     fn probe_statistics(&mut self) -> &mut ProbeStatistics {
         &mut self.probe_statistics
     }
+
+    fn raw_dap_state(&mut self) -> &mut RawDapState {
+        &mut self.raw_dap_state
+    }
 }
 
 impl RawJtagIo for BlackMagicProbe {
@@ -14,7 +14,7 @@ use crate::{
 
 use super::{
     AutoImplementJtagAccess, DebugProbeError, IoSequenceItem, JtagDriverState, ProbeStatistics,
-    RawJtagIo, RawSwdIo, SwdSettings,
+    RawDapState, RawJtagIo, RawSwdIo, SwdSettings,
 };
 
 /// A factory for creating [`Ch347UsbJtag`] instances.
@@ -33,6 +33,7 @@ pub struct Ch347UsbJtag {
     device: Ch347UsbJtagDevice,
     jtag_state: JtagDriverState,
     probe_statistics: ProbeStatistics,
+    raw_dap_state: RawDapState,
     swd_settings: SwdSettings,
 }
 
@@ -48,7 +49,8 @@ impl ProbeFactory for Ch347UsbJtagFactory {
             device: ch347,
             jtag_state: JtagDriverState::default(),
             probe_statistics: ProbeStatistics::default(),
-            swd_settings: SwdSettings::default(),
+            raw_dap_state: RawDapState::default(),
+            swd_settings: Ch347UsbJtag::default_swd_settings(),
         }))
     }
 
@@ -108,9 +110,17 @@ impl RawSwdIo for Ch347UsbJtag {
         &self.swd_settings
     }
 
+    fn swd_settings_mut(&mut self) -> &mut SwdSettings {
+        &mut self.swd_settings
+    }
+
     fn probe_statistics(&mut self) -> &mut ProbeStatistics {
         &mut self.probe_statistics
     }
+
+    fn raw_dap_state(&mut self) -> &mut RawDapState {
+        &mut self.raw_dap_state
+    }
 }
 
 impl AutoImplementJtagAccess for Ch347UsbJtag {}
@@ -1003,6 +1003,11 @@ impl RawDapAccess for CmsisDap {
             .map(|_| ())
     }
 
+    fn supports_native_block(&self) -> bool {
+        // Block transfers use DAP_TransferBlock, unless a packet doesn't fit a single word.
+        transfer_block_words(self.packet_size) > 0
+    }
+
     fn raw_write_block(
         &mut self,
         address: RegisterAddress,
@@ -1010,19 +1015,13 @@ impl RawDapAccess for CmsisDap {
     ) -> Result<(), ArmError> {
         self.process_batch()?;
 
-        // the overhead for a single packet is 6 bytes
-        //
-        // [0]: HID overhead
-        // [1]: Category
-        // [2]: DAP Index
-        // [3]: Len 1
-        // [4]: Len 2
-        // [5]: Request type
-        //
-
-        let max_packet_size_words = (self.packet_size - 6) / 4;
-
-        let data_chunk_len = max_packet_size_words as usize;
+        let data_chunk_len = transfer_block_words(self.packet_size);
+        if data_chunk_len == 0 {
+            for value in values {
+                self.raw_write_register(address, *value)?;
+            }
+            return Ok(());
+        }
 
         for (i, chunk) in values.chunks(data_chunk_len).enumerate() {
             let request = TransferBlockRequest::write_request(address, Vec::from(chunk));
@@ -1054,19 +1053,13 @@ impl RawDapAccess for CmsisDap {
     ) -> Result<(), ArmError> {
         self.process_batch()?;
 
-        // the overhead for a single packet is 6 bytes
-        //
-        // [0]: HID overhead
-        // [1]: Category
-        // [2]: DAP Index
-        // [3]: Len 1
-        // [4]: Len 2
-        // [5]: Request type
-        //
-
-        let max_packet_size_words = (self.packet_size - 6) / 4;
-
-        let data_chunk_len = max_packet_size_words as usize;
+        let data_chunk_len = transfer_block_words(self.packet_size);
+        if data_chunk_len == 0 {
+            for value in values.iter_mut() {
+                *value = self.raw_read_register(address)?;
+            }
+            return Ok(());
+        }
 
         for (i, chunk) in values.chunks_mut(data_chunk_len).enumerate() {
             let request = TransferBlockRequest::read_request(address, chunk.len() as u16);
@@ -1290,6 +1283,23 @@ impl SwoAccess for CmsisDap {
     }
 }
 
+/// The number of words a `DAP_TransferBlock` request fits into a packet of `packet_size` bytes.
+///
+/// This is zero if the packet is too small for a block transfer, the block functions then
+/// fall back to single transfers.
+fn transfer_block_words(packet_size: u16) -> usize {
+    // the overhead for a single packet is 6 bytes
+    //
+    // [0]: HID overhead
+    // [1]: Category
+    // [2]: DAP Index
+    // [3]: Len 1
+    // [4]: Len 2
+    // [5]: Request type
+    //
+    usize::from(packet_size).saturating_sub(6) / 4
+}
+
 impl Drop for CmsisDap {
     fn drop(&mut self) {
         tracing::debug!("Detaching from CMSIS-DAP probe");
@@ -1314,3 +1324,21 @@ impl From<ScanChainError> for CmsisDapError {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::transfer_block_words;
+
+    #[test]
+    fn native_block_support() {
+        // Even the smallest HID report fits a block transfer.
+        assert_eq!(transfer_block_words(64), 14);
+        assert_eq!(transfer_block_words(512), 126);
+
+        // A packet without room for a single word can't use DAP_TransferBlock, so the
+        // probe doesn't report native block support, and doesn't split blocks into
+        // empty chunks.
+        assert_eq!(transfer_block_words(9), 0);
+        assert_eq!(transfer_block_words(0), 0);
+    }
+}
@@ -512,7 +512,7 @@ fn shift_dr(
 
         // We need to stay in the idle cycle a bit
         let tms = std::iter::repeat_n(false, idle_cycles);
-        let tdi = std::iter::repeat_n(false, idle_cycles);
+        let tdi = std::iter::repeat_n(protocol.state().jtag_idle_tdi, idle_cycles);
 
         protocol.shift_bits(tms, tdi, iter::repeat(false))?;
     }
@@ -668,15 +668,21 @@ impl<Probe: AutoImplementJtagAccess> JtagAccess for Probe {
         self.reset_jtag_state_machine()
     }
 
-    fn set_idle_cycles(&mut self, idle_cycles: u8) -> Result<(), DebugProbeError> {
-        self.state_mut().jtag_idle_cycles = idle_cycles as usize;
-        Ok(())
+    fn set_idle_cycles(&mut self, idle_cycles: usize) -> Result<u8, DebugProbeError> {
+        let applied = idle_cycles.min(u8::MAX as usize) as u8;
+        self.state_mut().jtag_idle_cycles = applied as usize;
+        Ok(applied)
     }
 
     fn idle_cycles(&self) -> u8 {
         self.state().jtag_idle_cycles as u8
     }
 
+    fn set_idle_tdi(&mut self, tdi: bool) -> Result<(), DebugProbeError> {
+        self.state_mut().jtag_idle_tdi = tdi;
+        Ok(())
+    }
+
     fn read_register(&mut self, address: u32, len: u32) -> Result<BitVec, DebugProbeError> {
         let data = vec![0u8; len.div_ceil(8) as usize];
 
@@ -775,6 +781,173 @@ impl<Probe: AutoImplementJtagAccess> JtagAccess for Probe {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        Error,
+        architecture::arm::{
+            DapTransfer,
+            dp::{Ctrl, DpRegister},
+        },
+        probe::{DebugProbe, JtagDriverState, JtagIrOpcodes, WireProtocol},
+    };
+
+    /// Records the TMS and TDI value of every bit shifted through the TAP.
+    #[derive(Debug, Default)]
+    struct RecordingJtag {
+        state: JtagDriverState,
+        shifted: Vec<(bool, bool)>,
+    }
+
+    impl RawJtagIo for RecordingJtag {
+        fn state_mut(&mut self) -> &mut JtagDriverState {
+            &mut self.state
+        }
+
+        fn state(&self) -> &JtagDriverState {
+            &self.state
+        }
+
+        fn shift_bit(
+            &mut self,
+            tms: bool,
+            tdi: bool,
+            _capture: bool,
+        ) -> Result<(), DebugProbeError> {
+            self.state.state.update(tms);
+            self.shifted.push((tms, tdi));
+            Ok(())
+        }
+
+        fn read_captured_bits(&mut self) -> Result<BitVec, DebugProbeError> {
+            Ok(BitVec::new())
+        }
+    }
+
+    impl AutoImplementJtagAccess for RecordingJtag {}
+
+    impl DebugProbe for RecordingJtag {
+        fn get_name(&self) -> &str {
+            todo!()
+        }
+
+        fn speed_khz(&self) -> u32 {
+            todo!()
+        }
+
+        fn set_speed(&mut self, _speed_khz: u32) -> Result<u32, DebugProbeError> {
+            todo!()
+        }
+
+        fn attach(&mut self) -> Result<(), DebugProbeError> {
+            todo!()
+        }
+
+        fn detach(&mut self) -> Result<(), Error> {
+            todo!()
+        }
+
+        fn target_reset(&mut self) -> Result<(), DebugProbeError> {
+            todo!()
+        }
+
+        fn target_reset_assert(&mut self) -> Result<(), DebugProbeError> {
+            todo!()
+        }
+
+        fn target_reset_deassert(&mut self) -> Result<(), DebugProbeError> {
+            todo!()
+        }
+
+        fn select_protocol(&mut self, _protocol: WireProtocol) -> Result<(), DebugProbeError> {
+            todo!()
+        }
+
+        fn active_protocol(&self) -> Option<WireProtocol> {
+            Some(WireProtocol::Jtag)
+        }
+
+        fn into_probe(self: Box<Self>) -> Box<dyn DebugProbe> {
+            self
+        }
+    }
+
+    #[test]
+    fn idle_cycles_use_configured_tdi_value() {
+        for idle_tdi in [false, true] {
+            let mut probe = RecordingJtag::default();
+            probe.set_idle_cycles(3).unwrap();
+            probe.set_idle_tdi(idle_tdi).unwrap();
+
+            probe.write_dr(&[0x00], 8).unwrap();
+
+            // Update-DR -> Run-Test/Idle, followed by the idle cycles
+            let idle = &probe.shifted[probe.shifted.len() - 3..];
+            assert_eq!(idle, [(false, idle_tdi); 3]);
+            assert_eq!(probe.state.state, JtagState::Idle);
+        }
+    }
+
+    /// TDI values of the bits shifted in Shift-IR and in Shift-DR, replaying the recorded
+    /// TMS values from `start`.
+    fn shifted_ir_dr(start: JtagState, shifted: &[(bool, bool)]) -> (Vec<bool>, Vec<bool>) {
+        let mut state = start;
+        let mut ir = Vec::new();
+        let mut dr = Vec::new();
+
+        for &(tms, tdi) in shifted {
+            match state {
+                JtagState::Ir(RegisterState::Shift) => ir.push(tdi),
+                JtagState::Dr(RegisterState::Shift) => dr.push(tdi),
+                _ => {}
+            }
+            state.update(tms);
+        }
+
+        (ir, dr)
+    }
+
+    #[test]
+    fn dap_transfer_pads_other_taps_with_bypass() {
+        // An ARM DP with a 4 bit IR, followed by a TAP with a 5 bit IR.
+        let chain = [
+            ScanChainElement {
+                name: Some("dap".into()),
+                ir_len: Some(4),
+            },
+            ScanChainElement {
+                name: Some("boundary".into()),
+                ir_len: Some(5),
+            },
+        ];
+        let command =
+            DapTransfer::write(Ctrl::ADDRESS, 0x5000_0000).jtag_write(&JtagIrOpcodes::default());
+
+        for (target, bypass_ir_len, bypass_dr_len) in [(0, 5, 1), (1, 4, 1)] {
+            let mut probe = RecordingJtag::default();
+            probe.state.scan_chain = chain.to_vec();
+            probe.select_target(target).unwrap();
+
+            let start = probe.state.state;
+            probe
+                .write_register(command.address, &command.data, command.len)
+                .unwrap();
+
+            let (ir, dr) = shifted_ir_dr(start, &probe.shifted);
+            let ir_len = chain[target].ir_len.unwrap() as usize;
+            assert_eq!(ir.len(), ir_len + bypass_ir_len, "IR bits of TAP {target}");
+            assert_eq!(
+                dr.len(),
+                command.len as usize + bypass_dr_len,
+                "DR bits of TAP {target}"
+            );
+
+            // The other TAP gets the all-ones BYPASS instruction.
+            let bypass_ir = match target {
+                0 => &ir[ir_len..],
+                _ => &ir[..bypass_ir_len],
+            };
+            assert!(bypass_ir.iter().all(|bit| *bit), "BYPASS of TAP {target}");
+        }
+    }
 
     const ARM_TAP: IdCode = IdCode(0x4BA00477);
     const STM_BS_TAP: IdCode = IdCode(0x06433041);
@@ -16,7 +16,8 @@ use crate::{
     probe::{
         AutoImplementJtagAccess, DebugProbe, DebugProbeError, DebugProbeInfo, DebugProbeKind,
         DebugProbeSelector, IoSequenceItem, JtagAccess, JtagDriverState, ProbeCreationError,
-        ProbeFactory, ProbeStatistics, RawJtagIo, RawSwdIo, SwdSettings, UsbFilters, WireProtocol,
+        ProbeFactory, ProbeStatistics, RawDapState, RawJtagIo, RawSwdIo, SwdSettings, UsbFilters,
+        WireProtocol,
     },
 };
 use bitvec::prelude::*;
@@ -304,8 +305,9 @@ impl ProbeFactory for FtdiProbeFactory {
         let probe = FtdiProbe {
             adapter: JtagAdapter::open(ftdi, probes.pop().unwrap())?,
             jtag_state: JtagDriverState::default(),
-            swd_settings: SwdSettings::default(),
+            swd_settings: FtdiProbe::default_swd_settings(),
             probe_statistics: ProbeStatistics::default(),
+            raw_dap_state: RawDapState::default(),
         };
         tracing::debug!("opened probe: {:?}", probe);
         Ok(Box::new(probe))
@@ -322,6 +324,7 @@ pub struct FtdiProbe {
     adapter: JtagAdapter,
     jtag_state: JtagDriverState,
     probe_statistics: ProbeStatistics,
+    raw_dap_state: RawDapState,
     swd_settings: SwdSettings,
 }
 
@@ -451,9 +454,17 @@ impl RawSwdIo for FtdiProbe {
         &self.swd_settings
     }
 
+    fn swd_settings_mut(&mut self) -> &mut SwdSettings {
+        &mut self.swd_settings
+    }
+
     fn probe_statistics(&mut self) -> &mut ProbeStatistics {
         &mut self.probe_statistics
     }
+
+    fn raw_dap_state(&mut self) -> &mut RawDapState {
+        &mut self.raw_dap_state
+    }
 }
 
 impl RawJtagIo for FtdiProbe {
@@ -45,14 +45,17 @@ use crate::{
     },
     probe::{
         DebugProbe, DebugProbeError, DebugProbeInfo, DebugProbeKind, DebugProbeSelector,
-        IoSequenceItem, JtagDriverState, ProbeFactory, ProbeStatistics, RawJtagIo, RawSwdIo,
-        SwdSettings, UsbFilters, WireProtocol,
+        IoSequenceItem, JtagDriverState, ProbeFactory, ProbeStatistics, RawDapState, RawJtagIo,
+        RawSwdIo, SwdSettings, UsbFilters, WireProtocol,
     },
 };
 
 const SWO_BUFFER_SIZE: u16 = 128;
 const TIMEOUT_DEFAULT: Duration = Duration::from_millis(500);
 
+// Maximum number of bits in a single SWD I/O command
+const MAX_SWD_IO_BITS: usize = 65535;
+
 /// Factory to create [`JLink`] probes.
 #[derive(Debug)]
 pub struct JLinkFactory;
@@ -188,8 +191,9 @@ impl ProbeFactory for JLinkFactory {
 
             swo_config: None,
             speed_khz: 0, // default is unknown
-            swd_settings: SwdSettings::default(),
+            swd_settings: JLink::default_swd_settings(),
             probe_statistics: ProbeStatistics::default(),
+            raw_dap_state: RawDapState::default(),
             jtag_state: JtagDriverState::default(),
 
             jtag_tms_bits: vec![],
@@ -397,6 +401,7 @@ pub struct JLink {
     max_mem_block_size: usize,
 
     probe_statistics: ProbeStatistics,
+    raw_dap_state: RawDapState,
     swd_settings: SwdSettings,
 }
 
@@ -800,7 +805,7 @@ impl JLink {
         const COMMAND_OVERHEAD: usize = 4;
 
         let max_bits = (self.max_mem_block_size - COMMAND_OVERHEAD) / 2 * 8;
-        let max_bits = std::cmp::min(max_bits, 65535);
+        let max_bits = std::cmp::min(max_bits, MAX_SWD_IO_BITS);
 
         let swdio_chunks = swdio.into_iter().chunks(max_bits);
 
@@ -1206,9 +1211,26 @@ impl RawSwdIo for JLink {
         &self.swd_settings
     }
 
+    fn swd_settings_mut(&mut self) -> &mut SwdSettings {
+        &mut self.swd_settings
+    }
+
+    fn default_swd_settings() -> SwdSettings {
+        // Longer sequences are split into several commands anyway, so split them at
+        // transfer boundaries instead.
+        SwdSettings {
+            max_io_items_per_call: Some(MAX_SWD_IO_BITS),
+            ..SwdSettings::default()
+        }
+    }
+
     fn probe_statistics(&mut self) -> &mut ProbeStatistics {
         &mut self.probe_statistics
     }
+
+    fn raw_dap_state(&mut self) -> &mut RawDapState {
+        &mut self.raw_dap_state
+    }
 }
 
 impl RawJtagIo for JLink {
@@ -1434,6 +1456,16 @@ fn is_jlink(info: &DeviceInfo) -> bool {
 
 #[cfg(test)]
 mod test {
+    use crate::probe::RawSwdIo;
+
+    #[test]
+    fn swd_settings_are_tuned() {
+        let settings = super::JLink::default_swd_settings();
+
+        assert_eq!(settings.max_io_items_per_call, Some(super::MAX_SWD_IO_BITS));
+        assert!(settings.sample_on_falling_edge);
+    }
+
     #[test]
     fn jlink_pid_cmsisdap() {
         // J-Link devices configured as CMSIS-DAP should not be detected as J-Link devices.
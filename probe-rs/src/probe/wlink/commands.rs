@@ -1,6 +1,9 @@
 //! WCH-LinkRV commands
 
-use super::{DMI_OP_NOP, DMI_OP_READ, DMI_OP_WRITE, RiscvChip, WchLinkError, WchLinkVariant};
+use super::{
+    DMI_OP_BUSY, DMI_OP_NOP, DMI_OP_READ, DMI_OP_WRITE, DmRegister, RiscvChip, WchLinkError,
+    WchLinkVariant,
+};
 
 /// Only part of commands are implemented
 #[repr(u8)]
@@ -15,6 +18,8 @@ pub enum CommandId {
     SetSpeed = 0x0c,
     /// DMI operations
     DmiOp = 0x08,
+    /// Flash programming
+    Program = 0x02,
 }
 
 pub(crate) trait WchLinkCommand {
@@ -23,6 +28,13 @@ pub(crate) trait WchLinkCommand {
 
     fn payload(&self) -> Vec<u8>;
 
+    /// Whether executing the command twice has the same effect as executing it once.
+    ///
+    /// Commands which don't are not retried by default, see [`RetryPolicy`](super::RetryPolicy).
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
     /// Convert the request to bytes, which can be sent to the probe.
     /// Returns the amount of bytes written to the buffer.
     fn to_bytes(&self, buffer: &mut [u8]) -> Result<usize, super::WchLinkError> {
@@ -53,6 +65,10 @@ pub(crate) trait WchLinkCommandResponse {
     where
         Self: Sized,
     {
+        if resp.len() < 3 {
+            return Err(WchLinkError::InvalidPayload);
+        }
+
         if resp[0] == 0x81 {
             let reason = resp[1];
             let len = resp[2] as usize;
@@ -111,7 +127,7 @@ impl WchLinkCommandResponse for GetProbeInfoResponse {
         Ok(GetProbeInfoResponse {
             major_version: bytes[0],
             minor_version: bytes[1],
-            variant: WchLinkVariant::try_from_u8(bytes[2])?,
+            variant: WchLinkVariant::from_u8(bytes[2]),
         })
     }
 }
@@ -129,10 +145,14 @@ impl WchLinkCommand for GetProbeInfo {
 #[derive(Debug)]
 pub struct AttachChip;
 
+/// Information about the target chip, returned when attaching to it.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct AttachChipResponse {
+    /// Chip family
     pub chip_family: RiscvChip,
+    /// Raw chip family code, as reported by the probe
     pub riscvchip: u8,
+    /// Chip id to identify the target chip variant
     pub chip_id: u32,
 }
 
@@ -172,6 +192,25 @@ impl WchLinkCommand for DetachChip {
     }
 }
 
+/// Switch off one of the target power supplies of the probe
+#[derive(Debug, Clone, Copy)]
+pub enum PowerOff {
+    Supply3v3,
+    Supply5v,
+}
+
+impl WchLinkCommand for PowerOff {
+    const COMMAND_ID: CommandId = CommandId::Control;
+    type Response = ();
+
+    fn payload(&self) -> Vec<u8> {
+        match self {
+            PowerOff::Supply3v3 => vec![0x0a],
+            PowerOff::Supply5v => vec![0x0c],
+        }
+    }
+}
+
 /// Set speed
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -250,12 +289,14 @@ pub struct DmiOpResponse {
 
 impl WchLinkCommandResponse for DmiOpResponse {
     fn from_payload(bytes: &[u8]) -> Result<Self, WchLinkError> {
-        if bytes.len() != 6 {
+        let [addr, d0, d1, d2, d3, op] = *bytes else {
+            return Err(WchLinkError::InvalidPayload);
+        };
+        // The op field is only two bits wide.
+        if op > DMI_OP_BUSY {
             return Err(WchLinkError::InvalidPayload);
         }
-        let addr = bytes[0];
-        let op = bytes[5];
-        let data = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        let data = u32::from_be_bytes([d0, d1, d2, d3]);
         Ok(DmiOpResponse { addr, data, op })
     }
 }
@@ -282,6 +323,15 @@ impl WchLinkCommand for DmiOp {
         }
         bytes
     }
+
+    /// Writes to `dmcontrol` and `command` start an action in the debug module.
+    fn is_idempotent(&self) -> bool {
+        !matches!(
+            self,
+            DmiOp::Write { addr, .. }
+                if *addr == u8::from(DmRegister::DmControl) || *addr == u8::from(DmRegister::Command)
+        )
+    }
 }
 
 /// Reset the chip
@@ -294,6 +344,10 @@ impl WchLinkCommand for ResetTarget {
     fn payload(&self) -> Vec<u8> {
         vec![0x01]
     }
+
+    fn is_idempotent(&self) -> bool {
+        false
+    }
 }
 
 /// Check flash protection status
@@ -318,4 +372,100 @@ impl WchLinkCommand for UnprotectFlash {
     fn payload(&self) -> Vec<u8> {
         vec![0x02]
     }
+
+    fn is_idempotent(&self) -> bool {
+        false
+    }
+}
+
+/// Erase the whole code flash of the chip
+#[derive(Debug)]
+pub struct EraseChip;
+impl WchLinkCommand for EraseChip {
+    const COMMAND_ID: CommandId = CommandId::Program;
+    type Response = ();
+
+    fn payload(&self) -> Vec<u8> {
+        vec![0x01]
+    }
+
+    fn is_idempotent(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dmi_op_response() {
+        let resp = DmiOp::read(0x11)
+            .parse_response(&[0x82, 0x08, 0x06, 0x11, 0x00, 0x00, 0x03, 0x82, 0x00])
+            .unwrap();
+
+        assert_eq!((resp.addr, resp.data, resp.op), (0x11, 0x0382, 0));
+    }
+
+    #[test]
+    fn malformed_dmi_op_response() {
+        let responses: [&[u8]; 4] = [
+            // Too short
+            &[0x82, 0x08, 0x03, 0x11, 0x00, 0x00],
+            // Shorter than the header
+            &[0x82, 0x08],
+            // Length doesn't match the payload
+            &[0x82, 0x08, 0x06, 0x11, 0x00],
+            // Invalid op
+            &[0x82, 0x08, 0x06, 0x11, 0x00, 0x00, 0x00, 0x00, 0x04],
+        ];
+
+        for response in responses {
+            let result = DmiOp::read(0x11).parse_response(response);
+            assert!(
+                matches!(result, Err(WchLinkError::InvalidPayload)),
+                "Unexpected result for {response:x?}: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn erase_chip_request() {
+        let mut buffer = [0u8; 64];
+        let len = EraseChip.to_bytes(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..len], &[0x81, 0x02, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn attach_chip_response() {
+        let resp = AttachChip
+            .parse_response(&[0x82, 0x0d, 0x05, 0x09, 0x00, 0x31, 0x05, 0x10])
+            .unwrap();
+
+        assert_eq!(
+            resp,
+            AttachChipResponse {
+                chip_family: RiscvChip::CH32V003,
+                riscvchip: 0x09,
+                chip_id: 0x0031_0510,
+            }
+        );
+    }
+
+    #[test]
+    fn attach_chip_response_invalid_length() {
+        let resp = AttachChip.parse_response(&[
+            0x82, 0x0d, 0x09, 0x06, 0x30, 0x70, 0x05, 0x18, 0xab, 0xcd, 0xef, 0x01,
+        ]);
+
+        assert!(matches!(resp, Err(WchLinkError::InvalidPayload)));
+    }
+
+    #[test]
+    fn attach_chip_response_unknown_chip() {
+        let resp = AttachChip.parse_response(&[0x82, 0x0d, 0x05, 0xee, 0x00, 0x00, 0x00, 0x00]);
+
+        assert!(matches!(resp, Err(WchLinkError::UnknownChip(0xee))));
+    }
 }
@@ -3,6 +3,7 @@
 //! The protocol is mostly undocumented, and is changing between firmware versions.
 //! For more details see: <https://github.com/ch32-rs/wlink>
 
+use std::collections::VecDeque;
 use std::fmt;
 use std::time::Duration;
 
@@ -10,10 +11,11 @@ use bitvec::{bitvec, field::BitField, order::Lsb0, vec::BitVec, view::BitView};
 use nusb::DeviceInfo;
 use probe_rs_target::ScanChainElement;
 
-use self::{commands::Speed, usb_interface::WchLinkUsbDevice};
+use self::commands::Speed;
 use super::JtagAccess;
 use crate::{
     architecture::riscv::{
+        Abstractcs, Dmstatus,
         communication_interface::{RiscvError, RiscvInterfaceBuilder},
         dtm::jtag_dtm::JtagDtmBuilder,
     },
@@ -24,8 +26,19 @@ use crate::{
 };
 
 mod commands;
+mod tcp;
 mod usb_interface;
 
+pub use self::{
+    commands::AttachChipResponse,
+    tcp::serve_tcp,
+    usb_interface::{RetryPolicy, WchLinkUsbDevice},
+};
+use self::{
+    tcp::WchLinkTcpClient,
+    usb_interface::{WchLinkTransport, is_disconnected},
+};
+
 const VENDOR_ID: u16 = 0x1a86;
 const PRODUCT_ID: u16 = 0x8010;
 
@@ -37,12 +50,78 @@ const DMI_OP_MASK: u128 = 0b11; // 2 bits
 const DMI_OP_NOP: u8 = 0;
 const DMI_OP_READ: u8 = 1;
 const DMI_OP_WRITE: u8 = 2;
+const DMI_OP_BUSY: u8 = 3;
 
 const REG_BYPASS_ADDRESS: u8 = 0x1f;
 const REG_IDCODE_ADDRESS: u8 = 0x01;
 const REG_DTMCS_ADDRESS: u8 = 0x10;
 const REG_DMI_ADDRESS: u8 = 0x11;
 
+// Timeout for each USB transfer of `WchLink::detach_nonblocking`
+const DETACH_NONBLOCKING_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Debug module registers accessed by the probe driver itself.
+///
+/// See: RISC-V Debug Specification, 3.14 Debug Module Registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum DmRegister {
+    Data0 = 0x04,
+    Data1 = 0x05,
+    DmControl = 0x10,
+    DmStatus = 0x11,
+    HaltSum1 = 0x13,
+    AbstractCs = 0x16,
+    Command = 0x17,
+    HaltSum2 = 0x34,
+    HaltSum3 = 0x35,
+    HaltSum0 = 0x40,
+}
+
+impl From<DmRegister> for u8 {
+    fn from(register: DmRegister) -> u8 {
+        register as u8
+    }
+}
+
+const DMCONTROL_DMACTIVE: u32 = 1 << 0;
+const DMCONTROL_HALTREQ: u32 = 1 << 31;
+const DMCONTROL_RESUMEREQ: u32 = 1 << 30;
+const DMCONTROL_ACKHAVERESET: u32 = 1 << 28;
+const DMCONTROL_SETRESETHALTREQ: u32 = 1 << 3;
+const DMCONTROL_CLRRESETHALTREQ: u32 = 1 << 2;
+const DMCONTROL_NDMRESET: u32 = 1 << 1;
+
+const DMSTATUS_ALLHALTED: u32 = 1 << 9;
+const DMSTATUS_ALLRESUMEACK: u32 = 1 << 17;
+
+const ABSTRACTCS_BUSY: u32 = 1 << 12;
+
+// Access Memory abstract command (cmdtype 2) with 32 bit accesses
+const COMMAND_ACCESS_MEMORY_32: u32 = (2 << 24) | (2 << 20);
+const COMMAND_ACCESS_MEMORY_WRITE: u32 = 1 << 16;
+
+// Address of the user option bytes of the CH32 chips, each followed by its complement.
+// See: CH32V003, CH32V103 and CH32V20x/V30x reference manuals, User Option Bytes
+const CH32_OPTION_BYTES_ADDRESS: u32 = 0x1FFF_F800;
+
+// How long to wait for an abstract command to complete
+const ABSTRACT_COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+
+// How long to wait for the hart to acknowledge a halt or resume request
+const HALT_RESUME_TIMEOUT: Duration = Duration::from_millis(100);
+
+// How long the probe may take to respond to a full chip erase
+const ERASE_CHIP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long the target is given to settle after a reset, by default
+const DEFAULT_RESET_SETTLE_DELAY: Duration = Duration::from_millis(10);
+
+// How long a disconnected probe is given to enumerate again
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Delay between attempts to open a disconnected probe again
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 const DTMCS_DMIRESET_MASK: u32 = 1 << 16;
 const DTMCS_DMIHARDRESET_MASK: u32 = 1 << 17;
 
@@ -58,6 +137,10 @@ pub enum WchLinkVariant {
     SCh32v203 = 3,
     /// WCH-LinkW-CH32V208, a wirelessed version
     WCh32v208 = 5,
+    /// A variant not known to probe-rs, with the variant byte reported by the probe.
+    ///
+    /// It is assumed to behave like the full featured WCH-LinkE.
+    Unknown(u8),
 }
 
 impl fmt::Display for WchLinkVariant {
@@ -67,22 +150,84 @@ impl fmt::Display for WchLinkVariant {
             WchLinkVariant::ECh32v305 => write!(f, "WCH-LinkE-CH32V305"),
             WchLinkVariant::SCh32v203 => write!(f, "WCH-LinkS-CH32V203"),
             WchLinkVariant::WCh32v208 => write!(f, "WCH-LinkW-CH32V208"),
+            WchLinkVariant::Unknown(variant) => write!(f, "WCH-Link-Unknown({variant:#04x})"),
         }
     }
 }
 
 impl WchLinkVariant {
-    fn try_from_u8(value: u8) -> Result<Self, WchLinkError> {
+    fn from_u8(value: u8) -> Self {
         match value {
-            1 => Ok(Self::Ch549),
-            2 | 0x12 => Ok(Self::ECh32v305),
-            3 => Ok(Self::SCh32v203),
-            5 | 0x85 => Ok(Self::WCh32v208),
-            _ => Err(WchLinkError::UnknownDevice),
+            1 => Self::Ch549,
+            2 | 0x12 => Self::ECh32v305,
+            3 => Self::SCh32v203,
+            5 | 0x85 => Self::WCh32v208,
+            _ => Self::Unknown(value),
+        }
+    }
+
+    /// Only the WCH-LinkE can switch the target power supply.
+    fn support_power_control(&self) -> bool {
+        matches!(self, WchLinkVariant::ECh32v305 | WchLinkVariant::Unknown(_))
+    }
+}
+
+/// A firmware version of a WCH-Link, as `(major, minor)`.
+type FirmwareVersion = (u8, u8);
+
+/// Oldest firmware supported at all, needed to attach to a target.
+const MIN_FIRMWARE_ATTACH: FirmwareVersion = (2, 7);
+
+/// The features available on a WCH-Link.
+///
+/// Apart from the minimum firmware version needed to attach, no per-feature firmware
+/// requirements are known, so the features only depend on the probe variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WchLinkCapabilities {
+    /// Attaching to a target. Without this, the probe can't be used.
+    pub attach: bool,
+    /// Switching the power supply of the target.
+    pub power_control: bool,
+}
+
+impl WchLinkCapabilities {
+    /// Determine the capabilities of a WCH-Link `variant` running firmware `major.minor`.
+    pub fn new(variant: WchLinkVariant, major: u8, minor: u8) -> Self {
+        let firmware = (major, minor);
+
+        Self {
+            attach: firmware >= MIN_FIRMWARE_ATTACH,
+            power_control: variant.support_power_control(),
         }
     }
 }
 
+/// A DMI operation sent to the probe, see [`WchLink::dmi_history`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmiRecord {
+    /// The requested operation, 0 for a nop, 1 for a read and 2 for a write
+    pub op: u8,
+    /// The requested DMI address
+    pub addr: u8,
+    /// The data written by a write, or the data returned for other operations
+    pub data: u32,
+    /// The `op` field of the response, 0 if the operation succeeded
+    pub result: u8,
+}
+
+/// The result of [`WchLink::self_test`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfTestReport {
+    /// The probe variant
+    pub variant: WchLinkVariant,
+    /// The firmware version, as `(major, minor)`
+    pub firmware_version: (u8, u8),
+    /// The features available with this variant and firmware
+    pub capabilities: WchLinkCapabilities,
+    /// Whether the probe responded to a basic USB command
+    pub usb_ok: bool,
+}
+
 /// Currently supported RISC-V chip series/families. The IP core name is "Qingke".
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
@@ -136,6 +281,7 @@ impl RiscvChip {
         }
     }
 
+    /// Whether the flash read protection status can be read, and cleared, on this chip.
     fn support_flash_protect(&self) -> bool {
         matches!(
             self,
@@ -149,6 +295,14 @@ impl RiscvChip {
                 | RiscvChip::CH641
         )
     }
+
+    /// Whether the user option bytes are found at the address documented for the CH32 chips.
+    fn support_option_bytes(&self) -> bool {
+        matches!(
+            self,
+            RiscvChip::CH32V003 | RiscvChip::CH32V103 | RiscvChip::CH32V20X | RiscvChip::CH32V30X
+        )
+    }
 }
 
 /// Factory for creating [`WchLink`] probes.
@@ -164,20 +318,7 @@ impl std::fmt::Display for WchLinkFactory {
 impl ProbeFactory for WchLinkFactory {
     fn open(&self, selector: &DebugProbeSelector) -> Result<Box<dyn DebugProbe>, DebugProbeError> {
         let device = WchLinkUsbDevice::new_from_selector(selector)?;
-        let mut wlink = WchLink {
-            device,
-            name: "WCH-Link".into(),
-            variant: WchLinkVariant::Ch549,
-            v_major: 0,
-            v_minor: 0,
-            chip_id: 0,
-            chip_family: RiscvChip::CH32V103,
-            last_dmi_read: None,
-            speed: Speed::default(),
-            idle_cycles: 0,
-        };
-
-        wlink.init()?;
+        let wlink = WchLink::from_device(device)?;
 
         Ok(Box::new(wlink))
     }
@@ -189,19 +330,51 @@ impl ProbeFactory for WchLinkFactory {
 
 /// A WCH-Link device (mod:RV)
 pub struct WchLink {
-    device: WchLinkUsbDevice,
+    device: Box<dyn WchLinkTransport>,
     name: String,
     variant: WchLinkVariant,
     v_major: u8,
     v_minor: u8,
     /// Chip family
     chip_family: RiscvChip,
+    /// Chip family the attached chip is expected to belong to, if any
+    expected_chip_family: Option<RiscvChip>,
+    /// Fail to attach to a chip of another family than the expected one
+    strict_family_check: bool,
     /// Chip id to identify the target chip variant
     chip_id: u32,
     // Hack to support NOP after READ
     last_dmi_read: Option<(u8, u32, u8)>,
+    /// Response to the last DMI write
+    last_dmi_write: Option<(u8, u32, u8)>,
+    /// Response to the last DMI operation of any kind
+    last_dmi_op: Option<(u8, u32, u8)>,
+    /// The most recent DMI operations, oldest first
+    dmi_history: VecDeque<DmiRecord>,
+    /// Number of DMI operations kept in `dmi_history`, 0 if it is disabled
+    dmi_history_capacity: usize,
     speed: commands::Speed,
     idle_cycles: u8,
+    /// How each command sent to the probe is retried
+    retry_policy: RetryPolicy,
+    /// Flash protection was already cleared during the current attach
+    flash_unprotected: bool,
+    /// Clear the flash protection when needed
+    auto_unprotect: bool,
+    /// Clear the flash protection when attaching, instead of only before erasing
+    unprotect_on_attach: bool,
+    /// Switch off the target power supplies when detaching
+    power_off_on_detach: bool,
+    /// Time to wait after a target reset, before the target is accessed again
+    reset_settle_delay: Duration,
+    /// Flash protection status of the attached chip, if known
+    flash_protected: Option<bool>,
+    /// Response to the last successful attach
+    attached_chip: Option<AttachChipResponse>,
+    /// USB serial number of the probe, if known
+    serial_number: Option<String>,
+    /// The probe is being opened again after it was disconnected
+    reconnecting: bool,
 }
 
 impl fmt::Debug for WchLink {
@@ -212,25 +385,103 @@ impl fmt::Debug for WchLink {
             .field("v_major", &self.v_major)
             .field("v_minor", &self.v_minor)
             .field("chip_family", &self.chip_family)
+            .field("expected_chip_family", &self.expected_chip_family)
+            .field("strict_family_check", &self.strict_family_check)
             .field("chip_id", &self.chip_id)
             .field("last_dmi_read", &self.last_dmi_read)
+            .field("last_dmi_write", &self.last_dmi_write)
+            .field("last_dmi_op", &self.last_dmi_op)
+            .field("dmi_history", &self.dmi_history)
+            .field("dmi_history_capacity", &self.dmi_history_capacity)
             .field("speed", &self.speed)
             .field("idle_cycles", &self.idle_cycles)
+            .field("retry_policy", &self.retry_policy)
+            .field("flash_unprotected", &self.flash_unprotected)
+            .field("auto_unprotect", &self.auto_unprotect)
+            .field("unprotect_on_attach", &self.unprotect_on_attach)
+            .field("power_off_on_detach", &self.power_off_on_detach)
+            .field("reset_settle_delay", &self.reset_settle_delay)
+            .field("flash_protected", &self.flash_protected)
+            .field("attached_chip", &self.attached_chip)
+            .field("serial_number", &self.serial_number)
+            .field("reconnecting", &self.reconnecting)
             .finish()
     }
 }
 
 impl WchLink {
+    /// Create a probe from an already opened WCH-Link USB device.
+    ///
+    /// This reads the probe information and checks the firmware version,
+    /// but doesn't attach to the target.
+    pub fn from_device(device: WchLinkUsbDevice) -> Result<Self, DebugProbeError> {
+        let serial_number = device.serial_number().map(str::to_string);
+
+        let mut wlink = Self::from_transport(Box::new(device))?;
+        wlink.serial_number = serial_number;
+
+        Ok(wlink)
+    }
+
+    /// Connect to a WCH-Link made available by [`serve_tcp`] on another machine.
+    ///
+    /// This reads the probe information and checks the firmware version,
+    /// but doesn't attach to the target.
+    pub fn connect(addr: impl std::net::ToSocketAddrs) -> Result<Self, DebugProbeError> {
+        Self::from_transport(Box::new(WchLinkTcpClient::connect(addr)?))
+    }
+
+    fn from_transport(device: Box<dyn WchLinkTransport>) -> Result<Self, DebugProbeError> {
+        let mut wlink = WchLink {
+            device,
+            name: "WCH-Link".into(),
+            variant: WchLinkVariant::Ch549,
+            v_major: 0,
+            v_minor: 0,
+            chip_id: 0,
+            chip_family: RiscvChip::CH32V103,
+            expected_chip_family: None,
+            strict_family_check: false,
+            last_dmi_read: None,
+            last_dmi_write: None,
+            last_dmi_op: None,
+            dmi_history: VecDeque::new(),
+            dmi_history_capacity: 0,
+            speed: Speed::default(),
+            idle_cycles: 0,
+            retry_policy: RetryPolicy::default(),
+            flash_unprotected: false,
+            auto_unprotect: true,
+            unprotect_on_attach: true,
+            power_off_on_detach: false,
+            reset_settle_delay: DEFAULT_RESET_SETTLE_DELAY,
+            flash_protected: None,
+            attached_chip: None,
+            serial_number: None,
+            reconnecting: false,
+        };
+
+        wlink.init()?;
+
+        Ok(wlink)
+    }
+
     fn get_probe_info(&mut self) -> Result<(), DebugProbeError> {
-        let probe_info = self.device.send_command(commands::GetProbeInfo)?;
+        let probe_info = self.send_command(commands::GetProbeInfo)?;
         self.v_major = probe_info.major_version;
         self.v_minor = probe_info.minor_version;
+        self.variant = probe_info.variant;
 
-        if self.v_major != 0x02 && self.v_minor < 0x07 {
-            return Err(WchLinkError::UnsupportedFirmwareVersion("2.7").into());
+        if let WchLinkVariant::Unknown(variant) = self.variant {
+            tracing::warn!(
+                "Unknown WCH-Link variant {:#04x}, assuming it behaves like a WCH-LinkE",
+                variant
+            );
         }
 
-        self.variant = probe_info.variant;
+        if !self.capabilities().attach {
+            return Err(WchLinkError::UnsupportedFirmwareVersion("2.7").into());
+        }
 
         Ok(())
     }
@@ -239,6 +490,7 @@ impl WchLink {
         // first stage of wlink_init
         tracing::debug!("Initializing WCH-Link...");
 
+        self.device.drain()?;
         self.get_probe_info()?;
 
         // this is the official version format. So "v31" is actually a 2.11
@@ -252,180 +504,892 @@ impl WchLink {
             version_code
         );
 
-        if self.v_major != 0x02 && self.v_minor < 0x7 {
-            return Err(WchLinkError::UnsupportedFirmwareVersion("2.7").into());
-        }
         self.name = format!("{} v{}.{}", self.variant, self.v_major, self.v_minor);
 
         Ok(())
     }
 
-    fn dmi_op_read(&mut self, addr: u8) -> Result<(u8, u32, u8), DebugProbeError> {
-        let resp = self.device.send_command(commands::DmiOp::read(addr))?;
+    /// Returns the features supported by the connected probe, see [`WchLinkCapabilities`].
+    pub fn capabilities(&self) -> WchLinkCapabilities {
+        WchLinkCapabilities::new(self.variant, self.v_major, self.v_minor)
+    }
 
-        Ok((resp.addr, resp.data, resp.op))
+    /// Set how commands sent to the probe and the attach sequence are retried, see
+    /// [`RetryPolicy`].
+    ///
+    /// By default, each command is sent only once, and attaching is not retried.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
     }
 
-    fn dmi_op_write(&mut self, addr: u8, data: u32) -> Result<(u8, u32, u8), DebugProbeError> {
-        let resp = self
+    /// Returns how commands sent to the probe and the attach sequence are retried.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    fn send_command<C: commands::WchLinkCommand + fmt::Debug>(
+        &mut self,
+        cmd: C,
+    ) -> Result<C::Response, DebugProbeError> {
+        match self
             .device
-            .send_command(commands::DmiOp::write(addr, data))?;
+            .send_command_with_policy(&cmd, &self.retry_policy)
+        {
+            // The probe may have been reset together with the target, so open it again
+            // once, and repeat the command after the probe is back in its previous state.
+            // Commands which can't safely be executed twice are only repeated if the retry
+            // policy allows it, since the probe may have executed them before it went away.
+            Err(e) if is_disconnected(&e) && !self.reconnecting => {
+                self.reconnect()?;
+
+                if cmd.is_idempotent() || self.retry_policy.retry_non_idempotent {
+                    self.device
+                        .send_command_with_policy(&cmd, &self.retry_policy)
+                } else {
+                    tracing::warn!("Not repeating {:?} after reconnecting", cmd);
+                    Err(e)
+                }
+            }
+            result => result,
+        }
+    }
 
-        Ok((resp.addr, resp.data, resp.op))
+    /// Open the probe again after it was disconnected, and initialize it again.
+    ///
+    /// The probe is polled until it has enumerated again, and if a chip was attached,
+    /// it is attached again with the current speed, see [`WchLink::reattach`].
+    fn reconnect(&mut self) -> Result<(), DebugProbeError> {
+        tracing::warn!("WCH-Link was disconnected, reopening it");
+
+        let start = std::time::Instant::now();
+        loop {
+            match self.device.reopen() {
+                Ok(()) => break,
+                Err(e @ DebugProbeError::CommandNotSupportedByProbe { .. }) => return Err(e),
+                Err(e) if start.elapsed() < RECONNECT_TIMEOUT => {
+                    tracing::debug!("WCH-Link not available yet: {}", e);
+                    std::thread::sleep(RECONNECT_POLL_INTERVAL);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let attached = self.attached_chip.is_some();
+
+        self.reconnecting = true;
+        let result = self
+            .init()
+            .and_then(|()| if attached { self.reattach() } else { Ok(()) });
+        self.reconnecting = false;
+
+        result
     }
 
-    fn dmi_op_nop(&mut self) -> Result<(u8, u32, u8), DebugProbeError> {
-        let resp = self.device.send_command(commands::DmiOp::nop())?;
+    /// Set the chip family the attached chip is expected to belong to.
+    ///
+    /// If the probe detects a chip of another family when attaching, a warning is logged,
+    /// or attaching fails with [strict family checks](Self::set_strict_family_check).
+    /// Passing `None` disables the check.
+    pub fn set_expected_chip_family(&mut self, family: Option<RiscvChip>) {
+        self.expected_chip_family = family;
+    }
 
-        Ok((resp.addr, resp.data, resp.op))
+    /// Set whether attaching fails if the detected chip family is not the
+    /// [expected one](Self::set_expected_chip_family).
+    ///
+    /// This is disabled by default, so a mismatch is only logged as a warning.
+    pub fn set_strict_family_check(&mut self, strict: bool) {
+        self.strict_family_check = strict;
     }
-}
 
-impl DebugProbe for WchLink {
-    fn get_name(&self) -> &str {
-        &self.name
+    /// Set whether the flash read protection of the target is cleared when attaching.
+    ///
+    /// This is enabled by default. When disabled, the protection status is still
+    /// checked and can be queried with [`WchLink::flash_protected`].
+    pub fn set_auto_unprotect(&mut self, auto_unprotect: bool) {
+        self.auto_unprotect = auto_unprotect;
     }
 
-    fn speed_khz(&self) -> u32 {
-        self.speed.to_khz()
+    /// Set whether the flash read protection is cleared when attaching, if
+    /// [auto-unprotect](Self::set_auto_unprotect) is enabled.
+    ///
+    /// This is enabled by default. Sessions which only read from the target can disable
+    /// it, so attaching only checks the protection status. The protection is then cleared
+    /// by [`WchLink::erase_chip`] if necessary.
+    pub fn set_unprotect_on_attach(&mut self, unprotect_on_attach: bool) {
+        self.unprotect_on_attach = unprotect_on_attach;
     }
 
-    fn set_speed(&mut self, speed_khz: u32) -> Result<u32, DebugProbeError> {
-        let speed =
-            Speed::from_khz(speed_khz).ok_or(DebugProbeError::UnsupportedSpeed(speed_khz))?;
-        self.speed = speed;
-        self.device
-            .send_command(commands::SetSpeed(self.chip_family, speed))?;
-        Ok(speed.to_khz())
+    /// Set whether the 3.3 V and 5 V target power supplies are switched off when detaching.
+    ///
+    /// This is disabled by default, so the target keeps running after the session. It has
+    /// no effect on probes without [power control](WchLinkCapabilities::power_control).
+    pub fn set_power_off_on_detach(&mut self, power_off_on_detach: bool) {
+        self.power_off_on_detach = power_off_on_detach;
     }
 
-    /// Attach chip
-    fn attach(&mut self) -> Result<(), DebugProbeError> {
-        // second stage of wlink_init
-        tracing::trace!("attach to target chip");
+    /// Set how long to wait after resetting the target, before returning from
+    /// [`target_reset`](DebugProbe::target_reset) and
+    /// [`target_reset_deassert`](DebugProbe::target_reset_deassert).
+    ///
+    /// Boards with a slow power supply or clock may need more time than the default of
+    /// 10 ms before the target can be attached again.
+    pub fn set_reset_settle_delay(&mut self, delay: Duration) {
+        self.reset_settle_delay = delay;
+    }
 
-        self.device
-            .send_command(commands::SetSpeed(self.chip_family, self.speed))?;
+    /// Returns how long to wait after resetting the target.
+    pub fn reset_settle_delay(&self) -> Duration {
+        self.reset_settle_delay
+    }
+
+    /// Wait for the target to settle after a reset.
+    fn settle_after_reset(&self) {
+        if !self.reset_settle_delay.is_zero() {
+            tracing::debug!(
+                "Waiting {:?} for the target to settle",
+                self.reset_settle_delay
+            );
+            std::thread::sleep(self.reset_settle_delay);
+        }
+    }
+
+    /// Returns whether the flash of the attached chip is read-protected.
+    ///
+    /// Returns `None` if the chip has not been attached yet, or does not support flash protection.
+    pub fn flash_protected(&self) -> Option<bool> {
+        self.flash_protected
+    }
+
+    /// Returns the information reported by the probe when attaching to the target chip.
+    ///
+    /// Returns `None` if the probe isn't attached.
+    pub fn attached_chip(&self) -> Option<AttachChipResponse> {
+        self.attached_chip
+    }
+
+    /// Returns the id of the attached chip.
+    pub fn chip_id(&self) -> u32 {
+        self.chip_id
+    }
+
+    /// Compare the `detected` chip family with the expected one, if any.
+    fn check_chip_family(&self, detected: RiscvChip) -> Result<(), DebugProbeError> {
+        let Some(expected) = self.expected_chip_family else {
+            return Ok(());
+        };
+
+        if detected == expected {
+            return Ok(());
+        }
+
+        if self.strict_family_check {
+            return Err(WchLinkError::ChipFamilyMismatch { expected, detected }.into());
+        }
+
+        tracing::warn!(
+            "Attached to a {:?} chip, but expected a {:?} chip",
+            detected,
+            expected
+        );
+
+        Ok(())
+    }
 
-        let resp = self.device.send_command(commands::AttachChip)?;
+    /// Attach to the chip again, after the probe was reconnected.
+    ///
+    /// Unlike [`WchLink::attach`], this only restores the speed and the attach. The flash
+    /// protection was already handled by the first attach, and a reset of the hart is left
+    /// to the caller to acknowledge, as after any other reset.
+    fn reattach(&mut self) -> Result<(), DebugProbeError> {
+        self.send_command(commands::SetSpeed(self.chip_family, self.speed))?;
+
+        let resp = self.send_command(commands::AttachChip)?;
+        if resp.chip_family != self.chip_family {
+            return Err(WchLinkError::ChipFamilyMismatch {
+                expected: self.chip_family,
+                detected: resp.chip_family,
+            }
+            .into());
+        }
+
+        self.chip_id = resp.chip_id;
+        self.attached_chip = Some(resp);
+
+        Ok(())
+    }
+
+    fn attach_once(&mut self) -> Result<(), DebugProbeError> {
+        self.send_command(commands::SetSpeed(self.chip_family, self.speed))?;
 
+        let resp = self.send_command(commands::AttachChip)?;
+
+        if let Err(e) = self.check_chip_family(resp.chip_family) {
+            // Don't leave the probe attached to the wrong chip.
+            if let Err(detach_error) = self.send_command(commands::DetachChip) {
+                tracing::warn!(
+                    "Failed to detach from the unexpected chip: {}",
+                    detach_error
+                );
+            }
+            return Err(e);
+        }
         self.chip_family = resp.chip_family;
 
         tracing::info!("attached riscv chip {:?}", self.chip_family);
 
         self.chip_id = resp.chip_id;
+        self.attached_chip = Some(resp);
+
+        let dmstatus = self.check_target_connected()?;
+        self.ack_reset_on_attach(dmstatus)?;
+
+        if self.chip_family.support_flash_protect() && !self.flash_unprotected {
+            let status = self.send_command(commands::CheckFlashProtection)?;
+            let protected = is_flash_protected(status);
+            self.flash_protected = Some(protected);
+
+            if protected {
+                tracing::info!("Flash of the target chip is read-protected");
+            }
 
-        if self.chip_family.support_flash_protect() {
-            self.device.send_command(commands::CheckFlashProtection)?;
-            self.device.send_command(commands::UnprotectFlash)?;
+            if self.auto_unprotect && self.unprotect_on_attach {
+                self.send_command(commands::UnprotectFlash)?;
+                self.flash_unprotected = true;
+                self.flash_protected = Some(false);
+            }
         }
 
         Ok(())
     }
 
-    fn detach(&mut self) -> Result<(), crate::Error> {
-        tracing::trace!("Detach chip");
-        self.device.send_command(commands::DetachChip)?;
+    /// Check that a target responds on the wire, by reading `dmstatus`.
+    ///
+    /// The probe also reports a successful attach if nothing is connected, and DMI reads
+    /// then return the level of the floating data line. The DTM registers are not
+    /// accessible through the probe, so `dmstatus` is the first register which can be read.
+    fn check_target_connected(&mut self) -> Result<Dmstatus, DebugProbeError> {
+        let (_, dmstatus, _) = self.dmi_op_read(DmRegister::DmStatus)?;
 
-        Ok(())
+        if dmstatus == 0 || dmstatus == u32::MAX {
+            return Err(WchLinkError::NoTargetDetected(dmstatus).into());
+        }
+
+        Ok(Dmstatus::from(dmstatus))
     }
 
-    fn target_reset(&mut self) -> Result<(), DebugProbeError> {
-        self.device.send_command(commands::ResetTarget)?;
+    /// Acknowledge a reset of the hart caused by attaching.
+    ///
+    /// Some chips assert `ndmreset` on the first `dmcontrol` write of the attach
+    /// sequence, which also resets the debug module.
+    fn ack_reset_on_attach(&mut self, dmstatus: Dmstatus) -> Result<(), DebugProbeError> {
+        if !dmstatus.anyhavereset() {
+            return Ok(());
+        }
+
+        tracing::debug!("Target was reset during attach: {:?}", dmstatus);
+        self.dmi_op_write(
+            DmRegister::DmControl,
+            DMCONTROL_ACKHAVERESET | DMCONTROL_DMACTIVE,
+        )?;
+
+        let (_, dmcontrol, _) = self.dmi_op_read(DmRegister::DmControl)?;
+        if dmcontrol & DMCONTROL_DMACTIVE == 0 {
+            return Err(WchLinkError::DebugModuleInactive.into());
+        }
+
         Ok(())
     }
 
-    fn target_reset_assert(&mut self) -> Result<(), DebugProbeError> {
-        tracing::info!("target reset assert");
-        self.device
-            .send_command(commands::DmiOp::write(0x10, 0x80000001))?;
-        Ok(())
+    /// Detach from the target chip, without blocking on an unresponsive probe.
+    ///
+    /// The detach command is sent with a short timeout, and errors are logged instead
+    /// of being returned. This is useful when tearing down many probes at once.
+    pub fn detach_nonblocking(&mut self) {
+        tracing::trace!("Detach chip (non-blocking)");
+        self.attached_chip = None;
+
+        if let Err(e) = self
+            .device
+            .send_command_with_timeout(&commands::DetachChip, DETACH_NONBLOCKING_TIMEOUT)
+        {
+            tracing::warn!("Failed to detach chip: {e}");
+        }
     }
 
-    fn target_reset_deassert(&mut self) -> Result<(), DebugProbeError> {
-        tracing::info!("target reset deassert");
+    /// Erase the whole code flash of the attached chip.
+    ///
+    /// This is faster than erasing the flash sector by sector. Read-protected flash is
+    /// unprotected first if [auto-unprotect](Self::set_auto_unprotect) is enabled,
+    /// otherwise a `FlashProtected` error is returned.
+    ///
+    /// The probe only responds to the erase command once the erase is done, which is what
+    /// the [wlink](https://github.com/ch32-rs/wlink) utility relies on as well, so the
+    /// response is waited for with a timeout long enough for the whole erase.
+    pub fn erase_chip(&mut self) -> Result<(), DebugProbeError> {
+        if self.attached_chip.is_none() {
+            return Err(DebugProbeError::NotAttached);
+        }
+
+        if !self.chip_family.support_flash_protect() {
+            return Err(WchLinkError::UnsupportedOperation.into());
+        }
+
+        if self.flash_protected == Some(true) {
+            if !self.auto_unprotect {
+                return Err(WchLinkError::FlashProtected.into());
+            }
+
+            self.send_command(commands::UnprotectFlash)?;
+            self.flash_unprotected = true;
+            self.flash_protected = Some(false);
+        }
+
+        tracing::info!("Erasing the flash of {:?}", self.chip_family);
         self.device
-            .send_command(commands::DmiOp::write(0x10, 0x00000001))?;
-        Ok(())
+            .send_command_with_timeout(&commands::EraseChip, ERASE_CHIP_TIMEOUT)
+            .inspect_err(|e| tracing::warn!("Erasing the chip failed: {}", e))
     }
 
-    fn select_protocol(&mut self, protocol: WireProtocol) -> Result<(), DebugProbeError> {
-        // Assume Jtag, as it is the only supported protocol for riscv
-        match protocol {
-            WireProtocol::Jtag => Ok(()),
-            _ => Err(DebugProbeError::UnsupportedProtocol(protocol)),
-        }
+    /// Returns the unique ID of the probe itself, taken from its USB serial number.
+    ///
+    /// Returns an `UnsupportedOperation` error if the serial number is not a hexadecimal
+    /// number, or if there is none, which is always the case for probes
+    /// [connected over TCP](Self::connect).
+    pub fn probe_uid(&self) -> Result<u64, DebugProbeError> {
+        self.serial_number
+            .as_deref()
+            .and_then(|serial| u64::from_str_radix(serial, 16).ok())
+            .ok_or_else(|| WchLinkError::UnsupportedOperation.into())
     }
 
-    fn active_protocol(&self) -> Option<WireProtocol> {
-        Some(WireProtocol::Jtag)
+    /// Check that the probe works, without needing an attached target.
+    ///
+    /// Failing commands are logged and reflected in the report, instead of returning an error.
+    pub fn self_test(&mut self) -> SelfTestReport {
+        let usb_ok = match self.send_command(commands::GetProbeInfo) {
+            Ok(_) => true,
+            Err(e) => {
+                tracing::warn!("Self-test: failed to read probe info: {}", e);
+                false
+            }
+        };
+
+        SelfTestReport {
+            variant: self.variant,
+            firmware_version: (self.v_major, self.v_minor),
+            capabilities: self.capabilities(),
+            usb_ok,
+        }
     }
 
-    fn into_probe(self: Box<Self>) -> Box<dyn DebugProbe> {
-        self
+    /// Returns the `(addr, data, op)` response of the most recent DMI operation sent to the probe.
+    ///
+    /// This is useful to debug misbehaving DMI sequences.
+    pub fn last_dmi_op(&self) -> Option<(u8, u32, u8)> {
+        self.last_dmi_op
     }
 
-    fn has_riscv_interface(&self) -> bool {
-        true
+    /// Returns the `(addr, data, op)` response of the most recent DMI write sent to the probe.
+    pub fn last_dmi_write(&self) -> Option<(u8, u32, u8)> {
+        self.last_dmi_write
     }
 
-    fn try_get_riscv_interface_builder<'probe>(
-        &'probe mut self,
-    ) -> Result<Box<dyn RiscvInterfaceBuilder<'probe> + 'probe>, RiscvError> {
-        Ok(Box::new(JtagDtmBuilder::new(self)))
+    /// Set how many of the most recent DMI operations are kept in the
+    /// [DMI history](Self::dmi_history).
+    ///
+    /// The history is disabled by default, setting the capacity to 0 disables it again.
+    /// Reducing the capacity discards the oldest operations.
+    pub fn set_dmi_history_capacity(&mut self, capacity: usize) {
+        self.dmi_history_capacity = capacity;
+
+        let excess = self.dmi_history.len().saturating_sub(capacity);
+        self.dmi_history.drain(..excess);
     }
-}
 
-/// Wrap WCH-Link's USB based DMI access as a fake JtagAccess
-impl JtagAccess for WchLink {
-    fn set_scan_chain(&mut self, _scan_chain: &[ScanChainElement]) -> Result<(), DebugProbeError> {
-        Ok(())
+    /// Returns the most recent DMI operations sent to the probe, oldest first.
+    ///
+    /// This is empty unless the history is enabled with
+    /// [`WchLink::set_dmi_history_capacity`]. Operations which failed to reach the probe
+    /// are not recorded.
+    pub fn dmi_history(&self) -> impl Iterator<Item = &DmiRecord> {
+        self.dmi_history.iter()
     }
 
-    fn scan_chain(&mut self) -> Result<&[ScanChainElement], DebugProbeError> {
-        Ok(&[])
+    fn record_dmi_op(&mut self, op: u8, addr: u8, data: u32, result: u8) {
+        if self.dmi_history_capacity == 0 {
+            return;
+        }
+
+        if self.dmi_history.len() >= self.dmi_history_capacity {
+            self.dmi_history.pop_front();
+        }
+
+        self.dmi_history.push_back(DmiRecord {
+            op,
+            addr,
+            data,
+            result,
+        });
     }
 
-    fn tap_reset(&mut self) -> Result<(), DebugProbeError> {
-        Ok(())
+    /// Halt hart 0, without attaching to the target.
+    ///
+    /// `dmcontrol` is written with `hartsel` set to 0, so any other selected hart is
+    /// deselected. Returns a `Timeout` error if the hart doesn't report being halted.
+    pub fn halt(&mut self) -> Result<(), DebugProbeError> {
+        self.dmi_op_write(
+            DmRegister::DmControl,
+            DMCONTROL_HALTREQ | DMCONTROL_DMACTIVE,
+        )?;
+        let result = self.wait_for_dmstatus(DMSTATUS_ALLHALTED);
+
+        // Clear the halt request again, also if the hart didn't halt.
+        self.dmi_op_write(DmRegister::DmControl, DMCONTROL_DMACTIVE)?;
+
+        result
     }
 
-    fn read_register(&mut self, address: u32, len: u32) -> Result<BitVec, DebugProbeError> {
-        tracing::debug!("read register 0x{:08x}", address);
-        assert_eq!(len, 32);
+    /// Resume hart 0, selecting it in `dmcontrol` like [`WchLink::halt`].
+    ///
+    /// Returns a `Timeout` error if the hart doesn't acknowledge the resume request.
+    pub fn resume(&mut self) -> Result<(), DebugProbeError> {
+        self.dmi_op_write(
+            DmRegister::DmControl,
+            DMCONTROL_RESUMEREQ | DMCONTROL_DMACTIVE,
+        )?;
+        self.wait_for_dmstatus(DMSTATUS_ALLRESUMEACK)
+    }
 
-        let mut ret = bitvec![0; len as usize];
-        match address as u8 {
-            REG_IDCODE_ADDRESS => {
-                // using hard coded idcode 0x00000001, the same as WCH's openocd fork
-                tracing::debug!("using hard coded idcode 0x00000001");
-                ret[0..8].store_le::<u8>(0x1);
-                Ok(ret)
-            }
-            REG_DTMCS_ADDRESS => {
-                // See: RISC-V Debug Specification, 6.1.4
-                // 0x71: abits=7, version=1(1.0)
-                ret[0..8].store_le::<u8>(0x71);
-                Ok(ret)
-            }
-            REG_BYPASS_ADDRESS => Ok(bitvec![0; 4]),
-            _ => panic!("unknown read register address {address:08x}"),
+    /// Reset the target, and halt hart 0 before it executes any instruction.
+    ///
+    /// The reset is done with `dmcontrol.ndmreset`, while the halt-on-reset request is set.
+    /// Returns an `UnsupportedOperation` error if the debug module doesn't support halt-on-reset,
+    /// and a `Timeout` error if the hart doesn't report being halted after the reset.
+    pub fn reset_and_halt(&mut self) -> Result<(), DebugProbeError> {
+        if !self.read_dmstatus()?.hasresethaltreq() {
+            return Err(WchLinkError::UnsupportedOperation.into());
         }
+
+        self.dmi_op_write(
+            DmRegister::DmControl,
+            DMCONTROL_SETRESETHALTREQ | DMCONTROL_DMACTIVE,
+        )?;
+        self.dmi_op_write(
+            DmRegister::DmControl,
+            DMCONTROL_NDMRESET | DMCONTROL_DMACTIVE,
+        )?;
+        self.dmi_op_write(DmRegister::DmControl, DMCONTROL_DMACTIVE)?;
+
+        let result = self.wait_for_dmstatus(DMSTATUS_ALLHALTED);
+
+        // Clear the halt-on-reset request again, so that later resets let the hart run.
+        self.dmi_op_write(
+            DmRegister::DmControl,
+            DMCONTROL_CLRRESETHALTREQ | DMCONTROL_ACKHAVERESET | DMCONTROL_DMACTIVE,
+        )?;
+
+        result
     }
 
-    fn set_idle_cycles(&mut self, idle_cycles: u8) -> Result<(), DebugProbeError> {
-        self.idle_cycles = idle_cycles;
-        Ok(())
+    /// Read the `dmstatus` register of the debug module.
+    ///
+    /// This is a direct DMI read, which doesn't go through the emulated JTAG registers.
+    pub fn read_dmstatus(&mut self) -> Result<Dmstatus, DebugProbeError> {
+        let (_, dmstatus, _) = self.dmi_op_read(DmRegister::DmStatus)?;
+
+        Ok(Dmstatus::from(dmstatus))
     }
 
-    fn idle_cycles(&self) -> u8 {
-        self.idle_cycles
+    /// Read the `haltsum<level>` register of the debug module.
+    ///
+    /// Each bit of the returned mask is set if the corresponding hart, or group of harts,
+    /// is halted. Bit `i` of `haltsum0` is for hart `hartsel[19:5] * 32 + i`, and each bit
+    /// of `haltsum1` summarizes 32 harts, `haltsum2` 1024 harts and `haltsum3` 32768 harts.
+    pub fn read_haltsum(&mut self, level: u8) -> Result<u32, DebugProbeError> {
+        let register = match level {
+            0 => DmRegister::HaltSum0,
+            1 => DmRegister::HaltSum1,
+            2 => DmRegister::HaltSum2,
+            3 => DmRegister::HaltSum3,
+            _ => return Err(WchLinkError::InvalidHaltsumLevel(level).into()),
+        };
+
+        let (_, haltsum, _) = self.dmi_op_read(register)?;
+
+        Ok(haltsum)
     }
 
-    fn write_register(
-        &mut self,
-        address: u32,
-        data: &[u8],
+    /// Read a word of target memory at `address`.
+    ///
+    /// This is done with the Access Memory abstract command, which is set up through DMI.
+    /// An error left behind by an earlier abstract command is cleared first. If the abstract
+    /// command fails, its error is cleared again before it is returned.
+    pub fn read_memory_word(&mut self, address: u32) -> Result<u32, DebugProbeError> {
+        self.access_memory_word(address, 0, false)
+    }
+
+    /// Write a word of target memory at `address`, like [`WchLink::read_memory_word`].
+    pub fn write_memory_word(&mut self, address: u32, value: u32) -> Result<(), DebugProbeError> {
+        self.access_memory_word(address, value, true)?;
+
+        Ok(())
+    }
+
+    /// Read the user option bytes of the attached chip.
+    ///
+    /// The 16 bytes start with `RDPR`, `USER`, `DATA0`, `DATA1` and `WRPR0` to `WRPR3`, each
+    /// followed by its complement. They are read from target memory with
+    /// [`WchLink::read_memory_word`]. Returns an `UnsupportedOperation` error on chips which
+    /// don't have them at the address of the CH32V003, CH32V103 and CH32V20x/V30x.
+    pub fn read_option_bytes(&mut self) -> Result<[u8; 16], DebugProbeError> {
+        if self.attached_chip.is_none() {
+            return Err(DebugProbeError::NotAttached);
+        }
+
+        if !self.chip_family.support_option_bytes() {
+            return Err(WchLinkError::UnsupportedOperation.into());
+        }
+
+        let mut option_bytes = [0; 16];
+        for (offset, chunk) in (0..).step_by(4).zip(option_bytes.chunks_exact_mut(4)) {
+            let word = self.read_memory_word(CH32_OPTION_BYTES_ADDRESS + offset)?;
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+
+        Ok(option_bytes)
+    }
+
+    fn access_memory_word(
+        &mut self,
+        address: u32,
+        value: u32,
+        write: bool,
+    ) -> Result<u32, DebugProbeError> {
+        // Writes to `command` are ignored while a command is running, or while the error of
+        // an earlier command is still set.
+        let cmderr = self.wait_for_abstract_command()?;
+        if cmderr != 0 {
+            tracing::debug!("Clearing the error {cmderr} of an earlier abstract command");
+            self.clear_cmderr()?;
+        }
+
+        let command = if write {
+            COMMAND_ACCESS_MEMORY_32 | COMMAND_ACCESS_MEMORY_WRITE
+        } else {
+            COMMAND_ACCESS_MEMORY_32
+        };
+        if write {
+            self.dmi_op_write_checked(DmRegister::Data0, value)?;
+        }
+        self.dmi_op_write_checked(DmRegister::Data1, address)?;
+        self.dmi_op_write_checked(DmRegister::Command, command)?;
+
+        let cmderr = self.wait_for_abstract_command()?;
+        if cmderr != 0 {
+            self.clear_cmderr()?;
+            return Err(WchLinkError::AbstractCommand(cmderr).into());
+        }
+
+        if write {
+            return Ok(value);
+        }
+
+        self.dmi_op_read_checked(DmRegister::Data0)
+    }
+
+    /// Wait until no abstract command is running, and return the `cmderr` field of `abstractcs`.
+    fn wait_for_abstract_command(&mut self) -> Result<u32, DebugProbeError> {
+        let abstractcs = self.poll_dmi(
+            DmRegister::AbstractCs.into(),
+            ABSTRACTCS_BUSY,
+            0,
+            ABSTRACT_COMMAND_TIMEOUT,
+        )?;
+
+        Ok(Abstractcs::from(abstractcs).cmderr())
+    }
+
+    /// Read the `abstractcs` register of the debug module.
+    ///
+    /// The error of the last abstract command can be decoded from it with
+    /// [`AbstractCommandErrorKind::from_cmderr`](crate::architecture::riscv::communication_interface::AbstractCommandErrorKind::from_cmderr).
+    pub fn read_abstractcs(&mut self) -> Result<Abstractcs, DebugProbeError> {
+        let (_, abstractcs, _) = self.dmi_op_read(DmRegister::AbstractCs)?;
+
+        Ok(Abstractcs::from(abstractcs))
+    }
+
+    /// Clear the error of the last abstract command in `abstractcs`.
+    ///
+    /// No further abstract commands are executed while it is set.
+    pub fn clear_cmderr(&mut self) -> Result<(), DebugProbeError> {
+        let mut abstractcs = Abstractcs::from(0);
+        abstractcs.set_cmderr(0x7);
+
+        self.dmi_op_write_checked(DmRegister::AbstractCs, abstractcs.into())
+    }
+
+    /// Read the DMI register at `addr` until `value & mask == expected`, and return the last value read.
+    ///
+    /// Returns a `Timeout` error if the register doesn't reach the expected value within `timeout`.
+    /// Whether that is worth a warning is up to the caller.
+    pub fn poll_dmi(
+        &mut self,
+        addr: u8,
+        mask: u32,
+        expected: u32,
+        timeout: Duration,
+    ) -> Result<u32, DebugProbeError> {
+        let start = std::time::Instant::now();
+
+        loop {
+            let value = self.dmi_op_read_checked(addr)?;
+            if value & mask == expected {
+                return Ok(value);
+            }
+
+            if start.elapsed() > timeout {
+                tracing::debug!(
+                    "Timeout waiting for DMI register {addr:#04x} to be {expected:#x} (mask {mask:#x}), last value {value:#x}"
+                );
+                return Err(DebugProbeError::Timeout);
+            }
+
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Poll `dmstatus` until all bits of `mask` are set.
+    fn wait_for_dmstatus(&mut self, mask: u32) -> Result<(), DebugProbeError> {
+        self.poll_dmi(DmRegister::DmStatus.into(), mask, mask, HALT_RESUME_TIMEOUT)?;
+
+        Ok(())
+    }
+
+    fn dmi_op_read(&mut self, addr: impl Into<u8>) -> Result<(u8, u32, u8), DebugProbeError> {
+        let addr = addr.into();
+        let resp = self.send_command(commands::DmiOp::read(addr))?;
+
+        self.last_dmi_op = Some((resp.addr, resp.data, resp.op));
+        self.record_dmi_op(DMI_OP_READ, addr, resp.data, resp.op);
+
+        Ok((resp.addr, resp.data, resp.op))
+    }
+
+    fn dmi_op_write(
+        &mut self,
+        addr: impl Into<u8>,
+        data: u32,
+    ) -> Result<(u8, u32, u8), DebugProbeError> {
+        let addr = addr.into();
+        let resp = self.send_command(commands::DmiOp::write(addr, data))?;
+
+        self.last_dmi_write = Some((resp.addr, resp.data, resp.op));
+        self.last_dmi_op = self.last_dmi_write;
+        self.record_dmi_op(DMI_OP_WRITE, addr, data, resp.op);
+
+        Ok((resp.addr, resp.data, resp.op))
+    }
+
+    /// Perform a DMI read, which fails if the debug module reports the read as failed or busy.
+    fn dmi_op_read_checked(&mut self, addr: impl Into<u8>) -> Result<u32, DebugProbeError> {
+        let addr = addr.into();
+        let (_, data, op) = self.dmi_op_read(addr)?;
+        if op != DMI_OP_NOP {
+            return Err(WchLinkError::DmiOpFailed { addr, op }.into());
+        }
+
+        Ok(data)
+    }
+
+    /// Perform a DMI write, which fails if the debug module reports the write as failed or busy.
+    fn dmi_op_write_checked(
+        &mut self,
+        addr: impl Into<u8>,
+        data: u32,
+    ) -> Result<(), DebugProbeError> {
+        let addr = addr.into();
+        let (_, _, op) = self.dmi_op_write(addr, data)?;
+        if op != DMI_OP_NOP {
+            return Err(WchLinkError::DmiOpFailed { addr, op }.into());
+        }
+
+        Ok(())
+    }
+
+    fn dmi_op_nop(&mut self) -> Result<(u8, u32, u8), DebugProbeError> {
+        let resp = self.send_command(commands::DmiOp::nop())?;
+
+        self.last_dmi_op = Some((resp.addr, resp.data, resp.op));
+        self.record_dmi_op(DMI_OP_NOP, 0, resp.data, resp.op);
+
+        Ok((resp.addr, resp.data, resp.op))
+    }
+}
+
+impl DebugProbe for WchLink {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn speed_khz(&self) -> u32 {
+        self.speed.to_khz()
+    }
+
+    fn set_speed(&mut self, speed_khz: u32) -> Result<u32, DebugProbeError> {
+        let speed =
+            Speed::from_khz(speed_khz).ok_or(DebugProbeError::UnsupportedSpeed(speed_khz))?;
+        self.speed = speed;
+        self.send_command(commands::SetSpeed(self.chip_family, speed))?;
+        Ok(speed.to_khz())
+    }
+
+    /// Attach chip
+    fn attach(&mut self) -> Result<(), DebugProbeError> {
+        // second stage of wlink_init
+        tracing::trace!("attach to target chip");
+
+        let chip_family = self.chip_family;
+        self.flash_unprotected = false;
+        self.flash_protected = None;
+        self.attached_chip = None;
+
+        let attempts = self.retry_policy.attach_attempts;
+        let mut attempt = 1;
+
+        loop {
+            match self.attach_once() {
+                Err(e) if attempt < attempts && !is_chip_family_mismatch(&e) => {
+                    tracing::debug!("Attach failed (attempt {attempt}/{attempts}), retrying: {e}");
+                    attempt += 1;
+
+                    // Start over with the chip family the attach started with.
+                    self.chip_family = chip_family;
+                    std::thread::sleep(self.retry_policy.backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn detach(&mut self) -> Result<(), crate::Error> {
+        tracing::trace!("Detach chip");
+        self.send_command(commands::DetachChip)?;
+        self.attached_chip = None;
+
+        if self.power_off_on_detach {
+            if self.capabilities().power_control {
+                tracing::debug!("Switching off target power");
+                self.send_command(commands::PowerOff::Supply3v3)?;
+                self.send_command(commands::PowerOff::Supply5v)?;
+            } else {
+                tracing::warn!("{} can't switch off the target power", self.name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn target_reset(&mut self) -> Result<(), DebugProbeError> {
+        self.send_command(commands::ResetTarget)?;
+        self.settle_after_reset();
+        Ok(())
+    }
+
+    fn target_reset_assert(&mut self) -> Result<(), DebugProbeError> {
+        tracing::info!("target reset assert");
+        self.dmi_op_write(
+            DmRegister::DmControl,
+            DMCONTROL_HALTREQ | DMCONTROL_DMACTIVE,
+        )?;
+        Ok(())
+    }
+
+    fn target_reset_deassert(&mut self) -> Result<(), DebugProbeError> {
+        tracing::info!("target reset deassert");
+        self.dmi_op_write(DmRegister::DmControl, DMCONTROL_DMACTIVE)?;
+        self.settle_after_reset();
+        Ok(())
+    }
+
+    fn select_protocol(&mut self, protocol: WireProtocol) -> Result<(), DebugProbeError> {
+        // Assume Jtag, as it is the only supported protocol for riscv
+        match protocol {
+            WireProtocol::Jtag => Ok(()),
+            _ => Err(DebugProbeError::UnsupportedProtocol(protocol)),
+        }
+    }
+
+    fn active_protocol(&self) -> Option<WireProtocol> {
+        Some(WireProtocol::Jtag)
+    }
+
+    fn into_probe(self: Box<Self>) -> Box<dyn DebugProbe> {
+        self
+    }
+
+    fn has_riscv_interface(&self) -> bool {
+        true
+    }
+
+    fn try_get_riscv_interface_builder<'probe>(
+        &'probe mut self,
+    ) -> Result<Box<dyn RiscvInterfaceBuilder<'probe> + 'probe>, RiscvError> {
+        Ok(Box::new(JtagDtmBuilder::new(self)))
+    }
+}
+
+/// Wrap WCH-Link's USB based DMI access as a fake JtagAccess
+impl JtagAccess for WchLink {
+    fn set_scan_chain(&mut self, _scan_chain: &[ScanChainElement]) -> Result<(), DebugProbeError> {
+        Ok(())
+    }
+
+    fn scan_chain(&mut self) -> Result<&[ScanChainElement], DebugProbeError> {
+        Ok(&[])
+    }
+
+    fn tap_reset(&mut self) -> Result<(), DebugProbeError> {
+        Ok(())
+    }
+
+    fn read_register(&mut self, address: u32, len: u32) -> Result<BitVec, DebugProbeError> {
+        tracing::debug!("read register 0x{:08x}", address);
+        assert_eq!(len, 32);
+
+        let mut ret = bitvec![0; len as usize];
+        match address as u8 {
+            REG_IDCODE_ADDRESS => {
+                // using hard coded idcode 0x00000001, the same as WCH's openocd fork
+                tracing::debug!("using hard coded idcode 0x00000001");
+                ret[0..8].store_le::<u8>(0x1);
+                Ok(ret)
+            }
+            REG_DTMCS_ADDRESS => {
+                // See: RISC-V Debug Specification, 6.1.4
+                // 0x71: abits=7, version=1(1.0)
+                ret[0..8].store_le::<u8>(0x71);
+                Ok(ret)
+            }
+            REG_BYPASS_ADDRESS => Ok(bitvec![0; 4]),
+            _ => panic!("unknown read register address {address:08x}"),
+        }
+    }
+
+    fn set_idle_cycles(&mut self, idle_cycles: usize) -> Result<u8, DebugProbeError> {
+        self.idle_cycles = idle_cycles.min(u8::MAX as usize) as u8;
+        Ok(self.idle_cycles)
+    }
+
+    fn idle_cycles(&self) -> u8 {
+        self.idle_cycles
+    }
+
+    fn write_register(
+        &mut self,
+        address: u32,
+        data: &[u8],
         len: u32,
     ) -> Result<BitVec, DebugProbeError> {
         match address as u8 {
@@ -433,8 +1397,8 @@ impl JtagAccess for WchLink {
                 let val = u32::from_le_bytes(data.try_into().unwrap());
                 if val & DTMCS_DMIRESET_MASK != 0 {
                     tracing::debug!("DMI reset");
-                    self.dmi_op_write(0x10, 0x00000000)?;
-                    self.dmi_op_write(0x10, 0x00000001)?;
+                    self.dmi_op_write(DmRegister::DmControl, 0)?;
+                    self.dmi_op_write(DmRegister::DmControl, DMCONTROL_DMACTIVE)?;
                     // dmcontrol.dmactive is checked later
                 } else if val & DTMCS_DMIHARDRESET_MASK != 0 {
                     return Err(WchLinkError::UnsupportedOperation.into());
@@ -462,20 +1426,24 @@ impl JtagAccess for WchLink {
                     dmi_value,
                 );
 
+                // A busy response is passed on as is. The DTM layer then resets the DMI
+                // through `dtmcs`, and repeats the operation with more idle cycles.
                 let (addr, data, op) = match dmi_op {
                     DMI_OP_READ => {
                         let (addr, data, op) = self.dmi_op_read(dmi_addr)?;
                         tracing::trace!("dmi read 0x{:02x} 0x{:08x} op={}", addr, data, op);
+                        check_echoed_dmi_address(dmi_addr, addr)?;
                         self.last_dmi_read = Some((addr, data, op));
                         (addr, data, op)
                     }
                     DMI_OP_NOP => {
                         // No idea why NOP with zero addr should return the last read value.
                         // see-also: RiscvCommunicationInterface::read_dm_register_untyped
-                        let (addr, data, op) = if dmi_addr == 0 && dmi_value == 0 {
-                            self.last_dmi_read.unwrap()
-                        } else {
-                            self.dmi_op_nop()?
+                        //
+                        // Without a previous read, a real NOP is sent instead.
+                        let (addr, data, op) = match self.last_dmi_read {
+                            Some(last_read) if dmi_addr == 0 && dmi_value == 0 => last_read,
+                            _ => self.dmi_op_nop()?,
                         };
                         tracing::trace!("dmi nop 0x{:02x} 0x{:08x} op={}", addr, data, op);
                         (addr, data, op)
@@ -483,13 +1451,21 @@ impl JtagAccess for WchLink {
                     DMI_OP_WRITE => {
                         let (addr, data, op) = self.dmi_op_write(dmi_addr, dmi_value)?;
                         tracing::trace!("dmi write 0x{:02x} 0x{:08x} op={}", addr, data, op);
-                        if dmi_addr == 0x10 && dmi_value == 0x40000001 {
-                            // needs additional sleep for a resume operation
-                            std::thread::sleep(Duration::from_millis(10));
+                        check_echoed_dmi_address(dmi_addr, addr)?;
+                        if is_resume_request(dmi_addr, dmi_value) {
+                            // The resume takes effect with a delay, wait until it is acknowledged.
+                            // The caller checks the acknowledgement itself, so a timeout isn't an error here.
+                            match self.wait_for_dmstatus(DMSTATUS_ALLRESUMEACK) {
+                                Err(DebugProbeError::Timeout) => {}
+                                result => result?,
+                            }
                         }
                         (addr, data, op)
                     }
-                    _ => unreachable!("unknown dmi_op {dmi_op}"),
+                    _ => {
+                        tracing::warn!("Unknown DMI op {}, not sending it to the probe", dmi_op);
+                        return Err(WchLinkError::InvalidDmiOp(dmi_op).into());
+                    }
                 };
 
                 let ret = ((addr as u128) << DMI_ADDRESS_BIT_OFFSET)
@@ -521,6 +1497,52 @@ impl JtagAccess for WchLink {
     }
 }
 
+/// Check that the probe echoed the address of a DMI read or write.
+///
+/// The request and response are packed with a fixed field order. A firmware with a
+/// different order would otherwise return data from the wrong register.
+fn check_echoed_dmi_address(requested: u8, echoed: u8) -> Result<(), WchLinkError> {
+    if requested != echoed {
+        tracing::error!(
+            "DMI response for address 0x{:02x} has address 0x{:02x}, the response format is not supported",
+            requested,
+            echoed
+        );
+        return Err(WchLinkError::DmiAddressMismatch { requested, echoed });
+    }
+
+    Ok(())
+}
+
+/// Returns whether the DMI write of `value` to `addr` requests the hart to resume.
+///
+/// Only `resumereq` and `dmactive` are checked, so that writes which also set e.g.
+/// `hasel` or `ackhavereset` are detected as well. A write which also sets `haltreq`
+/// is not a resume request, as `resumereq` is ignored then.
+fn is_resume_request(addr: u8, value: u32) -> bool {
+    addr == u8::from(DmRegister::DmControl)
+        && value & (DMCONTROL_RESUMEREQ | DMCONTROL_DMACTIVE)
+            == DMCONTROL_RESUMEREQ | DMCONTROL_DMACTIVE
+        && value & DMCONTROL_HALTREQ == 0
+}
+
+/// Whether attaching failed because a chip of another family is connected, which
+/// retrying can't fix.
+fn is_chip_family_mismatch(error: &DebugProbeError) -> bool {
+    match error {
+        DebugProbeError::ProbeSpecific(e) => matches!(
+            e.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::ChipFamilyMismatch { .. })
+        ),
+        _ => false,
+    }
+}
+
+/// Decodes the response to `CheckFlashProtection`, which is `0x01` for protected flash.
+fn is_flash_protected(status: u8) -> bool {
+    status == 0x01
+}
+
 fn get_wlink_info(device: &DeviceInfo) -> Option<DebugProbeInfo> {
     if matches!(device.product_string(), Some("WCH-Link") | Some("WCH_Link")) {
         Some(DebugProbeInfo::new(
@@ -572,16 +1594,27 @@ fn list_wlink_devices() -> Vec<DebugProbeInfo> {
     probes
 }
 
+/// Errors specific to WCH-Link probes.
 #[derive(thiserror::Error, Debug, docsplay::Display)]
-pub(crate) enum WchLinkError {
+pub enum WchLinkError {
     /// Unknown WCH-Link device.
     UnknownDevice,
     /// The firmware on the probe is outdated, and not supported by probe-rs. The minimum supported firmware version is {0}.
     UnsupportedFirmwareVersion(&'static str),
     /// Not enough bytes written.
-    NotEnoughBytesWritten { is: usize, should: usize },
+    NotEnoughBytesWritten {
+        /// The number of bytes written.
+        is: usize,
+        /// The number of bytes that should have been written.
+        should: usize,
+    },
     /// Not enough bytes read.
-    NotEnoughBytesRead { is: usize, should: usize },
+    NotEnoughBytesRead {
+        /// The number of bytes read.
+        is: usize,
+        /// The number of bytes that should have been read.
+        should: usize,
+    },
     /// Usb endpoint not found.
     EndpointNotFound,
     /// Invalid payload.
@@ -592,6 +1625,1903 @@ pub(crate) enum WchLinkError {
     UnknownChip(u8),
     /// Unsupported operation.
     UnsupportedOperation,
+    /// The remote WCH-Link server reported an error: {message}
+    Remote {
+        /// The category of the error on the server
+        category: WchLinkErrorCategory,
+        /// The error message of the server
+        message: String,
+    },
+    /// {0} WCH-Links match the probe selector, add a serial number to select one of them.
+    AmbiguousSelector(usize),
+    /// The debug module of the target chip is not active after it was reset.
+    DebugModuleInactive,
+    /// The flash of the target chip is read-protected, enable auto-unprotect to erase it.
+    FlashProtected,
+    /// Invalid DMI operation {0}.
+    InvalidDmiOp(u8),
+    /// No target detected, `dmstatus` reads {0:#010x}. Check the wiring and the power supply of the target.
+    NoTargetDetected(u32),
+    /// The abstract command failed with error {0}.
+    AbstractCommand(u32),
+    /// The WCH-Link was disconnected.
+    Disconnected,
+    /// Invalid haltsum register {0}, it must be between 0 and 3.
+    InvalidHaltsumLevel(u8),
+    /// The target is a {detected:?} chip instead of the expected {expected:?}. Check that the right board is connected.
+    ChipFamilyMismatch {
+        /// The expected chip family.
+        expected: RiscvChip,
+        /// The chip family detected by the probe.
+        detected: RiscvChip,
+    },
+    /// The DMI operation on address {addr:#04x} failed with status {op}.
+    DmiOpFailed {
+        /// The address of the operation.
+        addr: u8,
+        /// The status returned for the operation, 2 if it failed and 3 if the debug module was busy.
+        op: u8,
+    },
+    /// The DMI response is for address {echoed:#04x} instead of {requested:#04x}.
+    DmiAddressMismatch {
+        /// The address of the request.
+        requested: u8,
+        /// The address echoed in the response.
+        echoed: u8,
+    },
+}
+
+impl WchLinkError {
+    /// Returns the category of this error, which tells what the user can do about it.
+    pub fn category(&self) -> WchLinkErrorCategory {
+        match self {
+            WchLinkError::NotEnoughBytesWritten { .. }
+            | WchLinkError::NotEnoughBytesRead { .. }
+            | WchLinkError::Disconnected => WchLinkErrorCategory::Transient,
+            WchLinkError::Remote { category, .. } => *category,
+            WchLinkError::UnsupportedFirmwareVersion(_)
+            | WchLinkError::EndpointNotFound
+            | WchLinkError::AmbiguousSelector(_)
+            | WchLinkError::FlashProtected
+            | WchLinkError::InvalidHaltsumLevel(_)
+            | WchLinkError::ChipFamilyMismatch { .. }
+            | WchLinkError::NoTargetDetected(_) => WchLinkErrorCategory::Configuration,
+            WchLinkError::UnknownDevice
+            | WchLinkError::UnknownChip(_)
+            | WchLinkError::UnsupportedOperation => WchLinkErrorCategory::Unsupported,
+            WchLinkError::InvalidPayload
+            | WchLinkError::Protocol(_, _)
+            | WchLinkError::DebugModuleInactive
+            | WchLinkError::InvalidDmiOp(_)
+            | WchLinkError::DmiAddressMismatch { .. }
+            | WchLinkError::DmiOpFailed { .. }
+            | WchLinkError::AbstractCommand(_) => WchLinkErrorCategory::Protocol,
+        }
+    }
 }
 
 impl ProbeError for WchLinkError {}
+
+/// Categories of [`WchLinkError`]s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WchLinkErrorCategory {
+    /// The USB communication failed, retrying or replugging the probe can help.
+    Transient,
+    /// The probe is not set up correctly, e.g. its firmware needs to be updated.
+    Configuration,
+    /// The probe, its firmware or the target chip does not support the operation.
+    Unsupported,
+    /// The probe sent an unexpected response.
+    Protocol,
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::VecDeque,
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use bitvec::field::BitField;
+
+    use super::{
+        DmRegister, DmiRecord, RetryPolicy, RiscvChip, WchLink, WchLinkCapabilities, WchLinkError,
+        WchLinkErrorCategory, WchLinkTransport, WchLinkVariant, is_resume_request,
+        tcp::{WchLinkTcpClient, decode_category, encode_category, serve_connection},
+        usb_interface::{is_disconnected, single_device},
+    };
+    use crate::{
+        architecture::riscv::communication_interface::AbstractCommandErrorKind,
+        probe::{DebugProbe, DebugProbeError, JtagAccess, ProbeCreationError},
+    };
+
+    /// Records the requests sent to it, and replies with queued responses.
+    #[derive(Clone, Default)]
+    struct FakeTransport {
+        requests: Arc<Mutex<Vec<Vec<u8>>>>,
+        responses: Arc<Mutex<VecDeque<Vec<u8>>>>,
+        /// Responses left over from a previous session, returned without a request
+        stale: Arc<Mutex<VecDeque<Vec<u8>>>>,
+        /// Time the fake probe takes to respond
+        delay: Duration,
+        /// Number of the next requests which time out without a response
+        timeouts: Arc<Mutex<usize>>,
+        /// Number of the next requests which time out, with the response arriving afterwards
+        late: Arc<Mutex<usize>>,
+        /// Number of the next requests which fail because the probe is disconnected
+        disconnects: Arc<Mutex<usize>>,
+        /// Number of times the probe was opened again
+        reopened: Arc<Mutex<usize>>,
+        /// Number of the next attempts to open the probe again which fail, because it has
+        /// not enumerated yet
+        reopen_failures: Arc<Mutex<usize>>,
+    }
+
+    impl FakeTransport {
+        /// Queue a successful response to command `command_id`.
+        fn respond(&self, command_id: u8, payload: &[u8]) {
+            let mut response = vec![0x82, command_id, payload.len() as u8];
+            response.extend_from_slice(payload);
+            self.responses.lock().unwrap().push_back(response);
+        }
+
+        /// Queue an error response to command `command_id`.
+        fn respond_error(&self, command_id: u8) {
+            self.responses
+                .lock()
+                .unwrap()
+                .push_back(vec![0x81, 0x55, 0x01, command_id]);
+        }
+
+        /// The command IDs of all requests sent so far, except the initial probe info request.
+        fn sent_commands(&self) -> Vec<u8> {
+            self.requests.lock().unwrap()[1..]
+                .iter()
+                .map(|request| request[1])
+                .collect()
+        }
+    }
+
+    impl WchLinkTransport for FakeTransport {
+        fn transfer(
+            &mut self,
+            request: &[u8],
+            response: &mut [u8],
+            timeout: Duration,
+        ) -> Result<usize, DebugProbeError> {
+            self.requests.lock().unwrap().push(request.to_vec());
+
+            let mut timeouts = self.timeouts.lock().unwrap();
+            if *timeouts > 0 {
+                *timeouts -= 1;
+                return Err(DebugProbeError::Timeout);
+            }
+            drop(timeouts);
+
+            let mut late = self.late.lock().unwrap();
+            if *late > 0 {
+                *late -= 1;
+                let reply = self
+                    .responses
+                    .lock()
+                    .unwrap()
+                    .pop_front()
+                    .expect("No response queued for request");
+                self.stale.lock().unwrap().push_back(reply);
+                return Err(DebugProbeError::Timeout);
+            }
+            drop(late);
+
+            let mut disconnects = self.disconnects.lock().unwrap();
+            if *disconnects > 0 {
+                *disconnects -= 1;
+                return Err(WchLinkError::Disconnected.into());
+            }
+            drop(disconnects);
+
+            if self.delay > timeout {
+                std::thread::sleep(timeout);
+                return Err(DebugProbeError::Timeout);
+            }
+            std::thread::sleep(self.delay);
+
+            let reply = self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .expect("No response queued for request");
+            response[..reply.len()].copy_from_slice(&reply);
+
+            Ok(reply.len())
+        }
+
+        fn read_pending(
+            &mut self,
+            response: &mut [u8],
+            _timeout: Duration,
+        ) -> Result<usize, DebugProbeError> {
+            let reply = self
+                .stale
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(DebugProbeError::Timeout)?;
+            response[..reply.len()].copy_from_slice(&reply);
+
+            Ok(reply.len())
+        }
+
+        fn reopen(&mut self) -> Result<(), DebugProbeError> {
+            let mut failures = self.reopen_failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(ProbeCreationError::NotFound.into());
+            }
+
+            *self.reopened.lock().unwrap() += 1;
+
+            Ok(())
+        }
+    }
+
+    const CMD_CONTROL: u8 = 0x0d;
+    const CMD_CONFIG_CHIP: u8 = 0x01;
+    const CMD_SET_SPEED: u8 = 0x0c;
+    const CMD_DMI_OP: u8 = 0x08;
+    const CMD_PROGRAM: u8 = 0x02;
+    const CMD_RESET: u8 = 0x0b;
+
+    /// Create a WCH-LinkE with firmware 2.9 on top of `transport`.
+    fn fake_probe(transport: &FakeTransport) -> WchLink {
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x02]);
+
+        WchLink::from_transport(Box::new(transport.clone())).unwrap()
+    }
+
+    /// Queue the responses to a SetSpeed and AttachChip for a CH32V30X, and to the
+    /// following `dmstatus` read.
+    fn respond_attach(transport: &FakeTransport) {
+        transport.respond(CMD_SET_SPEED, &[0x01]);
+        transport.respond(CMD_CONTROL, &[0x06, 0x30, 0x70, 0x05, 0x18]);
+        respond_dmi(transport, 0x11, 0x0000_0c82);
+    }
+
+    #[test]
+    fn probe_is_initialized_from_transport() {
+        let transport = FakeTransport::default();
+        let probe = fake_probe(&transport);
+
+        assert_eq!(probe.get_name(), "WCH-LinkE-CH32V305 v2.9");
+        assert_eq!((probe.v_major, probe.v_minor), (2, 9));
+        assert_eq!(probe.serial_number, None);
+        assert!(transport.sent_commands().is_empty());
+    }
+
+    #[test]
+    fn probe_with_old_firmware_is_rejected() {
+        let transport = FakeTransport::default();
+        transport.respond(CMD_CONTROL, &[0x02, 0x06, 0x02]);
+
+        let error = WchLink::from_transport(Box::new(transport.clone())).unwrap_err();
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::UnsupportedFirmwareVersion("2.7"))
+        ));
+    }
+
+    #[test]
+    fn family_mismatch_is_a_warning_by_default() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_expected_chip_family(Some(RiscvChip::CH32V103));
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+
+        probe.attach().unwrap();
+
+        assert_eq!(probe.chip_family, RiscvChip::CH32V30X);
+    }
+
+    #[test]
+    fn strict_family_check_fails_on_mismatch() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_expected_chip_family(Some(RiscvChip::CH32V103));
+        probe.set_strict_family_check(true);
+        probe.set_retry_policy(RetryPolicy {
+            attach_attempts: 2,
+            ..RetryPolicy::default()
+        });
+
+        transport.respond(CMD_SET_SPEED, &[0x01]);
+        transport.respond(CMD_CONTROL, &[0x06, 0x30, 0x70, 0x05, 0x18]);
+        transport.respond(CMD_CONTROL, &[]);
+
+        let error = probe.attach().unwrap_err();
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::ChipFamilyMismatch {
+                expected: RiscvChip::CH32V103,
+                detected: RiscvChip::CH32V30X,
+            })
+        ));
+        // The probe is detached again, and the attach is not retried.
+        assert_eq!(
+            transport.sent_commands(),
+            [CMD_SET_SPEED, CMD_CONTROL, CMD_CONTROL]
+        );
+        assert!(probe.attached_chip().is_none());
+    }
+
+    #[test]
+    fn attach_with_fake_transport() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        assert_eq!(probe.variant, WchLinkVariant::ECh32v305);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+
+        probe.attach().unwrap();
+
+        assert_eq!(probe.chip_family, RiscvChip::CH32V30X);
+        assert_eq!(probe.chip_id, 0x3070_0518);
+        assert_eq!(probe.attached_chip().map(|chip| chip.riscvchip), Some(0x06));
+        assert_eq!(probe.flash_protected(), Some(false));
+        assert_eq!(
+            transport.sent_commands(),
+            [
+                CMD_SET_SPEED,
+                CMD_CONTROL,
+                CMD_DMI_OP,
+                CMD_CONFIG_CHIP,
+                CMD_CONFIG_CHIP
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_responses_are_drained() {
+        let transport = FakeTransport::default();
+        transport
+            .stale
+            .lock()
+            .unwrap()
+            .push_back(vec![0x82, CMD_DMI_OP, 0x06, 0x11, 0, 0, 0x0c, 0x82, 0]);
+
+        let probe = fake_probe(&transport);
+
+        assert!(transport.stale.lock().unwrap().is_empty());
+        // The probe info was parsed from the actual response, not the stale one.
+        assert_eq!(probe.get_name(), "WCH-LinkE-CH32V305 v2.9");
+    }
+
+    /// The second payload byte of the requests sent so far, except the initial probe info request.
+    fn sent_subcommands(transport: &FakeTransport) -> Vec<u8> {
+        transport.requests.lock().unwrap()[1..]
+            .iter()
+            .map(|request| request[3])
+            .collect()
+    }
+
+    #[test]
+    fn detach_keeps_power_by_default() {
+        let transport = FakeTransport::default();
+        transport.respond(CMD_CONTROL, &[0x02, 0x0b, 0x02]);
+        let mut probe = WchLink::from_transport(Box::new(transport.clone())).unwrap();
+
+        transport.respond(CMD_CONTROL, &[]);
+        probe.detach().unwrap();
+
+        assert_eq!(sent_subcommands(&transport), [0xff]);
+    }
+
+    #[test]
+    fn detach_powers_off_target() {
+        let transport = FakeTransport::default();
+        transport.respond(CMD_CONTROL, &[0x02, 0x0b, 0x02]);
+        let mut probe = WchLink::from_transport(Box::new(transport.clone())).unwrap();
+        probe.set_power_off_on_detach(true);
+
+        transport.respond(CMD_CONTROL, &[]);
+        transport.respond(CMD_CONTROL, &[]);
+        transport.respond(CMD_CONTROL, &[]);
+        probe.detach().unwrap();
+
+        assert_eq!(transport.sent_commands(), [CMD_CONTROL; 3]);
+        assert_eq!(sent_subcommands(&transport), [0xff, 0x0a, 0x0c]);
+    }
+
+    #[test]
+    fn detach_without_power_control() {
+        let transport = FakeTransport::default();
+        // A WCH-LinkS can't switch the target power supply.
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x03]);
+        let mut probe = WchLink::from_transport(Box::new(transport.clone())).unwrap();
+        probe.set_power_off_on_detach(true);
+
+        transport.respond(CMD_CONTROL, &[]);
+        probe.detach().unwrap();
+
+        assert_eq!(sent_subcommands(&transport), [0xff]);
+    }
+
+    #[test]
+    fn attach_over_tcp() {
+        let transport = FakeTransport::default();
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x02]);
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server_transport = transport.clone();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(&mut server_transport, stream).unwrap();
+        });
+
+        let mut probe = WchLink::connect(addr).unwrap();
+        assert_eq!(probe.variant, WchLinkVariant::ECh32v305);
+
+        probe.attach().unwrap();
+        assert_eq!(probe.chip_family, RiscvChip::CH32V30X);
+        assert_eq!(probe.chip_id, 0x3070_0518);
+
+        drop(probe);
+        server.join().unwrap();
+
+        assert_eq!(
+            transport.sent_commands(),
+            [
+                CMD_SET_SPEED,
+                CMD_CONTROL,
+                CMD_DMI_OP,
+                CMD_CONFIG_CHIP,
+                CMD_CONFIG_CHIP
+            ]
+        );
+    }
+
+    #[test]
+    fn tcp_server_discards_late_response() {
+        let transport = FakeTransport::default();
+        // The response to the first request arrives after it timed out.
+        *transport.late.lock().unwrap() = 1;
+        transport.respond(CMD_DMI_OP, &[0x11, 0x00, 0x00, 0x0c, 0x82, 0x00]);
+        transport.respond(CMD_DMI_OP, &[0x10, 0x00, 0x00, 0x00, 0x01, 0x00]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server_transport = transport.clone();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(&mut server_transport, stream).unwrap();
+        });
+
+        let mut client = WchLinkTcpClient::connect(addr).unwrap();
+        let mut response = [0u8; 64];
+
+        let error = client
+            .transfer(
+                &[0x81, CMD_DMI_OP],
+                &mut response,
+                Duration::from_millis(100),
+            )
+            .unwrap_err();
+        assert!(matches!(error, DebugProbeError::Timeout));
+
+        let len = client
+            .transfer(
+                &[0x81, CMD_DMI_OP],
+                &mut response,
+                Duration::from_millis(100),
+            )
+            .unwrap();
+        // The response to the second request, not the late one.
+        assert_eq!(
+            response[..len],
+            [0x82, CMD_DMI_OP, 0x06, 0x10, 0, 0, 0, 0x01, 0]
+        );
+        assert!(transport.stale.lock().unwrap().is_empty());
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tcp_client_is_unusable_after_partial_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).unwrap();
+            // Only the status of the response
+            stream.write_all(&[0x00]).unwrap();
+        });
+
+        let mut client = WchLinkTcpClient::connect(addr).unwrap();
+        let mut response = [0u8; 64];
+
+        // Too long to be framed, so it is rejected without using the connection.
+        let error = client
+            .transfer(&[0u8; 256], &mut response, Duration::from_millis(100))
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            DebugProbeError::ProbeSpecific(ref e)
+                if matches!(e.downcast_ref::<WchLinkError>(), Some(WchLinkError::InvalidPayload))
+        ));
+
+        for _ in 0..2 {
+            let error = client
+                .transfer(&[0x81], &mut response, Duration::from_millis(100))
+                .unwrap_err();
+            assert!(is_disconnected(&error));
+        }
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn remote_error_categories_are_preserved() {
+        for category in [
+            WchLinkErrorCategory::Transient,
+            WchLinkErrorCategory::Configuration,
+            WchLinkErrorCategory::Unsupported,
+            WchLinkErrorCategory::Protocol,
+        ] {
+            assert_eq!(decode_category(encode_category(category)), category);
+        }
+    }
+
+    #[test]
+    fn attach_without_target() {
+        // Floating data line, pulled up or down
+        for dmstatus in [u32::MAX, 0] {
+            let transport = FakeTransport::default();
+            let mut probe = fake_probe(&transport);
+
+            transport.respond(CMD_SET_SPEED, &[0x01]);
+            transport.respond(CMD_CONTROL, &[0x06, 0x30, 0x70, 0x05, 0x18]);
+            respond_dmi(&transport, 0x11, dmstatus);
+
+            let error = probe.attach().unwrap_err();
+
+            let DebugProbeError::ProbeSpecific(error) = error else {
+                panic!("unexpected error: {error:?}");
+            };
+            assert!(matches!(
+                error.downcast_ref::<WchLinkError>(),
+                Some(WchLinkError::NoTargetDetected(value)) if *value == dmstatus
+            ));
+            assert!(error.to_string().contains("Check the wiring"));
+        }
+    }
+
+    #[test]
+    fn attach_acknowledges_reset() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        transport.respond(CMD_SET_SPEED, &[0x01]);
+        transport.respond(CMD_CONTROL, &[0x06, 0x30, 0x70, 0x05, 0x18]);
+        // anyhavereset is set
+        respond_dmi(&transport, 0x11, 0x000c_0c82);
+        respond_dmi(&transport, 0x10, 0);
+        respond_dmi(&transport, 0x10, 0x0000_0001);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+
+        probe.attach().unwrap();
+
+        assert_eq!(probe.chip_family, RiscvChip::CH32V30X);
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [(0x11, 0, 1), (0x10, 0x1000_0001, 2), (0x10, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn erase_chip_waits_for_response() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+        probe.attach().unwrap();
+
+        // The response is sent once the erase is done, nothing is polled.
+        transport.respond(CMD_PROGRAM, &[]);
+
+        probe.erase_chip().unwrap();
+
+        assert_eq!(transport.sent_commands()[5..], [CMD_PROGRAM]);
+    }
+
+    #[test]
+    fn erase_protected_chip_without_auto_unprotect() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_auto_unprotect(false);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x01]);
+        probe.attach().unwrap();
+
+        let error = probe.erase_chip().unwrap_err();
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::FlashProtected)
+        ));
+        // Nothing is sent after attaching.
+        assert_eq!(transport.sent_commands().len(), 4);
+    }
+
+    #[test]
+    fn attach_is_retried() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_retry_policy(RetryPolicy {
+            attach_attempts: 2,
+            ..RetryPolicy::default()
+        });
+
+        transport.respond(CMD_SET_SPEED, &[0x01]);
+        transport.respond_error(CMD_CONTROL);
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+
+        probe.attach().unwrap();
+
+        assert_eq!(
+            transport.sent_commands(),
+            [
+                CMD_SET_SPEED,
+                CMD_CONTROL,
+                CMD_SET_SPEED,
+                CMD_CONTROL,
+                CMD_DMI_OP,
+                CMD_CONFIG_CHIP,
+                CMD_CONFIG_CHIP
+            ]
+        );
+    }
+
+    #[test]
+    fn attach_returns_error_of_last_retry() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_retry_policy(RetryPolicy {
+            attach_attempts: 2,
+            ..RetryPolicy::default()
+        });
+
+        transport.respond(CMD_SET_SPEED, &[0x01]);
+        transport.respond_error(CMD_CONTROL);
+        transport.respond(CMD_SET_SPEED, &[0x01]);
+        transport.respond(CMD_CONTROL, &[0x06, 0x30, 0x70, 0x05, 0x18]);
+        respond_dmi(&transport, 0x11, 0);
+
+        let error = probe.attach().unwrap_err();
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::NoTargetDetected(0))
+        ));
+        assert_eq!(
+            transport.sent_commands(),
+            [
+                CMD_SET_SPEED,
+                CMD_CONTROL,
+                CMD_SET_SPEED,
+                CMD_CONTROL,
+                CMD_DMI_OP
+            ]
+        );
+    }
+
+    #[test]
+    fn read_only_attach_does_not_unprotect() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_unprotect_on_attach(false);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x01]);
+
+        probe.attach().unwrap();
+
+        assert_eq!(probe.flash_protected(), Some(true));
+        assert_eq!(
+            transport.sent_commands(),
+            [CMD_SET_SPEED, CMD_CONTROL, CMD_DMI_OP, CMD_CONFIG_CHIP]
+        );
+
+        // The protection is still cleared before erasing.
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+        transport.respond(CMD_PROGRAM, &[]);
+
+        probe.erase_chip().unwrap();
+
+        assert_eq!(
+            transport.sent_commands()[4..],
+            [CMD_CONFIG_CHIP, CMD_PROGRAM]
+        );
+    }
+
+    #[test]
+    fn protected_flash_is_reported() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_auto_unprotect(false);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x01]);
+
+        probe.attach().unwrap();
+
+        assert_eq!(probe.flash_protected(), Some(true));
+        // The flash is not unprotected.
+        assert_eq!(
+            transport.sent_commands(),
+            [CMD_SET_SPEED, CMD_CONTROL, CMD_DMI_OP, CMD_CONFIG_CHIP]
+        );
+    }
+
+    #[test]
+    fn protected_flash_is_unprotected_on_attach() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        assert_eq!(probe.flash_protected(), None);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x01]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+
+        probe.attach().unwrap();
+
+        assert_eq!(probe.flash_protected(), Some(false));
+        assert_eq!(
+            transport.sent_commands(),
+            [
+                CMD_SET_SPEED,
+                CMD_CONTROL,
+                CMD_DMI_OP,
+                CMD_CONFIG_CHIP,
+                CMD_CONFIG_CHIP
+            ]
+        );
+    }
+
+    #[test]
+    fn last_dmi_op_after_read() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        assert_eq!(probe.last_dmi_op(), None);
+
+        transport.respond(CMD_DMI_OP, &[0x11, 0x00, 0x00, 0x12, 0x34, 0x00]);
+        probe.dmi_op_read(0x11).unwrap();
+
+        assert_eq!(probe.last_dmi_op(), Some((0x11, 0x1234, 0)));
+        assert_eq!(probe.last_dmi_write(), None);
+    }
+
+    #[test]
+    fn last_dmi_write_is_kept_after_read() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        transport.respond(CMD_DMI_OP, &[0x10, 0x00, 0x00, 0x00, 0x01, 0x00]);
+        probe.dmi_op_write(0x10, 0x1).unwrap();
+        assert_eq!(probe.last_dmi_write(), Some((0x10, 0x1, 0)));
+        assert_eq!(probe.last_dmi_op(), Some((0x10, 0x1, 0)));
+
+        transport.respond(CMD_DMI_OP, &[0x11, 0x00, 0x00, 0x0c, 0x82, 0x00]);
+        probe.dmi_op_read(0x11).unwrap();
+        assert_eq!(probe.last_dmi_op(), Some((0x11, 0x0c82, 0)));
+        assert_eq!(probe.last_dmi_write(), Some((0x10, 0x1, 0)));
+    }
+
+    #[test]
+    fn dmi_history_records_ops_in_order() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x11, 0x0000_0382);
+        probe.read_dmstatus().unwrap();
+        // Disabled by default
+        assert_eq!(probe.dmi_history().next(), None);
+
+        probe.set_dmi_history_capacity(2);
+
+        respond_dmi(&transport, 0x11, 0x0000_0382);
+        respond_dmi(&transport, 0x10, 0x8000_0001);
+        respond_dmi(&transport, 0x00, 0x0000_0c82);
+        probe.read_dmstatus().unwrap();
+        probe.dmi_op_write(0x10, 0x8000_0001).unwrap();
+        probe.dmi_op_nop().unwrap();
+
+        // Only the two most recent operations are kept.
+        assert_eq!(
+            probe.dmi_history().copied().collect::<Vec<_>>(),
+            [
+                DmiRecord {
+                    op: 2,
+                    addr: 0x10,
+                    data: 0x8000_0001,
+                    result: 0,
+                },
+                DmiRecord {
+                    op: 0,
+                    addr: 0x00,
+                    data: 0x0000_0c82,
+                    result: 0,
+                },
+            ]
+        );
+
+        probe.set_dmi_history_capacity(1);
+        assert_eq!(probe.dmi_history().count(), 1);
+        assert_eq!(probe.dmi_history().next().map(|record| record.op), Some(0));
+    }
+
+    #[test]
+    fn retry_policy_retries_timeouts() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        probe.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            timeout: Duration::from_millis(100),
+            backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+
+        // The first two attempts time out.
+        *transport.timeouts.lock().unwrap() = 2;
+        respond_dmi(&transport, 0x11, 0x0000_0382);
+
+        let dmstatus = probe.read_dmstatus().unwrap();
+
+        assert!(dmstatus.allhalted());
+        assert_eq!(sent_dmi_ops(&transport), [(0x11, 0, 1); 3]);
+    }
+
+    #[test]
+    fn retry_policy_ignores_late_response_over_tcp() {
+        let transport = FakeTransport::default();
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x02]);
+        // The first attempt times out, and its response arrives afterwards.
+        respond_dmi(&transport, 0x11, 0x0000_0c82);
+        respond_dmi(&transport, 0x11, 0x0000_0382);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server_transport = transport.clone();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(&mut server_transport, stream).unwrap();
+        });
+
+        let mut probe = WchLink::connect(addr).unwrap();
+        probe.set_retry_policy(RetryPolicy {
+            attempts: 2,
+            ..RetryPolicy::default()
+        });
+        *transport.late.lock().unwrap() = 1;
+
+        let dmstatus = probe.read_dmstatus().unwrap();
+
+        // The status of the retried read, not the late one of the first attempt
+        assert!(dmstatus.allhalted());
+        assert_eq!(sent_dmi_ops(&transport), [(0x11, 0, 1); 2]);
+
+        drop(probe);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn retry_policy_does_not_repeat_dmcontrol_writes() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        probe.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            ..RetryPolicy::default()
+        });
+
+        *transport.timeouts.lock().unwrap() = 1;
+
+        let error = probe.target_reset_assert().unwrap_err();
+
+        assert!(matches!(error, DebugProbeError::Timeout));
+        assert_eq!(sent_dmi_ops(&transport), [(0x10, 0x8000_0001, 2)]);
+    }
+
+    #[test]
+    fn reconnect_after_disconnect() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // The probe is reset, and answers the probe info request after it was reopened.
+        *transport.disconnects.lock().unwrap() = 1;
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x02]);
+        respond_dmi(&transport, 0x11, 0x0000_0382);
+
+        let dmstatus = probe.read_dmstatus().unwrap();
+
+        assert!(dmstatus.allhalted());
+        assert_eq!(*transport.reopened.lock().unwrap(), 1);
+        assert_eq!(
+            transport.sent_commands(),
+            [CMD_DMI_OP, CMD_CONTROL, CMD_DMI_OP]
+        );
+    }
+
+    #[test]
+    fn reconnect_waits_for_enumeration() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // The probe is only found again on the third attempt.
+        *transport.disconnects.lock().unwrap() = 1;
+        *transport.reopen_failures.lock().unwrap() = 2;
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x02]);
+        respond_dmi(&transport, 0x11, 0x0000_0382);
+
+        probe.read_dmstatus().unwrap();
+
+        assert_eq!(*transport.reopen_failures.lock().unwrap(), 0);
+        assert_eq!(*transport.reopened.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn reconnect_attaches_again() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+        probe.attach().unwrap();
+        let attach_commands = transport.sent_commands().len();
+
+        // The chip is attached again before the failed command is repeated, without
+        // clearing the flash protection again.
+        *transport.disconnects.lock().unwrap() = 1;
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x02]);
+        transport.respond(CMD_SET_SPEED, &[0x01]);
+        transport.respond(CMD_CONTROL, &[0x06, 0x30, 0x70, 0x05, 0x18]);
+        respond_dmi(&transport, 0x11, 0x0000_0382);
+
+        let dmstatus = probe.read_dmstatus().unwrap();
+
+        assert!(dmstatus.allhalted());
+        assert!(probe.attached_chip().is_some());
+        assert_eq!(
+            transport.sent_commands()[attach_commands..],
+            [
+                CMD_DMI_OP,
+                CMD_CONTROL,
+                CMD_SET_SPEED,
+                CMD_CONTROL,
+                CMD_DMI_OP
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnect_does_not_repeat_reset() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // The probe is reset together with the target.
+        *transport.disconnects.lock().unwrap() = 1;
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x02]);
+
+        let error = probe.target_reset().unwrap_err();
+
+        assert!(is_disconnected(&error));
+        assert_eq!(*transport.reopened.lock().unwrap(), 1);
+        assert_eq!(transport.sent_commands(), [CMD_RESET, CMD_CONTROL]);
+    }
+
+    #[test]
+    fn default_retry_policy_sends_once() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        assert_eq!(probe.retry_policy(), RetryPolicy::default());
+
+        *transport.timeouts.lock().unwrap() = 1;
+
+        let error = probe.read_dmstatus().unwrap_err();
+
+        assert!(matches!(error, DebugProbeError::Timeout));
+        assert_eq!(sent_dmi_ops(&transport), [(0x11, 0, 1)]);
+    }
+
+    #[test]
+    fn read_haltsum_levels() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // Harts 0, 3 and 31 are halted.
+        respond_dmi(&transport, 0x40, 0x8000_0009);
+        respond_dmi(&transport, 0x13, 0x0000_0001);
+
+        assert_eq!(probe.read_haltsum(0).unwrap(), 0x8000_0009);
+        assert_eq!(probe.read_haltsum(1).unwrap(), 0x0000_0001);
+        assert_eq!(sent_dmi_ops(&transport), [(0x40, 0, 1), (0x13, 0, 1)]);
+
+        let error = probe.read_haltsum(4).unwrap_err();
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::InvalidHaltsumLevel(4))
+        ));
+    }
+
+    #[test]
+    fn read_dmstatus_is_decoded() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x11, 0x0004_0382);
+
+        let dmstatus = probe.read_dmstatus().unwrap();
+
+        assert!(dmstatus.anyhavereset());
+        assert!(!dmstatus.allhavereset());
+        assert!(!dmstatus.allresumeack());
+        assert!(dmstatus.allhalted());
+        assert!(dmstatus.anyhalted());
+        assert!(!dmstatus.allrunning());
+        assert!(!dmstatus.anyrunning());
+        assert!(dmstatus.authenticated());
+        assert_eq!(dmstatus.version(), 2);
+
+        assert_eq!(sent_dmi_ops(&transport), [(0x11, 0, 1)]);
+    }
+
+    #[test]
+    fn read_abstractcs_busy() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x16, 0x0200_1002);
+
+        let abstractcs = probe.read_abstractcs().unwrap();
+
+        assert!(abstractcs.busy());
+        assert!(AbstractCommandErrorKind::from_cmderr(abstractcs.cmderr()).is_none());
+        assert_eq!(abstractcs.progbufsize(), 2);
+        assert_eq!(abstractcs.datacount(), 2);
+        assert_eq!(sent_dmi_ops(&transport), [(0x16, 0, 1)]);
+    }
+
+    #[test]
+    fn read_and_clear_cmderr() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x16, 0x0200_0302);
+        respond_dmi(&transport, 0x16, 0);
+
+        let abstractcs = probe.read_abstractcs().unwrap();
+        assert!(!abstractcs.busy());
+        assert!(matches!(
+            AbstractCommandErrorKind::from_cmderr(abstractcs.cmderr()),
+            Some(AbstractCommandErrorKind::Exception)
+        ));
+
+        probe.clear_cmderr().unwrap();
+
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [(0x16, 0, 1), (0x16, 0x0000_0700, 2)]
+        );
+    }
+
+    #[test]
+    fn cmderr_outside_field_is_ignored() {
+        // Only the three bits of the field are decoded.
+        assert!(matches!(
+            AbstractCommandErrorKind::from_cmderr(0x0b),
+            Some(AbstractCommandErrorKind::Exception)
+        ));
+        assert!(AbstractCommandErrorKind::from_cmderr(0x08).is_none());
+    }
+
+    #[test]
+    fn dm_register_addresses() {
+        assert_eq!(u8::from(DmRegister::DmControl), 0x10);
+        assert_eq!(u8::from(DmRegister::DmStatus), 0x11);
+        assert_eq!(u8::from(DmRegister::AbstractCs), 0x16);
+    }
+
+    #[test]
+    fn resume_request_is_detected() {
+        assert!(is_resume_request(0x10, 0x4000_0001));
+        // Halt request
+        assert!(!is_resume_request(0x10, 0x8000_0001));
+        // Write to a different register
+        assert!(!is_resume_request(0x11, 0x4000_0001));
+        // With hasel and ackhavereset set
+        assert!(is_resume_request(0x10, 0x5400_0001));
+        // resumereq is ignored together with haltreq
+        assert!(!is_resume_request(0x10, 0xc000_0001));
+        // The debug module is inactive
+        assert!(!is_resume_request(0x10, 0x4000_0000));
+    }
+
+    #[test]
+    fn alternate_resume_encoding_polls_allresumeack() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x10, 0);
+        respond_dmi(&transport, 0x11, 0x0003_0c82);
+
+        // resumereq with hasel set
+        let register = (0x10u128 << 34) | (0x4400_0001u128 << 2) | 2;
+        probe
+            .write_register(0x11, &register.to_le_bytes(), 41)
+            .unwrap();
+
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [(0x10, 0x4400_0001, 2), (0x11, 0, 1)]
+        );
+    }
+
+    /// The DMI requests sent so far, as `(addr, data, op)`.
+    fn sent_dmi_ops(transport: &FakeTransport) -> Vec<(u8, u32, u8)> {
+        transport
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request[1] == CMD_DMI_OP)
+            .map(|request| {
+                let data = u32::from_be_bytes(request[4..8].try_into().unwrap());
+                (request[3], data, request[8])
+            })
+            .collect()
+    }
+
+    /// Queue the response to a DMI operation.
+    fn respond_dmi(transport: &FakeTransport, addr: u8, data: u32) {
+        let mut payload = vec![addr];
+        payload.extend_from_slice(&data.to_be_bytes());
+        payload.push(0);
+        transport.respond(CMD_DMI_OP, &payload);
+    }
+
+    #[test]
+    fn halt_polls_allhalted() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x10, 0);
+        respond_dmi(&transport, 0x11, 0x0000_0c82);
+        respond_dmi(&transport, 0x11, 0x0000_0382);
+        respond_dmi(&transport, 0x10, 0);
+
+        probe.halt().unwrap();
+
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [
+                (0x10, 0x8000_0001, 2),
+                (0x11, 0, 1),
+                (0x11, 0, 1),
+                (0x10, 0x0000_0001, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn target_reset_waits_for_the_settle_delay() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_reset_settle_delay(Duration::from_millis(50));
+
+        transport.respond(CMD_RESET, &[]);
+        let start = Instant::now();
+        probe.target_reset().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        respond_dmi(&transport, 0x10, 0);
+        let start = Instant::now();
+        probe.target_reset_deassert().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        assert_eq!(transport.sent_commands(), [CMD_RESET, CMD_DMI_OP]);
+    }
+
+    #[test]
+    fn target_reset_assert_is_a_recorded_dmi_write() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.set_dmi_history_capacity(1);
+
+        respond_dmi(&transport, 0x10, 0x8000_0001);
+        probe.target_reset_assert().unwrap();
+
+        assert_eq!(probe.last_dmi_write(), Some((0x10, 0x8000_0001, 0)));
+        assert_eq!(probe.dmi_history().count(), 1);
+    }
+
+    #[test]
+    fn reset_and_halt_sets_resethaltreq() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x11, 0x0000_00a2);
+        respond_dmi(&transport, 0x10, 0);
+        respond_dmi(&transport, 0x10, 0);
+        respond_dmi(&transport, 0x10, 0);
+        respond_dmi(&transport, 0x11, 0x000c_00a2);
+        respond_dmi(&transport, 0x11, 0x000c_03a2);
+        respond_dmi(&transport, 0x10, 0);
+
+        probe.reset_and_halt().unwrap();
+
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [
+                (0x11, 0, 1),
+                (0x10, 0x0000_0009, 2),
+                (0x10, 0x0000_0003, 2),
+                (0x10, 0x0000_0001, 2),
+                (0x11, 0, 1),
+                (0x11, 0, 1),
+                (0x10, 0x1000_0005, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_and_halt_requires_resethaltreq() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x11, 0x0000_0082);
+
+        let error = probe.reset_and_halt().unwrap_err();
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::UnsupportedOperation)
+        ));
+        assert_eq!(sent_dmi_ops(&transport), [(0x11, 0, 1)]);
+    }
+
+    #[test]
+    fn memory_word_uses_abstract_command() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x16, 0x0000_0002);
+        respond_dmi(&transport, 0x05, 0x2000_0010);
+        respond_dmi(&transport, 0x17, 0x0220_0000);
+        respond_dmi(&transport, 0x16, 0x0000_0002);
+        respond_dmi(&transport, 0x04, 0x1234_5678);
+
+        assert_eq!(probe.read_memory_word(0x2000_0010).unwrap(), 0x1234_5678);
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [
+                (0x16, 0, 1),
+                (0x05, 0x2000_0010, 2),
+                (0x17, 0x0220_0000, 2),
+                (0x16, 0, 1),
+                (0x04, 0, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_word_clears_stale_abstract_command_error() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // An exception of an earlier command
+        respond_dmi(&transport, 0x16, 0x0000_0302);
+        respond_dmi(&transport, 0x16, 0x0000_0700);
+        respond_dmi(&transport, 0x04, 0xcafe_f00d);
+        respond_dmi(&transport, 0x05, 0x2000_0014);
+        respond_dmi(&transport, 0x17, 0x0221_0000);
+        respond_dmi(&transport, 0x16, 0x0000_0002);
+
+        probe.write_memory_word(0x2000_0014, 0xcafe_f00d).unwrap();
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [
+                (0x16, 0, 1),
+                (0x16, 0x0000_0700, 2),
+                (0x04, 0xcafe_f00d, 2),
+                (0x05, 0x2000_0014, 2),
+                (0x17, 0x0221_0000, 2),
+                (0x16, 0, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_word_fails_on_busy_dmi_write() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x16, 0x0000_0002);
+        // The write of the address to `data1`, answered with op 3 (busy)
+        transport.respond(CMD_DMI_OP, &[0x05, 0x20, 0x00, 0x00, 0x10, 0x03]);
+
+        let error = probe.read_memory_word(0x2000_0010).unwrap_err();
+
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::DmiOpFailed { addr: 0x05, op: 3 })
+        ));
+        // The abstract command is not started.
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [(0x16, 0, 1), (0x05, 0x2000_0010, 2)]
+        );
+    }
+
+    #[test]
+    fn memory_word_fails_on_failed_data_read() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x16, 0x0000_0002);
+        respond_dmi(&transport, 0x05, 0x2000_0010);
+        respond_dmi(&transport, 0x17, 0x0220_0000);
+        respond_dmi(&transport, 0x16, 0x0000_0002);
+        // The read of `data0`, answered with op 2 (failed)
+        transport.respond(CMD_DMI_OP, &[0x04, 0x12, 0x34, 0x56, 0x78, 0x02]);
+
+        let error = probe.read_memory_word(0x2000_0010).unwrap_err();
+
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::DmiOpFailed { addr: 0x04, op: 2 })
+        ));
+    }
+
+    #[test]
+    fn memory_word_reports_abstract_command_error() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x16, 0x0000_0002);
+        respond_dmi(&transport, 0x05, 0x2000_0010);
+        respond_dmi(&transport, 0x17, 0x0220_0000);
+        // Bus error
+        respond_dmi(&transport, 0x16, 0x0000_0502);
+        respond_dmi(&transport, 0x16, 0x0000_0700);
+
+        let error = probe.read_memory_word(0x2000_0010).unwrap_err();
+
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::AbstractCommand(5))
+        ));
+        // The error is cleared for the next command.
+        assert_eq!(
+            sent_dmi_ops(&transport).last(),
+            Some(&(0x16, 0x0000_0700, 2))
+        );
+    }
+
+    #[test]
+    fn read_option_bytes_of_ch32v30x() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+        probe.attach().unwrap();
+
+        let words = [0x3fc0_5aa5, 0x00ff_00ff, 0x00ff_00ff, 0x00ff_00ff];
+        for (address, word) in (0x1fff_f800..).step_by(4).zip(words) {
+            respond_dmi(&transport, 0x16, 0x0000_0002);
+            respond_dmi(&transport, 0x05, address);
+            respond_dmi(&transport, 0x17, 0x0220_0000);
+            respond_dmi(&transport, 0x16, 0x0000_0002);
+            respond_dmi(&transport, 0x04, word);
+        }
+
+        let option_bytes = probe.read_option_bytes().unwrap();
+
+        // RDPR and its complement
+        assert_eq!(option_bytes[..2], [0xa5, 0x5a]);
+        assert_eq!(option_bytes[4..8], [0xff, 0x00, 0xff, 0x00]);
+
+        let addresses = sent_dmi_ops(&transport)
+            .into_iter()
+            .filter(|&(addr, _, op)| addr == 0x05 && op == 2)
+            .map(|(_, data, _)| data)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addresses,
+            [0x1fff_f800, 0x1fff_f804, 0x1fff_f808, 0x1fff_f80c]
+        );
+    }
+
+    #[test]
+    fn read_option_bytes_requires_attach() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        assert!(matches!(
+            probe.read_option_bytes(),
+            Err(DebugProbeError::NotAttached)
+        ));
+        assert!(transport.sent_commands().is_empty());
+    }
+
+    #[test]
+    fn poll_dmi_until_settled() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // A busy bit, which clears after the third read.
+        respond_dmi(&transport, 0x16, 0x0000_1002);
+        respond_dmi(&transport, 0x16, 0x0000_1002);
+        respond_dmi(&transport, 0x16, 0x0000_0002);
+
+        let value = probe
+            .poll_dmi(0x16, 0x0000_1000, 0, Duration::from_secs(1))
+            .unwrap();
+
+        assert_eq!(value, 0x0000_0002);
+        assert_eq!(sent_dmi_ops(&transport), [(0x16, 0, 1); 3]);
+    }
+
+    #[test]
+    fn poll_dmi_times_out() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x16, 0x0000_1002);
+
+        let error = probe
+            .poll_dmi(0x16, 0x0000_1000, 0, Duration::ZERO)
+            .unwrap_err();
+
+        assert!(matches!(error, DebugProbeError::Timeout));
+        assert_eq!(sent_dmi_ops(&transport), [(0x16, 0, 1)]);
+    }
+
+    #[test]
+    fn poll_dmi_fails_on_busy_dmi_read() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // Answered with op 3 (busy), the data would match the expected value.
+        transport.respond(CMD_DMI_OP, &[0x16, 0x00, 0x00, 0x00, 0x02, 0x03]);
+
+        let error = probe
+            .poll_dmi(0x16, 0x0000_1000, 0, Duration::from_secs(1))
+            .unwrap_err();
+
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::DmiOpFailed { addr: 0x16, op: 3 })
+        ));
+        assert_eq!(sent_dmi_ops(&transport), [(0x16, 0, 1)]);
+    }
+
+    #[test]
+    fn resume_polls_allresumeack() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x10, 0);
+        respond_dmi(&transport, 0x11, 0x0003_0c82);
+
+        probe.resume().unwrap();
+
+        assert_eq!(
+            sent_dmi_ops(&transport),
+            [(0x10, 0x4000_0001, 2), (0x11, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn set_idle_cycles_is_clamped() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        assert_eq!(probe.set_idle_cycles(12).unwrap(), 12);
+        assert_eq!(probe.set_idle_cycles(300).unwrap(), 255);
+        assert_eq!(probe.idle_cycles(), 255);
+    }
+
+    #[test]
+    fn detach_nonblocking_times_out() {
+        let mut transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // Make the probe unresponsive.
+        transport.delay = Duration::from_secs(1);
+        probe.device = Box::new(transport.clone());
+
+        let start = Instant::now();
+        probe.detach_nonblocking();
+
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(transport.sent_commands(), [CMD_CONTROL]);
+    }
+
+    #[test]
+    fn detach_nonblocking_ignores_errors() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_attach(&transport);
+        transport.respond(CMD_CONFIG_CHIP, &[0x02]);
+        transport.respond(CMD_CONFIG_CHIP, &[]);
+        probe.attach().unwrap();
+        assert!(probe.attached_chip().is_some());
+
+        transport.respond_error(CMD_CONTROL);
+        probe.detach_nonblocking();
+
+        assert_eq!(probe.attached_chip(), None);
+        assert_eq!(transport.sent_commands().last(), Some(&CMD_CONTROL));
+    }
+
+    #[test]
+    fn nop_without_previous_read() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_attach(&transport);
+        probe.attach().unwrap();
+
+        respond_dmi(&transport, 0, 0);
+
+        let response = probe
+            .write_register(0x11, &0u128.to_le_bytes(), 41)
+            .unwrap();
+
+        assert!(response.not_any());
+        assert_eq!(sent_dmi_ops(&transport).last(), Some(&(0, 0, 0)));
+    }
+
+    #[test]
+    fn nop_returns_previous_read() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x11, 0x0000_0c82);
+
+        let read = (0x11u128 << 34) | 1;
+        probe.write_register(0x11, &read.to_le_bytes(), 41).unwrap();
+        let response = probe
+            .write_register(0x11, &0u128.to_le_bytes(), 41)
+            .unwrap();
+
+        assert_eq!(response[2..34].load_le::<u32>(), 0x0000_0c82);
+        // Only the read was sent to the probe.
+        assert_eq!(sent_dmi_ops(&transport), [(0x11, 0, 1)]);
+    }
+
+    #[test]
+    fn mismatched_dmi_address_is_an_error() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        respond_dmi(&transport, 0x22, 0x0000_0c82);
+
+        let read = (0x11u128 << 34) | 1;
+        let error = probe
+            .write_register(0x11, &read.to_le_bytes(), 41)
+            .unwrap_err();
+
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::DmiAddressMismatch {
+                requested: 0x11,
+                echoed: 0x22
+            })
+        ));
+        // The mismatched response is not returned by a following NOP.
+        assert_eq!(probe.last_dmi_read, None);
+    }
+
+    #[test]
+    fn unknown_dmi_op_is_an_error() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        let register = (0x10u128 << 34) | 3;
+        let error = probe
+            .write_register(0x11, &register.to_le_bytes(), 41)
+            .unwrap_err();
+
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::InvalidDmiOp(3))
+        ));
+        assert!(sent_dmi_ops(&transport).is_empty());
+    }
+
+    #[test]
+    fn dmi_busy_is_passed_on() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        // A read of `dmstatus`, answered with op 3 (busy)
+        transport.respond(CMD_DMI_OP, &[0x11, 0x00, 0x00, 0x00, 0x00, 0x03]);
+
+        let read = (0x11u128 << 34) | 1;
+        let response = probe.write_register(0x11, &read.to_le_bytes(), 41).unwrap();
+
+        assert_eq!(response[0..2].load_le::<u8>(), 3);
+        assert_eq!(response[34..40].load_le::<u8>(), 0x11);
+        // The operation is not repeated by the probe driver.
+        assert_eq!(sent_dmi_ops(&transport), [(0x11, 0, 1)]);
+    }
+
+    #[test]
+    fn failing_dmi_write_is_an_error() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        transport.respond_error(CMD_DMI_OP);
+
+        let write = (0x10u128 << 34) | (0x1u128 << 2) | 2;
+        let error = probe
+            .write_register(0x11, &write.to_le_bytes(), 41)
+            .unwrap_err();
+
+        let DebugProbeError::ProbeSpecific(error) = error else {
+            panic!("Unexpected error: {error:?}");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::Protocol(_, _))
+        ));
+        assert_eq!(probe.last_dmi_write(), None);
+    }
+
+    #[test]
+    fn attach_requires_firmware_2_7() {
+        assert!(!WchLinkCapabilities::new(WchLinkVariant::ECh32v305, 2, 6).attach);
+        assert!(WchLinkCapabilities::new(WchLinkVariant::ECh32v305, 2, 7).attach);
+
+        // A newer major version with a lower minor version is supported as well.
+        assert!(WchLinkCapabilities::new(WchLinkVariant::ECh32v305, 3, 0).attach);
+    }
+
+    #[test]
+    fn capabilities_by_variant() {
+        let caps = WchLinkCapabilities::new(WchLinkVariant::Ch549, 2, 15);
+        assert!(caps.attach);
+        assert!(!caps.power_control);
+    }
+
+    #[test]
+    fn open_with_unknown_variant() {
+        let transport = FakeTransport::default();
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x42]);
+        let probe = WchLink::from_transport(Box::new(transport.clone())).unwrap();
+
+        assert_eq!(probe.variant, WchLinkVariant::Unknown(0x42));
+        assert_eq!(probe.get_name(), "WCH-Link-Unknown(0x42) v2.9");
+
+        // The fallback has the features of a WCH-LinkE.
+        let caps = probe.capabilities();
+        assert!(caps.attach);
+        assert!(caps.power_control);
+    }
+
+    #[test]
+    fn probe_uid_from_serial() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+        probe.serial_number = Some("0001A0000000".into());
+        assert_eq!(probe.probe_uid().unwrap(), 0x0001_a000_0000);
+
+        probe.serial_number = Some("WCH".into());
+        assert!(probe.probe_uid().is_err());
+
+        // A probe connected over TCP has no serial number.
+        probe.serial_number = None;
+        assert!(probe.probe_uid().is_err());
+        assert!(transport.sent_commands().is_empty());
+    }
+
+    #[test]
+    fn self_test_report() {
+        let transport = FakeTransport::default();
+        let mut probe = fake_probe(&transport);
+
+        transport.respond(CMD_CONTROL, &[0x02, 0x09, 0x02]);
+
+        let report = probe.self_test();
+
+        assert_eq!(report.variant, WchLinkVariant::ECh32v305);
+        assert_eq!(report.firmware_version, (2, 9));
+        assert!(report.capabilities.power_control);
+        assert!(report.usb_ok);
+
+        transport.respond_error(CMD_CONTROL);
+
+        let report = probe.self_test();
+
+        assert!(!report.usb_ok);
+    }
+
+    #[test]
+    fn selector_matches_single_device() {
+        let devices = [("1234", "WCH-Link A"), ("5678", "WCH-Link B")];
+        let with_serial =
+            |serial: &str| devices.into_iter().filter(move |device| device.0 == serial);
+
+        assert_eq!(
+            single_device(with_serial("5678")).unwrap(),
+            ("5678", "WCH-Link B")
+        );
+        assert!(matches!(
+            single_device(with_serial("0000")),
+            Err(ProbeCreationError::NotFound)
+        ));
+
+        let Err(ProbeCreationError::ProbeSpecific(error)) = single_device(devices.into_iter())
+        else {
+            panic!("Two matching devices should be an error");
+        };
+        assert!(matches!(
+            error.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::AmbiguousSelector(2))
+        ));
+    }
+
+    #[test]
+    fn error_categories() {
+        let errors = [
+            (
+                WchLinkError::Remote {
+                    category: WchLinkErrorCategory::Protocol,
+                    message: "Protocol error.".into(),
+                },
+                WchLinkErrorCategory::Protocol,
+            ),
+            (
+                WchLinkError::UnknownDevice,
+                WchLinkErrorCategory::Unsupported,
+            ),
+            (
+                WchLinkError::UnsupportedFirmwareVersion("2.7"),
+                WchLinkErrorCategory::Configuration,
+            ),
+            (
+                WchLinkError::NotEnoughBytesWritten { is: 1, should: 4 },
+                WchLinkErrorCategory::Transient,
+            ),
+            (
+                WchLinkError::NotEnoughBytesRead { is: 1, should: 4 },
+                WchLinkErrorCategory::Transient,
+            ),
+            (
+                WchLinkError::AmbiguousSelector(2),
+                WchLinkErrorCategory::Configuration,
+            ),
+            (
+                WchLinkError::EndpointNotFound,
+                WchLinkErrorCategory::Configuration,
+            ),
+            (
+                WchLinkError::FlashProtected,
+                WchLinkErrorCategory::Configuration,
+            ),
+            (WchLinkError::InvalidPayload, WchLinkErrorCategory::Protocol),
+            (
+                WchLinkError::DebugModuleInactive,
+                WchLinkErrorCategory::Protocol,
+            ),
+            (
+                WchLinkError::InvalidDmiOp(3),
+                WchLinkErrorCategory::Protocol,
+            ),
+            (
+                WchLinkError::DmiOpFailed { addr: 0x17, op: 2 },
+                WchLinkErrorCategory::Protocol,
+            ),
+            (
+                WchLinkError::DmiAddressMismatch {
+                    requested: 0x11,
+                    echoed: 0x22,
+                },
+                WchLinkErrorCategory::Protocol,
+            ),
+            (
+                WchLinkError::AbstractCommand(5),
+                WchLinkErrorCategory::Protocol,
+            ),
+            (WchLinkError::Disconnected, WchLinkErrorCategory::Transient),
+            (
+                WchLinkError::InvalidHaltsumLevel(4),
+                WchLinkErrorCategory::Configuration,
+            ),
+            (
+                WchLinkError::ChipFamilyMismatch {
+                    expected: RiscvChip::CH32V103,
+                    detected: RiscvChip::CH32V30X,
+                },
+                WchLinkErrorCategory::Configuration,
+            ),
+            (
+                WchLinkError::NoTargetDetected(u32::MAX),
+                WchLinkErrorCategory::Configuration,
+            ),
+            (
+                WchLinkError::Protocol(0x55, vec![0x81, 0x55, 0x01, 0x01]),
+                WchLinkErrorCategory::Protocol,
+            ),
+            (
+                WchLinkError::UnknownChip(0xff),
+                WchLinkErrorCategory::Unsupported,
+            ),
+            (
+                WchLinkError::UnsupportedOperation,
+                WchLinkErrorCategory::Unsupported,
+            ),
+        ];
+
+        for (error, category) in errors {
+            assert_eq!(error.category(), category, "{error:?}");
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Access to a WCH-Link connected to another machine.
+//!
+//! The server forwards the raw command packets to a local WCH-Link, so the command
+//! serialization stays on the client.
+//!
+//! Each request is framed as `[timeout_ms: u16 BE, len: u8, packet..]`, and each
+//! response as `[status: u8, len: u8, payload..]`. On success the payload is the
+//! response packet of the probe, otherwise it is the [category](WchLinkErrorCategory)
+//! of the error followed by its message.
+//!
+//! The client can't read the USB endpoint of the probe, so it can't discard a response
+//! which arrives after a request timed out. The server does that instead, before it
+//! reports the timeout, so that the next request isn't answered with the late response.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::probe::DebugProbeError;
+
+use super::{
+    WchLinkError, WchLinkErrorCategory, WchLinkUsbDevice,
+    usb_interface::{WchLinkTransport, error_category},
+};
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+const STATUS_TIMEOUT: u8 = 2;
+
+// Additional time allowed for the network round trip, on top of the USB timeout
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Client side of the connection, used in place of a local USB device.
+pub(crate) struct WchLinkTcpClient {
+    stream: TcpStream,
+    /// A request failed without a complete response, so the responses on the stream
+    /// can't be matched to the requests anymore.
+    broken: bool,
+}
+
+impl WchLinkTcpClient {
+    pub(crate) fn connect(addr: impl ToSocketAddrs) -> Result<Self, DebugProbeError> {
+        let stream = TcpStream::connect(addr).map_err(DebugProbeError::Usb)?;
+        stream.set_nodelay(true).map_err(DebugProbeError::Usb)?;
+
+        Ok(Self {
+            stream,
+            broken: false,
+        })
+    }
+
+    fn exchange(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Result<usize, DebugProbeError>> {
+        let timeout_ms = u16::try_from(timeout.as_millis()).unwrap_or(u16::MAX);
+        let Ok(len) = u8::try_from(request.len()) else {
+            return Ok(Err(WchLinkError::InvalidPayload.into()));
+        };
+
+        let mut frame = Vec::with_capacity(request.len() + 3);
+        frame.extend_from_slice(&timeout_ms.to_be_bytes());
+        frame.push(len);
+        frame.extend_from_slice(request);
+
+        self.stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+        self.stream.write_all(&frame)?;
+
+        self.stream
+            .set_read_timeout(Some(timeout + NETWORK_TIMEOUT))?;
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header)?;
+        let [status, len] = header;
+
+        let mut payload = vec![0u8; len as usize];
+        self.stream.read_exact(&mut payload)?;
+
+        Ok(match status {
+            STATUS_OK => {
+                let len = payload.len().min(response.len());
+                response[..len].copy_from_slice(&payload[..len]);
+                Ok(len)
+            }
+            STATUS_TIMEOUT => Err(DebugProbeError::Timeout),
+            _ => {
+                let (category, message) = payload.split_first().unwrap_or((&0, &[]));
+
+                Err(WchLinkError::Remote {
+                    category: decode_category(*category),
+                    message: String::from_utf8_lossy(message).into_owned(),
+                }
+                .into())
+            }
+        })
+    }
+}
+
+impl WchLinkTransport for WchLinkTcpClient {
+    fn transfer(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, DebugProbeError> {
+        if self.broken {
+            return Err(WchLinkError::Disconnected.into());
+        }
+
+        match self.exchange(request, response, timeout) {
+            Ok(result) => result,
+            Err(e) => {
+                // A late or partial response would be taken as the response to the next
+                // request, so the connection can't be used anymore.
+                tracing::warn!("Connection to the WCH-Link server failed: {}", e);
+                self.broken = true;
+                let _ = self.stream.shutdown(Shutdown::Both);
+
+                Err(WchLinkError::Disconnected.into())
+            }
+        }
+    }
+}
+
+/// Make `device` available to [`WchLink::connect`](super::WchLink::connect) clients.
+///
+/// Clients connecting to `listener` are served one after the other. This only returns
+/// if accepting a connection fails.
+///
+/// The server does not authenticate or encrypt the connection, anyone who can reach
+/// `listener` gets full access to the probe and the target. Only listen on a trusted
+/// network, or tunnel the connection, e.g. over SSH.
+pub fn serve_tcp(mut device: WchLinkUsbDevice, listener: &TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept()?;
+        tracing::info!("WCH-Link client {} connected", peer);
+
+        if let Err(e) = serve_connection(&mut device, stream) {
+            tracing::warn!("WCH-Link client {} failed: {}", peer, e);
+        }
+
+        tracing::info!("WCH-Link client {} disconnected", peer);
+    }
+}
+
+/// Forward the requests of a single client to `transport`, until the client disconnects.
+pub(super) fn serve_connection(
+    transport: &mut dyn WchLinkTransport,
+    mut stream: TcpStream,
+) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    loop {
+        let mut header = [0u8; 3];
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let timeout = Duration::from_millis(u16::from_be_bytes([header[0], header[1]]).into());
+
+        let mut request = vec![0u8; header[2] as usize];
+        stream.read_exact(&mut request)?;
+
+        let mut response = [0u8; 64];
+        let (status, payload) = match transport.transfer(&request, &mut response, timeout) {
+            Ok(len) => (STATUS_OK, response[..len].to_vec()),
+            Err(DebugProbeError::Timeout) => {
+                if let Err(e) = transport.drain() {
+                    tracing::warn!("Failed to discard a late response: {}", e);
+                }
+                (STATUS_TIMEOUT, Vec::new())
+            }
+            Err(e) => {
+                let mut message = e.to_string().into_bytes();
+                message.truncate(u8::MAX as usize - 1);
+
+                let mut payload = vec![encode_category(error_category(&e))];
+                payload.extend_from_slice(&message);
+                (STATUS_ERROR, payload)
+            }
+        };
+
+        let mut frame = Vec::with_capacity(payload.len() + 2);
+        frame.push(status);
+        frame.push(payload.len() as u8);
+        frame.extend_from_slice(&payload);
+        stream.write_all(&frame)?;
+    }
+}
+
+/// Encode the category of an error, to send it to the client.
+pub(super) fn encode_category(category: WchLinkErrorCategory) -> u8 {
+    match category {
+        WchLinkErrorCategory::Transient => 0,
+        WchLinkErrorCategory::Configuration => 1,
+        WchLinkErrorCategory::Unsupported => 2,
+        WchLinkErrorCategory::Protocol => 3,
+    }
+}
+
+/// Decode the category sent by the server, unknown values are treated as transient.
+pub(super) fn decode_category(value: u8) -> WchLinkErrorCategory {
+    match value {
+        1 => WchLinkErrorCategory::Configuration,
+        2 => WchLinkErrorCategory::Unsupported,
+        3 => WchLinkErrorCategory::Protocol,
+        _ => WchLinkErrorCategory::Transient,
+    }
+}
@@ -1,30 +1,88 @@
-use std::time::Duration;
+use std::{io, time::Duration};
 
-use nusb::Interface;
+use nusb::{Interface, transfer::TransferError};
 
 use crate::probe::{
     DebugProbeError, DebugProbeSelector, ProbeCreationError, usb_util::InterfaceExt,
 };
 
-use super::{WchLinkError, commands::WchLinkCommand, get_wlink_info};
+use super::{WchLinkError, WchLinkErrorCategory, commands::WchLinkCommand, get_wlink_info};
 
 const ENDPOINT_OUT: u8 = 0x01;
 const ENDPOINT_IN: u8 = 0x81;
 
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Timeout for each read while draining stale data from the probe
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(10);
+// Upper limit for the number of stale responses discarded while draining
+const MAX_DRAIN_READS: usize = 16;
+
 // const RAW_ENDPOINT_OUT: u8 = 0x02;
 // const RAW_ENDPOINT_IN: u8 = 0x82;
 
+/// How commands and the attach sequence are retried after a failure.
+///
+/// Set it with [`WchLink::set_retry_policy`](super::WchLink::set_retry_policy). A command is
+/// only retried after a [transient](WchLinkErrorCategory::Transient) error, and before each
+/// retry, any late response to the failed attempt is discarded. Over TCP, the server
+/// discards it before it reports the timeout, see [`serve_tcp`](super::serve_tcp).
+///
+/// Note that the failed attempt may still have been executed by the probe, so commands
+/// which can't safely be executed twice, like writes to `dmcontrol` or erasing the chip,
+/// are not retried unless `retry_non_idempotent` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of times a command is sent at most, including the first attempt.
+    pub attempts: usize,
+    /// Timeout for each USB transfer.
+    pub timeout: Duration,
+    /// Delay before each retry.
+    pub backoff: Duration,
+    /// Also retry commands which have side effects when executed twice.
+    pub retry_non_idempotent: bool,
+    /// Number of times the whole attach sequence is run at most, including the first attempt.
+    ///
+    /// Each retry runs the attach sequence again, except for clearing the flash protection
+    /// if that already succeeded. Only the error of the last attempt is returned.
+    pub attach_attempts: usize,
+}
+
+impl Default for RetryPolicy {
+    /// Send each command and attach only once.
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            timeout: DEFAULT_TIMEOUT,
+            backoff: Duration::ZERO,
+            retry_non_idempotent: false,
+            attach_attempts: 1,
+        }
+    }
+}
+
+/// An opened WCH-Link USB device.
+///
+/// Use [`WchLink::from_device`](super::WchLink::from_device) to create a probe from it.
 pub struct WchLinkUsbDevice {
     device_handle: Interface,
+    serial_number: Option<String>,
+    /// Selector used to open the device again after it was disconnected
+    selector: DebugProbeSelector,
 }
 
 impl WchLinkUsbDevice {
+    /// Open the WCH-Link matching `selector`.
+    ///
+    /// Fails if no WCH-Link or more than one WCH-Link matches, e.g. when several
+    /// probes are connected and the selector doesn't contain a serial number.
     pub fn new_from_selector(selector: &DebugProbeSelector) -> Result<Self, ProbeCreationError> {
-        let device = nusb::list_devices()
-            .map_err(ProbeCreationError::Usb)?
-            .filter(|device| selector.matches(device))
-            .find(|device| get_wlink_info(device).is_some())
-            .ok_or(ProbeCreationError::NotFound)?;
+        let device = single_device(
+            nusb::list_devices()
+                .map_err(ProbeCreationError::Usb)?
+                .filter(|device| selector.matches(device))
+                .filter(|device| get_wlink_info(device).is_some()),
+        )?;
 
         let mut endpoint_out = false;
         let mut endpoint_in = false;
@@ -56,42 +114,207 @@ impl WchLinkUsbDevice {
             .map_err(ProbeCreationError::Usb)?;
         tracing::trace!("Claimed interface 0 of USB device.");
 
-        let usb_wlink = Self { device_handle };
+        let usb_wlink = Self {
+            device_handle,
+            serial_number: device.serial_number().map(str::to_string),
+            selector: selector.clone(),
+        };
 
         tracing::debug!("Succesfully attached to WCH-Link.");
 
         Ok(usb_wlink)
     }
+}
+
+impl WchLinkUsbDevice {
+    /// The USB serial number of the probe, if it has one.
+    pub(super) fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_deref()
+    }
+}
 
-    pub(crate) fn send_command<C: WchLinkCommand + std::fmt::Debug>(
-        &mut self,
-        cmd: C,
-    ) -> Result<C::Response, DebugProbeError> {
-        tracing::trace!("Sending command: {:?}", cmd);
+/// Take the only device out of `devices`.
+pub(super) fn single_device<T>(
+    mut devices: impl Iterator<Item = T>,
+) -> Result<T, ProbeCreationError> {
+    let device = devices.next().ok_or(ProbeCreationError::NotFound)?;
 
-        let mut rxbuf = [0u8; 64];
-        let len = cmd.to_bytes(&mut rxbuf)?;
+    let others = devices.count();
+    if others > 0 {
+        return Err(WchLinkError::AmbiguousSelector(others + 1).into());
+    }
 
-        let timeout = Duration::from_millis(100);
+    Ok(device)
+}
 
+impl WchLinkTransport for WchLinkUsbDevice {
+    fn transfer(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, DebugProbeError> {
         let written_bytes = self
             .device_handle
-            .write_bulk(ENDPOINT_OUT, &rxbuf[..len], timeout)
-            .map_err(DebugProbeError::Usb)?;
+            .write_bulk(ENDPOINT_OUT, request, timeout)
+            .map_err(usb_error)?;
 
-        if written_bytes != len {
+        if written_bytes != request.len() {
             return Err(WchLinkError::NotEnoughBytesWritten {
                 is: written_bytes,
-                should: len,
+                should: request.len(),
             }
             .into());
         }
 
+        self.device_handle
+            .read_bulk(ENDPOINT_IN, response, timeout)
+            .map_err(usb_error)
+    }
+
+    fn read_pending(
+        &mut self,
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, DebugProbeError> {
+        match self.device_handle.read_bulk(ENDPOINT_IN, response, timeout) {
+            Ok(len) => Ok(len),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(DebugProbeError::Timeout),
+            Err(e) => Err(usb_error(e)),
+        }
+    }
+
+    fn reopen(&mut self) -> Result<(), DebugProbeError> {
+        *self = Self::new_from_selector(&self.selector)?;
+
+        Ok(())
+    }
+}
+
+/// Convert the error of a USB transfer, reporting a probe which is gone as
+/// [`WchLinkError::Disconnected`].
+fn usb_error(error: io::Error) -> DebugProbeError {
+    let disconnected = matches!(
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<TransferError>()),
+        Some(TransferError::Disconnected)
+    );
+
+    if disconnected {
+        WchLinkError::Disconnected.into()
+    } else {
+        DebugProbeError::Usb(error)
+    }
+}
+
+/// Raw access to the command endpoints of a WCH-Link.
+///
+/// This is implemented by [`WchLinkUsbDevice`], and allows testing the protocol
+/// without a probe.
+pub(crate) trait WchLinkTransport: Send {
+    /// Send `request` to the probe, and read its response into `response`.
+    ///
+    /// Returns the number of bytes read.
+    fn transfer(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, DebugProbeError>;
+
+    /// Read a response the probe sent without a request, into `response`.
+    ///
+    /// Returns a `Timeout` error if there is none. Transports without a local USB
+    /// endpoint have nothing to read, their server has to discard late responses.
+    fn read_pending(
+        &mut self,
+        _response: &mut [u8],
+        _timeout: Duration,
+    ) -> Result<usize, DebugProbeError> {
+        Err(DebugProbeError::Timeout)
+    }
+
+    /// Open the probe again, after it was [disconnected](WchLinkError::Disconnected),
+    /// e.g. because it was reset together with the target.
+    fn reopen(&mut self) -> Result<(), DebugProbeError> {
+        Err(DebugProbeError::CommandNotSupportedByProbe {
+            command_name: "reopen",
+        })
+    }
+}
+
+impl dyn WchLinkTransport {
+    /// Discard any responses left over by a previous session, e.g. one which was aborted
+    /// in the middle of a command.
+    ///
+    /// Otherwise, the first command would receive the stale response instead of its own.
+    pub(crate) fn drain(&mut self) -> Result<(), DebugProbeError> {
+        let mut buffer = [0u8; 64];
+
+        for _ in 0..MAX_DRAIN_READS {
+            match self.read_pending(&mut buffer, DRAIN_TIMEOUT) {
+                Ok(0) | Err(DebugProbeError::Timeout) => return Ok(()),
+                Ok(len) => tracing::debug!("Discarding stale response: {:02x?}", &buffer[..len]),
+                Err(e) => return Err(e),
+            }
+        }
+
+        tracing::warn!(
+            "Probe is still sending data after discarding {} stale responses",
+            MAX_DRAIN_READS
+        );
+
+        Ok(())
+    }
+
+    /// Send a command, retrying it according to `policy`.
+    ///
+    /// The error of the last attempt is returned.
+    pub(crate) fn send_command_with_policy<C: WchLinkCommand + std::fmt::Debug>(
+        &mut self,
+        cmd: &C,
+        policy: &RetryPolicy,
+    ) -> Result<C::Response, DebugProbeError> {
+        let mut attempt = 1;
+
+        loop {
+            match self.send_command_with_timeout(cmd, policy.timeout) {
+                Err(e)
+                    if attempt < policy.attempts
+                        && (cmd.is_idempotent() || policy.retry_non_idempotent)
+                        && is_retryable(&e) =>
+                {
+                    tracing::debug!(
+                        "Command {:?} failed (attempt {}/{}), retrying: {}",
+                        cmd,
+                        attempt,
+                        policy.attempts,
+                        e
+                    );
+                    attempt += 1;
+
+                    std::thread::sleep(policy.backoff);
+                    self.drain()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a command, waiting at most `timeout` for each USB transfer.
+    pub(crate) fn send_command_with_timeout<C: WchLinkCommand + std::fmt::Debug>(
+        &mut self,
+        cmd: &C,
+        timeout: Duration,
+    ) -> Result<C::Response, DebugProbeError> {
+        tracing::trace!("Sending command: {:?}", cmd);
+
+        let mut txbuf = [0u8; 64];
+        let len = cmd.to_bytes(&mut txbuf)?;
+
         let mut rxbuf = [0u8; 64];
-        let read_bytes = self
-            .device_handle
-            .read_bulk(ENDPOINT_IN, &mut rxbuf[..], timeout)
-            .map_err(DebugProbeError::Usb)?;
+        let read_bytes = self.transfer(&txbuf[..len], &mut rxbuf[..], timeout)?;
 
         if read_bytes < 3 {
             return Err(WchLinkError::NotEnoughBytesRead {
@@ -113,3 +336,31 @@ impl WchLinkUsbDevice {
         Ok(response)
     }
 }
+
+/// Whether a command failed with `error` because the probe was disconnected.
+pub(super) fn is_disconnected(error: &DebugProbeError) -> bool {
+    match error {
+        DebugProbeError::ProbeSpecific(e) => matches!(
+            e.downcast_ref::<WchLinkError>(),
+            Some(WchLinkError::Disconnected)
+        ),
+        _ => false,
+    }
+}
+
+/// The category of a transfer error, errors not specific to the WCH-Link are transient.
+pub(super) fn error_category(error: &DebugProbeError) -> WchLinkErrorCategory {
+    match error {
+        DebugProbeError::ProbeSpecific(e) => e
+            .downcast_ref::<WchLinkError>()
+            .map_or(WchLinkErrorCategory::Transient, WchLinkError::category),
+        _ => WchLinkErrorCategory::Transient,
+    }
+}
+
+/// Whether a command which failed with `error` can succeed when sent again.
+///
+/// A disconnected probe has to be opened again first, so it is not retried here.
+fn is_retryable(error: &DebugProbeError) -> bool {
+    error_category(error) == WchLinkErrorCategory::Transient && !is_disconnected(error)
+}